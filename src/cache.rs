@@ -82,6 +82,69 @@ impl ICache {
     }
 }
 
+/// Assembly performing the data cache maintenance `$op` (e.g. `cisw`) by set/way on every cache
+/// level up to the Level of Coherence of the current PE.
+///
+/// Only registers are used, so that it can run while the data cache is being turned off. It
+/// clobbers x0-x7 and x9-x11, and assumes that FEAT_CCIDX is not implemented.
+#[cfg(target_arch = "aarch64")]
+macro_rules! dcache_set_way_all {
+    ($op:literal) => {
+        concat!(
+            "
+            dmb     sy
+            mrs     x0, clidr_el1
+            and     x3, x0, #0x7000000
+            lsr     x3, x3, #23         // LoC * 2
+            cbz     x3, 6f
+            mov     x10, #0             // level * 2
+        2:
+            add     x2, x10, x10, lsr #1
+            lsr     x1, x0, x2
+            and     x1, x1, #7          // cache type of this level
+            cmp     x1, #2
+            b.lt    5f                  // no data cache at this level
+            msr     csselr_el1, x10
+            isb
+            mrs     x1, ccsidr_el1
+            and     x2, x1, #7
+            add     x2, x2, #4          // log2(line size)
+            mov     x4, #0x3ff
+            and     x4, x4, x1, lsr #3  // maximum way number
+            clz     w5, w4              // bit position of the way
+            mov     x7, #0x7fff
+            and     x7, x7, x1, lsr #13 // maximum set number
+        3:
+            mov     x9, x4
+        4:
+            lsl     x6, x9, x5
+            orr     x11, x10, x6
+            lsl     x6, x7, x2
+            orr     x11, x11, x6
+            dc      ",
+            $op,
+            ", x11
+            subs    x9, x9, #1
+            b.ge    4b
+            subs    x7, x7, #1
+            b.ge    3b
+        5:
+            add     x10, x10, #2
+            cmp     x3, x10
+            b.gt    2b
+        6:
+            mov     x10, #0
+            msr     csselr_el1, x10
+            dsb     sy
+            isb
+            "
+        )
+    };
+}
+
+#[cfg(target_arch = "aarch64")]
+pub(crate) use dcache_set_way_all;
+
 macro_rules! cache_ins {
     (ICache) => {
         "ic"
@@ -145,6 +208,30 @@ define_cache_op!(DCache, Clean, PoC);
 define_cache_op!(DCache, Invalidate, PoC);
 define_cache_op!(DCache, CleanAndInvalidate, PoC);
 
+impl DCache<CleanAndInvalidate, PoC> {
+    /// Clean and invalidate all D-Cache to the Point of Coherency in the current PE, by
+    /// set/way.
+    ///
+    /// Set/way operations are local to the PE, and lines may be allocated again by other PEs
+    /// or by speculation, so this is only meant for turning the caches off.
+    #[inline]
+    pub fn local_flush_all() {
+        match () {
+            #[cfg(target_arch = "aarch64")]
+            () => unsafe {
+                asm!(
+                    dcache_set_way_all!("cisw"),
+                    out("x0") _, out("x1") _, out("x2") _, out("x3") _, out("x4") _, out("x5") _,
+                    out("x6") _, out("x7") _, out("x9") _, out("x10") _, out("x11") _,
+                )
+            },
+
+            #[cfg(not(target_arch = "aarch64"))]
+            () => unimplemented!(),
+        }
+    }
+}
+
 /// Level 1 instruction cache policy
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum L1ICachePolicy {
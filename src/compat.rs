@@ -0,0 +1,285 @@
+//! AArch32 (compat) execution at EL0.
+//!
+//! A32 and T32 programs run with the same [`UserContext`]: at EL0, R0-R12 are mapped to X0-X12,
+//! SP_usr to X13 and LR_usr to X14, while CPSR is held in SPSR_EL1 with M[4] set.
+
+use crate::{
+    exception::ExceptionInfo,
+    trap::{TrapReason, UserContext},
+};
+use bit_field::BitField;
+
+/// CPSR.M for User mode, with M[4] selecting AArch32.
+const CPSR_MODE_USR: u32 = 0b1_0000;
+/// CPSR.T, the T32 instruction set.
+const CPSR_T: u32 = 1 << 5;
+/// CPSR bits a program may set: NZCVQ, IT, GE, E and T. The mode is always User, and A, I and F
+/// are always clear.
+const CPSR_USER_MASK: u32 = 0xf800_0000 | 0b11 << 25 | 0xf << 16 | 0x3f << 10 | 1 << 9 | CPSR_T;
+
+/// Returns whether EL0 can be executed in AArch32 state.
+pub fn is_supported() -> bool {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let pfr0: u64;
+        unsafe { core::arch::asm!("mrs {}, id_aa64pfr0_el1", out(reg) pfr0) };
+        pfr0.get_bits(0..4) == 0b0010
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    unimplemented!()
+}
+
+/// Extracts the immediate of an A32 or T32 SVC instruction, or returns `None` if `insn` is not
+/// one.
+///
+/// ESR_EL1 only holds 16 bits of the immediate, the whole 24 bits of an A32 SVC are needed for
+/// the OABI syscall numbers (`0x900000 + n`).
+pub fn decode_svc(insn: u32, thumb: bool) -> Option<u32> {
+    if thumb {
+        (insn & 0xff00 == 0xdf00).then_some(insn & 0xff)
+    } else {
+        // cond = 0b1111 is the unconditional instruction space.
+        (insn.get_bits(24..28) == 0xf && insn.get_bits(28..32) != 0xf)
+            .then_some(insn.get_bits(0..24))
+    }
+}
+
+/// The context of an AArch32 program, running at EL0.
+///
+/// # Example
+/// ```no_run
+/// # use aarch64::{compat::CompatContext, trap::TrapReason};
+/// # fn syscall(num: u32, args: [u32; 6]) -> u32 { unimplemented!() }
+/// # let (entry, sp) = (0x8000, 0xf000_0000);
+/// let mut context = CompatContext::new(entry, sp);
+/// loop {
+///     match context.run() {
+///         TrapReason::Syscall => {
+///             let ret = syscall(context.get_syscall_num(), context.get_syscall_args());
+///             context.set_syscall_ret(ret);
+///         }
+///         // ...
+///         _ => {}
+///     }
+/// }
+/// ```
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct CompatContext {
+    pub context: UserContext,
+}
+
+impl CompatContext {
+    /// Creates a context starting at `entry`, in T32 state if bit 0 is set as for a BX.
+    pub fn new(entry: u32, sp: u32) -> Self {
+        let mut this = Self::default();
+        this.set_cpsr(0);
+        this.set_entry(entry);
+        this.set_sp(sp);
+        this
+    }
+
+    /// Get register `n`, R13 being SP and R14 being LR
+    pub fn r(&self, n: usize) -> u32 {
+        let g = &self.context.general;
+        let value = match n {
+            0 => g.x0,
+            1 => g.x1,
+            2 => g.x2,
+            3 => g.x3,
+            4 => g.x4,
+            5 => g.x5,
+            6 => g.x6,
+            7 => g.x7,
+            8 => g.x8,
+            9 => g.x9,
+            10 => g.x10,
+            11 => g.x11,
+            12 => g.x12,
+            13 => g.x13,
+            14 => g.x14,
+            _ => panic!("invalid register r{}", n),
+        };
+        value as u32
+    }
+
+    /// Set register `n`, R13 being SP and R14 being LR
+    pub fn set_r(&mut self, n: usize, value: u32) {
+        let g = &mut self.context.general;
+        let reg = match n {
+            0 => &mut g.x0,
+            1 => &mut g.x1,
+            2 => &mut g.x2,
+            3 => &mut g.x3,
+            4 => &mut g.x4,
+            5 => &mut g.x5,
+            6 => &mut g.x6,
+            7 => &mut g.x7,
+            8 => &mut g.x8,
+            9 => &mut g.x9,
+            10 => &mut g.x10,
+            11 => &mut g.x11,
+            12 => &mut g.x12,
+            13 => &mut g.x13,
+            14 => &mut g.x14,
+            _ => panic!("invalid register r{}", n),
+        };
+        *reg = value as usize;
+    }
+
+    /// Get stack pointer, R13
+    pub fn get_sp(&self) -> u32 {
+        self.r(13)
+    }
+
+    /// Set stack pointer, R13
+    pub fn set_sp(&mut self, sp: u32) {
+        self.set_r(13, sp);
+    }
+
+    /// Get link register, R14
+    pub fn get_lr(&self) -> u32 {
+        self.r(14)
+    }
+
+    /// Get program counter
+    pub fn get_pc(&self) -> u32 {
+        self.context.elr as u32
+    }
+
+    /// Set program counter, keeping the instruction set
+    pub fn set_pc(&mut self, pc: u32) {
+        self.context.elr = pc as usize;
+    }
+
+    /// Jump to `addr` as with a BX: bit 0 selects T32 state.
+    pub fn set_entry(&mut self, addr: u32) {
+        let mut cpsr = self.cpsr();
+        cpsr.set_bit(5, addr.get_bit(0));
+        self.set_cpsr(cpsr);
+        self.set_pc(addr & !1);
+    }
+
+    /// Get CPSR
+    pub fn cpsr(&self) -> u32 {
+        self.context.spsr as u32
+    }
+
+    /// Set CPSR
+    ///
+    /// Only the flags, the IT state, the endianness and the T bit are taken from `cpsr`: the
+    /// program always runs in User mode, with exceptions unmasked.
+    pub fn set_cpsr(&mut self, cpsr: u32) {
+        self.context.spsr = ((cpsr & CPSR_USER_MASK) | CPSR_MODE_USR) as usize;
+    }
+
+    /// Whether the program runs in T32 state
+    pub fn is_thumb(&self) -> bool {
+        self.cpsr() & CPSR_T != 0
+    }
+
+    /// Get number of syscall, R7 with the EABI
+    pub fn get_syscall_num(&self) -> u32 {
+        self.r(7)
+    }
+
+    /// Get syscall args, R0-R5
+    pub fn get_syscall_args(&self) -> [u32; 6] {
+        [
+            self.r(0),
+            self.r(1),
+            self.r(2),
+            self.r(3),
+            self.r(4),
+            self.r(5),
+        ]
+    }
+
+    /// Get the seven syscall args of the EABI, R0-R6
+    ///
+    /// 64-bit arguments take an even and odd register pair, see [`arg64`](Self::arg64).
+    pub fn get_syscall_args7(&self) -> [u32; 7] {
+        [
+            self.r(0),
+            self.r(1),
+            self.r(2),
+            self.r(3),
+            self.r(4),
+            self.r(5),
+            self.r(6),
+        ]
+    }
+
+    /// Get the 64-bit argument in registers `n` (low half) and `n + 1` (high half)
+    pub fn arg64(&self, n: usize) -> u64 {
+        self.r(n) as u64 | (self.r(n + 1) as u64) << 32
+    }
+
+    /// Set return value of syscall, R0
+    pub fn set_syscall_ret(&mut self, ret: u32) {
+        self.set_r(0, ret);
+    }
+
+    /// Set 64-bit return value of syscall, R0 (low half) and R1 (high half)
+    pub fn set_syscall_ret64(&mut self, ret: u64) {
+        self.set_r(0, ret as u32);
+        self.set_r(1, (ret >> 32) as u32);
+    }
+
+    /// Moves back to the SVC instruction and restores R0, to execute the syscall again.
+    ///
+    /// The SVC instruction is 2 bytes long in T32 state, 4 bytes long in A32 state.
+    pub fn restart_syscall(&mut self) {
+        self.set_r(0, self.context.orig_x0 as u32);
+        let len = if self.is_thumb() { 2 } else { 4 };
+        self.set_pc(self.get_pc().wrapping_sub(len));
+    }
+
+    /// Skips the instruction that trapped, e.g. an emulated one.
+    pub fn skip_instruction(&mut self, info: &ExceptionInfo) {
+        let len = info.instruction_len() as u32;
+        self.set_pc(self.get_pc().wrapping_add(len));
+    }
+
+    /// Go to user space in AArch32 state with the context, and come back when a trap occurs.
+    ///
+    /// See [`UserContext::run`]. The FP/SIMD registers D0-D31 are saved as Q0-Q15.
+    pub fn run(&mut self) -> TrapReason {
+        // Keep the mode in case the fields were changed directly.
+        self.set_cpsr(self.cpsr());
+        self.context.run()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_compat_context() {
+        let mut context = CompatContext::new(0x8001, 0x7fff_0000);
+        assert!(context.is_thumb());
+        assert_eq!(context.get_pc(), 0x8000);
+        assert_eq!(context.context.general.x13, 0x7fff_0000);
+        assert_eq!(context.cpsr(), 0b11_0000);
+
+        // SVC #0 in T32 state
+        context.set_pc(0x8006);
+        context.restart_syscall();
+        assert_eq!(context.get_pc(), 0x8004);
+        let info = ExceptionInfo::new(0x4400_0000, 0);
+        context.skip_instruction(&info);
+        assert_eq!(context.get_pc(), 0x8006);
+
+        // Privileged modes and masked interrupts are refused.
+        context.set_cpsr(0x6000_01d3);
+        assert_eq!(context.cpsr(), 0x6000_0010);
+        assert!(!context.is_thumb());
+
+        assert_eq!(decode_svc(0xef90_0001, false), Some(0x90_0001));
+        assert_eq!(decode_svc(0xdf05, true), Some(5));
+        assert_eq!(decode_svc(0xe1a0_0000, false), None);
+        // Unconditional space, not an SVC.
+        assert_eq!(decode_svc(0xff00_0000, false), None);
+    }
+}
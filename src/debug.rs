@@ -0,0 +1,353 @@
+//! Hardware breakpoints, watchpoints and single-step of user programs.
+//!
+//! The debug registers of a user context are held in [`DebugState`], and loaded into the PE by
+//! [`UserContext::run`](crate::trap::UserContext::run) while the program runs. Debug events are
+//! reported as [`TrapReason::HardwareBreakpoint`](crate::trap::TrapReason::HardwareBreakpoint),
+//! [`TrapReason::Watchpoint`](crate::trap::TrapReason::Watchpoint) and
+//! [`TrapReason::SingleStep`](crate::trap::TrapReason::SingleStep).
+
+use crate::registers::{ReadWriteable, Readable, Writeable, ID_AA64DFR0_EL1, MDSCR_EL1};
+use bit_field::BitField;
+#[allow(unused_imports)]
+use core::arch::asm;
+
+/// The most breakpoints the architecture allows.
+pub const MAX_BREAKPOINTS: usize = 16;
+
+/// The most watchpoints the architecture allows.
+pub const MAX_WATCHPOINTS: usize = 16;
+
+/// DBGBCR/DBGWCR.E, the breakpoint or watchpoint is enabled.
+const DBG_CR_E: u64 = 1;
+/// DBGBCR.PMC/DBGWCR.PAC, matching at EL0 only.
+const DBG_CR_EL0: u64 = 0b10 << 1;
+/// DBGBCR.BAS, matching an A64 instruction.
+const DBGBCR_BAS_A64: u64 = 0xf << 5;
+
+/// Returns the number of breakpoints implemented by the PE.
+pub fn num_breakpoints() -> usize {
+    ID_AA64DFR0_EL1.read(ID_AA64DFR0_EL1::BRPs) as usize + 1
+}
+
+/// Returns the number of watchpoints implemented by the PE.
+pub fn num_watchpoints() -> usize {
+    ID_AA64DFR0_EL1.read(ID_AA64DFR0_EL1::WRPs) as usize + 1
+}
+
+/// Initializes the debug system of the current PE: the OS lock is released, so that debug
+/// exceptions can be taken, and they are all disabled.
+///
+/// # Safety
+///
+/// This must be called before running user programs with a [`DebugState`].
+pub unsafe fn init() {
+    #[cfg(target_arch = "aarch64")]
+    {
+        use crate::registers::OSLAR_EL1;
+        OSLAR_EL1.write(OSLAR_EL1::OSLK::Unlocked);
+        MDSCR_EL1.set(0);
+        crate::barrier::isb(crate::barrier::SY);
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    unimplemented!()
+}
+
+/// Enables or disables debug exceptions at EL1, i.e. breakpoints and watchpoints in the kernel.
+///
+/// They are still masked while PSTATE.D is set.
+pub fn set_kernel_debug(enable: bool) {
+    MDSCR_EL1.modify(if enable {
+        MDSCR_EL1::KDE::Enabled
+    } else {
+        MDSCR_EL1::KDE::Disabled
+    });
+}
+
+/// Disables breakpoints, watchpoints and single-step, e.g. when leaving a user program.
+pub fn disable() {
+    MDSCR_EL1.modify(MDSCR_EL1::MDE::Disabled + MDSCR_EL1::SS::Disabled);
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        crate::barrier::isb(crate::barrier::SY)
+    };
+}
+
+/// Which accesses a watchpoint matches.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum WatchKind {
+    Read = 0b01,
+    Write = 0b10,
+    ReadWrite = 0b11,
+}
+
+/// An error setting a breakpoint or watchpoint of a [`DebugState`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DebugError {
+    /// The breakpoint is not implemented by the PE, see [`num_breakpoints`].
+    InvalidBreakpoint(usize),
+    /// The watchpoint is not implemented by the PE, see [`num_watchpoints`].
+    InvalidWatchpoint(usize),
+    /// The watched bytes are empty, or cross an aligned doubleword.
+    InvalidRange,
+}
+
+/// Breakpoint and watchpoint registers, and single-step control, of a user context.
+///
+/// Only the registers implemented by the PE, see [`num_breakpoints`] and [`num_watchpoints`],
+/// are used.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+#[repr(C)]
+pub struct DebugState {
+    /// Breakpoint Value Registers, DBGBVR<n>_EL1
+    pub bvr: [u64; MAX_BREAKPOINTS],
+    /// Breakpoint Control Registers, DBGBCR<n>_EL1
+    pub bcr: [u64; MAX_BREAKPOINTS],
+    /// Watchpoint Value Registers, DBGWVR<n>_EL1
+    pub wvr: [u64; MAX_WATCHPOINTS],
+    /// Watchpoint Control Registers, DBGWCR<n>_EL1
+    pub wcr: [u64; MAX_WATCHPOINTS],
+    /// Whether the program traps after executing each instruction
+    pub single_step: bool,
+}
+
+impl DebugState {
+    /// Sets breakpoint `n` on the instruction at `addr`.
+    ///
+    /// Fails if the PE does not implement breakpoint `n`.
+    pub fn set_breakpoint(&mut self, n: usize, addr: usize) -> Result<(), DebugError> {
+        self.set_breakpoint_checked(n, addr, num_breakpoints())
+    }
+
+    fn set_breakpoint_checked(
+        &mut self,
+        n: usize,
+        addr: usize,
+        implemented: usize,
+    ) -> Result<(), DebugError> {
+        if n >= implemented.min(MAX_BREAKPOINTS) {
+            return Err(DebugError::InvalidBreakpoint(n));
+        }
+        self.bvr[n] = addr as u64 & !3;
+        self.bcr[n] = DBGBCR_BAS_A64 | DBG_CR_EL0 | DBG_CR_E;
+        Ok(())
+    }
+
+    /// Clears breakpoint `n`.
+    pub fn clear_breakpoint(&mut self, n: usize) {
+        self.bvr[n] = 0;
+        self.bcr[n] = 0;
+    }
+
+    /// Sets watchpoint `n` on the `len` bytes at `addr`, for the accesses `kind`.
+    ///
+    /// Fails if the PE does not implement watchpoint `n`, or if the bytes are not within an
+    /// aligned doubleword.
+    pub fn set_watchpoint(
+        &mut self,
+        n: usize,
+        addr: usize,
+        len: usize,
+        kind: WatchKind,
+    ) -> Result<(), DebugError> {
+        self.set_watchpoint_checked(n, addr, len, kind, num_watchpoints())
+    }
+
+    fn set_watchpoint_checked(
+        &mut self,
+        n: usize,
+        addr: usize,
+        len: usize,
+        kind: WatchKind,
+        implemented: usize,
+    ) -> Result<(), DebugError> {
+        if n >= implemented.min(MAX_WATCHPOINTS) {
+            return Err(DebugError::InvalidWatchpoint(n));
+        }
+        let offset = addr % 8;
+        if len == 0 || len > 8 - offset {
+            return Err(DebugError::InvalidRange);
+        }
+        let bas = ((1u64 << len) - 1) << offset;
+        self.wvr[n] = (addr - offset) as u64;
+        self.wcr[n] = bas << 5 | (kind as u64) << 3 | DBG_CR_EL0 | DBG_CR_E;
+        Ok(())
+    }
+
+    /// Clears watchpoint `n`.
+    pub fn clear_watchpoint(&mut self, n: usize) {
+        self.wvr[n] = 0;
+        self.wcr[n] = 0;
+    }
+
+    /// Returns whether any breakpoint or watchpoint is enabled.
+    pub fn has_hw_points(&self) -> bool {
+        self.bcr
+            .iter()
+            .chain(&self.wcr)
+            .any(|cr| cr & DBG_CR_E != 0)
+    }
+
+    /// Returns whether the debug registers need to be loaded to run the program.
+    pub fn is_active(&self) -> bool {
+        self.single_step || self.has_hw_points()
+    }
+
+    /// Loads the registers into the PE, enabling the debug events.
+    ///
+    /// # Safety
+    ///
+    /// The debug system must be initialized with [`init`]. The events are taken at EL0 only,
+    /// [`disable`] must be called when leaving the user program.
+    pub unsafe fn restore(&self) {
+        for n in 0..num_breakpoints() {
+            write_bvr(n, self.bvr[n]);
+            write_bcr(n, self.bcr[n]);
+        }
+        for n in 0..num_watchpoints() {
+            write_wvr(n, self.wvr[n]);
+            write_wcr(n, self.wcr[n]);
+        }
+        let mut mdscr = MDSCR_EL1.get();
+        mdscr.set_bit(15, self.has_hw_points());
+        mdscr.set_bit(0, self.single_step);
+        MDSCR_EL1.set(mdscr);
+        #[cfg(target_arch = "aarch64")]
+        crate::barrier::isb(crate::barrier::SY);
+    }
+
+    /// Saves the registers from the PE.
+    pub fn save(&mut self) {
+        for n in 0..num_breakpoints() {
+            self.bvr[n] = read_bvr(n);
+            self.bcr[n] = read_bcr(n);
+        }
+        for n in 0..num_watchpoints() {
+            self.wvr[n] = read_wvr(n);
+            self.wcr[n] = read_wcr(n);
+        }
+        self.single_step = MDSCR_EL1.is_set(MDSCR_EL1::SS);
+    }
+}
+
+/// Defines the functions reading and writing the debug registers `$name<n>_el1`.
+macro_rules! dbg_regs {
+    ($read:ident, $write:ident, $name:literal) => {
+        dbg_regs!(@ $read, $write, $name, 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15);
+    };
+    (@ $read:ident, $write:ident, $name:literal, $($i:literal)*) => {
+        fn $read(n: usize) -> u64 {
+            #[cfg(target_arch = "aarch64")]
+            {
+                let value: u64;
+                match n {
+                    $($i => unsafe {
+                        asm!(concat!("mrs {}, ", $name, $i, "_el1"), out(reg) value)
+                    },)*
+                    _ => panic!("invalid debug register {}", n),
+                }
+                value
+            }
+
+            #[cfg(not(target_arch = "aarch64"))]
+            {
+                let _ = n;
+                unimplemented!()
+            }
+        }
+
+        unsafe fn $write(n: usize, value: u64) {
+            #[cfg(target_arch = "aarch64")]
+            match n {
+                $($i => asm!(concat!("msr ", $name, $i, "_el1, {}"), in(reg) value),)*
+                _ => panic!("invalid debug register {}", n),
+            }
+
+            #[cfg(not(target_arch = "aarch64"))]
+            {
+                let _ = (n, value);
+                unimplemented!()
+            }
+        }
+    };
+}
+
+dbg_regs!(read_bvr, write_bvr, "dbgbvr");
+dbg_regs!(read_bcr, write_bcr, "dbgbcr");
+dbg_regs!(read_wvr, write_wvr, "dbgwvr");
+dbg_regs!(read_wcr, write_wcr, "dbgwcr");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        addr::VirtAddr,
+        exception::ExceptionInfo,
+        trap::{TrapKind, TrapReason},
+    };
+
+    #[test]
+    pub fn test_debug_state() {
+        let mut state = DebugState::default();
+        assert!(!state.is_active());
+        state.set_breakpoint_checked(1, 0x40_1002, 2).unwrap();
+        assert_eq!((state.bvr[1], state.bcr[1]), (0x40_1000, 0x1e5));
+        state
+            .set_watchpoint_checked(0, 0x7000_0004, 4, WatchKind::Write, 2)
+            .unwrap();
+        assert_eq!((state.wvr[0], state.wcr[0]), (0x7000_0000, 0x1e15));
+
+        // Points not implemented by the PE
+        assert_eq!(
+            state.set_breakpoint_checked(2, 0x40_1000, 2),
+            Err(DebugError::InvalidBreakpoint(2))
+        );
+        assert_eq!(
+            state.set_watchpoint_checked(MAX_WATCHPOINTS, 0x7000_0000, 8, WatchKind::Read, 64),
+            Err(DebugError::InvalidWatchpoint(MAX_WATCHPOINTS))
+        );
+        assert_eq!((state.bvr[2], state.bcr[2]), (0, 0));
+
+        // Bytes out of a doubleword
+        for (addr, len) in [
+            (0x7000_0004, 0),
+            (0x7000_0004, 5),
+            (0x7000_0000, usize::MAX),
+        ] {
+            assert_eq!(
+                state.set_watchpoint_checked(1, addr, len, WatchKind::Read, 2),
+                Err(DebugError::InvalidRange)
+            );
+        }
+        assert!(state.is_active());
+        state.clear_breakpoint(1);
+        state.clear_watchpoint(0);
+        assert!(!state.is_active());
+
+        // Watchpoint on a write, from EL0.
+        let info = ExceptionInfo::new(0xd200_0062, 0x7000_0006);
+        assert_eq!(
+            TrapReason::new(TrapKind::Synchronous, info),
+            TrapReason::Watchpoint {
+                addr: VirtAddr::new(0x7000_0006),
+                write: true
+            }
+        );
+        // Without a valid FAR (FnV)
+        let info = ExceptionInfo::new(0xd200_0462, 0x7000_0006);
+        assert!(matches!(
+            TrapReason::new(TrapKind::Synchronous, info),
+            TrapReason::Other(_)
+        ));
+        let info = ExceptionInfo::new(0xca00_0022, 0);
+        assert_eq!(
+            TrapReason::new(TrapKind::Synchronous, info),
+            TrapReason::SingleStep
+        );
+        let info = ExceptionInfo::new(0xc200_0022, 0);
+        assert_eq!(
+            TrapReason::new(TrapKind::Synchronous, info),
+            TrapReason::HardwareBreakpoint
+        );
+    }
+}
@@ -0,0 +1,565 @@
+//! Decoding of the exception syndrome, ESR_EL1 and FAR_EL1.
+
+use crate::{addr::VirtAddr, registers::*, translation::FaultStatus};
+use bit_field::BitField;
+
+/// Exception Class, ESR_ELx.EC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionClass {
+    /// Unknown reason, e.g. an undefined instruction.
+    Unknown,
+    /// Trapped WFI, WFE, WFIT or WFET instruction.
+    WfiWfe,
+    /// Trapped MCR or MRC access with coproc 0b1111 (AArch32).
+    Mcr15,
+    /// Trapped MCRR or MRRC access with coproc 0b1111 (AArch32).
+    Mcrr15,
+    /// Trapped MCR or MRC access with coproc 0b1110 (AArch32).
+    Mcr14,
+    /// Trapped LDC or STC access (AArch32).
+    Ldc14,
+    /// Access to SVE, Advanced SIMD or floating-point functionality trapped by CPACR_EL1.FPEN,
+    /// CPTR_ELx.TFP or CPTR_ELx.FPEN.
+    FpSimdAccess,
+    /// Trapped VMRS access, from ID group traps.
+    Vmrs,
+    /// Trapped use of a pointer authentication instruction (FEAT_PAuth).
+    PointerAuth,
+    /// Trapped LD64B, ST64B, ST64BV or ST64BV0 instruction (FEAT_LS64).
+    Ld64b,
+    /// Trapped MRRC access with coproc 0b1110 (AArch32).
+    Mrrc14,
+    /// Branch Target Exception (FEAT_BTI).
+    BranchTarget,
+    /// Illegal Execution state.
+    IllegalState,
+    /// SVC instruction execution in AArch32 state.
+    Svc32,
+    /// HVC instruction execution in AArch32 state.
+    Hvc32,
+    /// SMC instruction execution in AArch32 state.
+    Smc32,
+    /// SVC instruction execution in AArch64 state.
+    Svc64,
+    /// HVC instruction execution in AArch64 state.
+    Hvc64,
+    /// SMC instruction execution in AArch64 state.
+    Smc64,
+    /// Trapped MSR, MRS or System instruction execution in AArch64 state.
+    MsrMrs,
+    /// Access to SVE functionality trapped by CPACR_EL1.ZEN or CPTR_ELx.
+    Sve,
+    /// Trapped ERET, ERETAA or ERETAB instruction (FEAT_NV).
+    Eret,
+    /// Trapped TSTART instruction (FEAT_TME).
+    Tstart,
+    /// Pointer authentication failure (FEAT_FPAC).
+    Fpac,
+    /// Access to SME functionality trapped (FEAT_SME).
+    Sme,
+    /// Granule Protection Check exception (FEAT_RME).
+    GranuleProtection,
+    /// IMPLEMENTATION DEFINED exception taken to EL3.
+    ImplementationDefined,
+    /// Instruction Abort from a lower Exception level.
+    InstructionAbortLower,
+    /// Instruction Abort taken without a change in Exception level.
+    InstructionAbortSame,
+    /// PC alignment fault.
+    PcAlignment,
+    /// Data Abort from a lower Exception level.
+    DataAbortLower,
+    /// Data Abort taken without a change in Exception level.
+    DataAbortSame,
+    /// SP alignment fault.
+    SpAlignment,
+    /// Memory Operation exception (FEAT_MOPS).
+    MemoryOperation,
+    /// Trapped floating-point exception from AArch32 state.
+    Fp32,
+    /// Trapped floating-point exception from AArch64 state.
+    Fp64,
+    /// Guarded Control Stack exception (FEAT_GCS).
+    GuardedControlStack,
+    /// SError interrupt.
+    SError,
+    /// Breakpoint exception from a lower Exception level.
+    BreakpointLower,
+    /// Breakpoint exception taken without a change in Exception level.
+    BreakpointSame,
+    /// Software Step exception from a lower Exception level.
+    SoftwareStepLower,
+    /// Software Step exception taken without a change in Exception level.
+    SoftwareStepSame,
+    /// Watchpoint exception from a lower Exception level.
+    WatchpointLower,
+    /// Watchpoint exception taken without a change in Exception level.
+    WatchpointSame,
+    /// BKPT instruction execution in AArch32 state.
+    Bkpt32,
+    /// Vector Catch exception from AArch32 state.
+    VectorCatch,
+    /// BRK instruction execution in AArch64 state.
+    Brk64,
+    /// Profiling exception (FEAT_SPE_EXC).
+    Profiling,
+    /// Unallocated EC value.
+    Reserved(u8),
+}
+
+impl ExceptionClass {
+    /// Decodes a 6-bit EC value.
+    pub fn from_bits(ec: u8) -> Self {
+        use ExceptionClass::*;
+
+        match ec & 0b11_1111 {
+            0x00 => Unknown,
+            0x01 => WfiWfe,
+            0x03 => Mcr15,
+            0x04 => Mcrr15,
+            0x05 => Mcr14,
+            0x06 => Ldc14,
+            0x07 => FpSimdAccess,
+            0x08 => Vmrs,
+            0x09 => PointerAuth,
+            0x0a => Ld64b,
+            0x0c => Mrrc14,
+            0x0d => BranchTarget,
+            0x0e => IllegalState,
+            0x11 => Svc32,
+            0x12 => Hvc32,
+            0x13 => Smc32,
+            0x15 => Svc64,
+            0x16 => Hvc64,
+            0x17 => Smc64,
+            0x18 => MsrMrs,
+            0x19 => Sve,
+            0x1a => Eret,
+            0x1b => Tstart,
+            0x1c => Fpac,
+            0x1d => Sme,
+            0x1e => GranuleProtection,
+            0x1f => ImplementationDefined,
+            0x20 => InstructionAbortLower,
+            0x21 => InstructionAbortSame,
+            0x22 => PcAlignment,
+            0x24 => DataAbortLower,
+            0x25 => DataAbortSame,
+            0x26 => SpAlignment,
+            0x27 => MemoryOperation,
+            0x28 => Fp32,
+            0x2c => Fp64,
+            0x2d => GuardedControlStack,
+            0x2f => SError,
+            0x30 => BreakpointLower,
+            0x31 => BreakpointSame,
+            0x32 => SoftwareStepLower,
+            0x33 => SoftwareStepSame,
+            0x34 => WatchpointLower,
+            0x35 => WatchpointSame,
+            0x38 => Bkpt32,
+            0x3a => VectorCatch,
+            0x3c => Brk64,
+            0x3d => Profiling,
+            ec => Reserved(ec),
+        }
+    }
+
+    /// Returns whether FAR_EL1 may hold the faulting address for this class.
+    ///
+    /// For aborts, the address is only valid when the syndrome doesn't report it otherwise,
+    /// see [`ExceptionInfo::far`].
+    pub fn has_far(self) -> bool {
+        use ExceptionClass::*;
+
+        matches!(
+            self,
+            InstructionAbortLower
+                | InstructionAbortSame
+                | PcAlignment
+                | DataAbortLower
+                | DataAbortSame
+                | WatchpointLower
+                | WatchpointSame
+        )
+    }
+}
+
+/// The access that caused a Data Abort, when the syndrome is valid (ISV).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataAccess {
+    /// The size of the access in bytes (SAS).
+    pub size: u8,
+    /// Whether the loaded value is sign-extended (SSE).
+    pub sign_extend: bool,
+    /// The register transferred (SRT), 31 being XZR.
+    pub register: u8,
+    /// Whether the register is 64 bits wide (SF).
+    pub sixty_four: bool,
+    /// Whether the instruction has acquire/release semantics (AR).
+    pub acquire_release: bool,
+}
+
+/// ISS of a Data Abort.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataAbort {
+    /// Data Fault Status Code (DFSC).
+    pub status: FaultStatus,
+    /// Whether the abort was caused by a write (WnR).
+    pub write: bool,
+    /// Whether the fault is a stage 2 fault on a stage 1 translation table walk (S1PTW).
+    pub s1ptw: bool,
+    /// Whether the abort came from a cache maintenance or address translation instruction
+    /// (CM).
+    pub cache_maintenance: bool,
+    /// Whether FAR_EL1 holds the faulting address, i.e. FnV is clear.
+    pub far_valid: bool,
+    /// The External abort type (EA).
+    pub external: bool,
+    /// The faulting access, if the syndrome is valid (ISV).
+    pub access: Option<DataAccess>,
+}
+
+impl DataAbort {
+    /// Decodes the ISS of a Data Abort.
+    pub fn from_iss(iss: u32) -> Self {
+        let access = if iss.get_bit(24) {
+            Some(DataAccess {
+                size: 1 << iss.get_bits(22..24),
+                sign_extend: iss.get_bit(21),
+                register: iss.get_bits(16..21) as u8,
+                sixty_four: iss.get_bit(15),
+                acquire_release: iss.get_bit(14),
+            })
+        } else {
+            None
+        };
+        Self {
+            status: FaultStatus::from_code(iss.get_bits(0..6) as u8),
+            write: iss.get_bit(6),
+            s1ptw: iss.get_bit(7),
+            cache_maintenance: iss.get_bit(8),
+            far_valid: !iss.get_bit(10),
+            external: iss.get_bit(9),
+            access,
+        }
+    }
+
+    /// Returns the lookup level of the fault, if any.
+    #[inline]
+    pub fn level(&self) -> Option<u8> {
+        self.status.level()
+    }
+}
+
+/// ISS of an Instruction Abort.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstructionAbort {
+    /// Instruction Fault Status Code (IFSC).
+    pub status: FaultStatus,
+    /// Whether the fault is a stage 2 fault on a stage 1 translation table walk (S1PTW).
+    pub s1ptw: bool,
+    /// Whether FAR_EL1 holds the faulting address, i.e. FnV is clear.
+    pub far_valid: bool,
+    /// The External abort type (EA).
+    pub external: bool,
+}
+
+impl InstructionAbort {
+    /// Decodes the ISS of an Instruction Abort.
+    pub fn from_iss(iss: u32) -> Self {
+        Self {
+            status: FaultStatus::from_code(iss.get_bits(0..6) as u8),
+            s1ptw: iss.get_bit(7),
+            far_valid: !iss.get_bit(10),
+            external: iss.get_bit(9),
+        }
+    }
+
+    /// Returns the lookup level of the fault, if any.
+    #[inline]
+    pub fn level(&self) -> Option<u8> {
+        self.status.level()
+    }
+}
+
+/// ISS of a trapped MSR, MRS or System instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SystemRegisterAccess {
+    pub op0: u8,
+    pub op1: u8,
+    pub op2: u8,
+    pub crn: u8,
+    pub crm: u8,
+    /// The register transferred, 31 being XZR.
+    pub rt: u8,
+    /// Whether the access is a read (MRS), or a write (MSR).
+    pub read: bool,
+}
+
+impl SystemRegisterAccess {
+    /// Decodes the ISS of a trapped MSR, MRS or System instruction.
+    pub fn from_iss(iss: u32) -> Self {
+        Self {
+            op0: iss.get_bits(20..22) as u8,
+            op2: iss.get_bits(17..20) as u8,
+            op1: iss.get_bits(14..17) as u8,
+            crn: iss.get_bits(10..14) as u8,
+            rt: iss.get_bits(5..10) as u8,
+            crm: iss.get_bits(1..5) as u8,
+            read: iss.get_bit(0),
+        }
+    }
+}
+
+/// ISS of a trapped floating-point exception.
+///
+/// The flags are only valid when `valid` (TFV) is set, and record the exceptions that occurred
+/// in the element given by `vector_iteration`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FpException {
+    /// Whether the flags below are valid (TFV).
+    pub valid: bool,
+    /// The number of the vector iteration that caused the exception, minus one (VECITR).
+    pub vector_iteration: u8,
+    /// Input Denormal (IDF).
+    pub input_denormal: bool,
+    /// Inexact (IXF).
+    pub inexact: bool,
+    /// Underflow (UFF).
+    pub underflow: bool,
+    /// Overflow (OFF).
+    pub overflow: bool,
+    /// Divide by Zero (DZF).
+    pub divide_by_zero: bool,
+    /// Invalid Operation (IOF).
+    pub invalid_operation: bool,
+}
+
+impl FpException {
+    /// Decodes the ISS of a trapped floating-point exception.
+    pub fn from_iss(iss: u32) -> Self {
+        Self {
+            valid: iss.get_bit(23),
+            vector_iteration: iss.get_bits(8..11) as u8,
+            input_denormal: iss.get_bit(7),
+            inexact: iss.get_bit(4),
+            underflow: iss.get_bit(3),
+            overflow: iss.get_bit(2),
+            divide_by_zero: iss.get_bit(1),
+            invalid_operation: iss.get_bit(0),
+        }
+    }
+}
+
+/// Error state of an SError interrupt (FEAT_RAS).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SErrorState {
+    /// Uncontainable.
+    Uncontainable,
+    /// Unrecoverable state.
+    Unrecoverable,
+    /// Restartable state.
+    Restartable,
+    /// Recoverable state.
+    Recoverable,
+    /// Corrected.
+    Corrected,
+    /// Reserved value.
+    Reserved(u8),
+}
+
+/// ISS of an SError interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SError {
+    /// The syndrome is IMPLEMENTATION DEFINED (IDS), given as the low 24 bits.
+    ImplementationDefined(u32),
+    /// The syndrome is architectural.
+    Architectural {
+        /// Whether the exception was taken on an implicit error synchronization event (IESB).
+        iesb: bool,
+        /// Asynchronous Error Type (AET), only meaningful for an Asynchronous SError.
+        state: SErrorState,
+        /// The External abort type (EA).
+        external: bool,
+        /// Data Fault Status Code (DFSC), either Uncategorized or Asynchronous SError.
+        status: u8,
+    },
+}
+
+impl SError {
+    /// Decodes the ISS of an SError interrupt.
+    pub fn from_iss(iss: u32) -> Self {
+        if iss.get_bit(24) {
+            return SError::ImplementationDefined(iss.get_bits(0..24));
+        }
+        let state = match iss.get_bits(10..13) as u8 {
+            0b000 => SErrorState::Uncontainable,
+            0b001 => SErrorState::Unrecoverable,
+            0b010 => SErrorState::Restartable,
+            0b011 => SErrorState::Recoverable,
+            0b110 => SErrorState::Corrected,
+            aet => SErrorState::Reserved(aet),
+        };
+        SError::Architectural {
+            iesb: iss.get_bit(13),
+            state,
+            external: iss.get_bit(9),
+            status: iss.get_bits(0..6) as u8,
+        }
+    }
+}
+
+/// The decoded ISS, for the exception classes with a known encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syndrome {
+    DataAbort(DataAbort),
+    InstructionAbort(InstructionAbort),
+    /// The immediate of an SVC instruction.
+    Svc(u16),
+    /// The immediate of an HVC instruction.
+    Hvc(u16),
+    /// The immediate of an SMC instruction.
+    Smc(u16),
+    /// The comment of a BRK instruction.
+    Brk(u16),
+    /// The comment of a BKPT instruction.
+    Bkpt(u16),
+    SystemRegister(SystemRegisterAccess),
+    FpException(FpException),
+    SError(SError),
+    /// The ISS has no decoder, or no meaning.
+    Other,
+}
+
+/// Everything known about a synchronous exception or SError, from ESR_EL1 and FAR_EL1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExceptionInfo {
+    /// The Exception Class.
+    pub class: ExceptionClass,
+    /// Whether the trapped instruction is 32 bits wide (IL). It is 16 bits wide for T32
+    /// instructions otherwise.
+    pub il: bool,
+    /// The raw Instruction Specific Syndrome.
+    pub iss: u32,
+    /// The decoded Instruction Specific Syndrome.
+    pub syndrome: Syndrome,
+    /// The faulting virtual address, if FAR_EL1 is valid for this exception.
+    pub far: Option<VirtAddr>,
+}
+
+impl ExceptionInfo {
+    /// Decodes the values of ESR_EL1 and FAR_EL1.
+    pub fn new(esr: u64, far: u64) -> Self {
+        use ExceptionClass::*;
+
+        let class = ExceptionClass::from_bits(esr.get_bits(26..32) as u8);
+        let iss = esr.get_bits(0..25) as u32;
+        let imm16 = iss.get_bits(0..16) as u16;
+        let syndrome = match class {
+            DataAbortLower | DataAbortSame => Syndrome::DataAbort(DataAbort::from_iss(iss)),
+            InstructionAbortLower | InstructionAbortSame => {
+                Syndrome::InstructionAbort(InstructionAbort::from_iss(iss))
+            }
+            Svc32 | Svc64 => Syndrome::Svc(imm16),
+            Hvc32 | Hvc64 => Syndrome::Hvc(imm16),
+            Smc32 | Smc64 => Syndrome::Smc(imm16),
+            Brk64 => Syndrome::Brk(imm16),
+            Bkpt32 => Syndrome::Bkpt(imm16),
+            MsrMrs => Syndrome::SystemRegister(SystemRegisterAccess::from_iss(iss)),
+            Fp32 | Fp64 => Syndrome::FpException(FpException::from_iss(iss)),
+            ExceptionClass::SError => Syndrome::SError(self::SError::from_iss(iss)),
+            _ => Syndrome::Other,
+        };
+        let far_valid = match syndrome {
+            Syndrome::DataAbort(abort) => abort.far_valid,
+            Syndrome::InstructionAbort(abort) => abort.far_valid,
+            // FnV, as for aborts
+            _ if matches!(class, WatchpointLower | WatchpointSame) => !iss.get_bit(10),
+            _ => class.has_far(),
+        };
+
+        Self {
+            class,
+            il: esr.get_bit(25),
+            iss,
+            syndrome,
+            far: far_valid.then(|| VirtAddr::new(far)),
+        }
+    }
+
+    /// Reads ESR_EL1 and FAR_EL1 of the current PE.
+    ///
+    /// Must be called in the exception handler, before anything else may overwrite them.
+    #[inline]
+    pub fn current() -> Self {
+        Self::new(ESR_EL1.get(), FAR_EL1.get())
+    }
+
+    /// Returns the size of the trapped instruction in bytes.
+    #[inline]
+    pub fn instruction_len(&self) -> usize {
+        if self.il {
+            4
+        } else {
+            2
+        }
+    }
+
+    /// Returns the lookup level of an abort, if any.
+    pub fn fault_level(&self) -> Option<u8> {
+        match self.syndrome {
+            Syndrome::DataAbort(abort) => abort.level(),
+            Syndrome::InstructionAbort(abort) => abort.level(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_decode_esr() {
+        // Write translation fault at level 3 from EL0, with a valid syndrome for `str w2, [x0]`.
+        let info = ExceptionInfo::new(0x9382_0047, 0x1234);
+        assert_eq!(info.class, ExceptionClass::DataAbortLower);
+        assert_eq!(info.far, Some(VirtAddr::new(0x1234)));
+        assert_eq!(info.fault_level(), Some(3));
+        match info.syndrome {
+            Syndrome::DataAbort(abort) => {
+                assert!(abort.write);
+                assert_eq!(abort.status, FaultStatus::Translation { level: 3 });
+                let access = abort.access.unwrap();
+                assert_eq!((access.size, access.register), (4, 2));
+                assert!(!access.sixty_four);
+            }
+            syndrome => panic!("unexpected {:?}", syndrome),
+        }
+
+        // `svc #0x10`
+        let info = ExceptionInfo::new(0x5600_0010, 0xdead);
+        assert_eq!(info.syndrome, Syndrome::Svc(0x10));
+        assert_eq!(info.far, None);
+
+        // `mrs x3, ctr_el0` trapped by SCTLR_EL1.UCT
+        let info = ExceptionInfo::new(0x6232_c061, 0);
+        match info.syndrome {
+            Syndrome::SystemRegister(access) => {
+                assert_eq!(
+                    (access.op0, access.op1, access.crn, access.crm),
+                    (3, 3, 0, 0)
+                );
+                assert_eq!((access.op2, access.rt), (1, 3));
+                assert!(access.read);
+            }
+            syndrome => panic!("unexpected {:?}", syndrome),
+        }
+
+        assert_eq!(
+            ExceptionClass::from_bits(0x3e),
+            ExceptionClass::Reserved(0x3e)
+        );
+    }
+}
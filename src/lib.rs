@@ -7,9 +7,19 @@ pub mod addr;
 pub mod asm;
 pub mod barrier;
 pub mod cache;
+pub mod compat;
+pub mod debug;
+pub mod exception;
+pub mod mmu;
+pub mod mte;
 pub mod paging;
+pub mod pauth;
 pub mod registers;
+pub mod signal;
+pub mod sve;
+pub mod syscall;
 pub mod translation;
 pub mod trap;
+pub mod unwind;
 
 pub extern crate ux;
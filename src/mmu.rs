@@ -0,0 +1,170 @@
+//! Enabling and disabling the stage 1 MMU of the EL1&0 translation regime.
+
+#[cfg(target_arch = "aarch64")]
+use crate::{barrier, cache::dcache_set_way_all, translation};
+use crate::{
+    paging::{memory_attribute, tcr::Tcr, AddressSpace, Frame},
+    registers::*,
+    translation::Ttbr,
+};
+#[cfg(target_arch = "aarch64")]
+use core::arch::asm;
+
+/// Everything needed to turn the MMU on.
+#[derive(Debug, Clone, Copy)]
+pub struct MmuConfig {
+    mair: u64,
+    tcr: Tcr,
+    lower: AddressSpace,
+    upper: AddressSpace,
+    data_cache: bool,
+    instruction_cache: bool,
+    write_execute_never: bool,
+    stack_alignment_check: bool,
+}
+
+impl MmuConfig {
+    /// Creates a configuration with the level 4 page tables `lower` and `upper`, for TTBR0_EL1
+    /// and TTBR1_EL1 respectively.
+    ///
+    /// MAIR_EL1 describes the memory types of [`memory_attribute`], the caches and SP alignment
+    /// checking are enabled, and WXN is disabled.
+    pub fn new(tcr: Tcr, lower: Frame, upper: Frame) -> Self {
+        Self {
+            mair: memory_attribute::mair_value(),
+            tcr,
+            lower: AddressSpace::new(Ttbr::Lower, lower, 0),
+            upper: AddressSpace::new(Ttbr::Upper, upper, 0),
+            data_cache: true,
+            instruction_cache: true,
+            write_execute_never: false,
+            stack_alignment_check: true,
+        }
+    }
+
+    /// Sets the value of MAIR_EL1.
+    pub fn with_mair(mut self, mair: u64) -> Self {
+        self.mair = mair;
+        self
+    }
+
+    /// Sets the address space installed in TTBR0_EL1, e.g. to give it an ASID.
+    pub fn with_lower(mut self, lower: AddressSpace) -> Self {
+        debug_assert_eq!(lower.ttbr(), Ttbr::Lower);
+        self.lower = lower;
+        self
+    }
+
+    /// Sets the address space installed in TTBR1_EL1.
+    pub fn with_upper(mut self, upper: AddressSpace) -> Self {
+        debug_assert_eq!(upper.ttbr(), Ttbr::Upper);
+        self.upper = upper;
+        self
+    }
+
+    /// Enables the data and instruction caches (SCTLR_EL1.C and SCTLR_EL1.I).
+    pub fn with_caches(mut self, data: bool, instruction: bool) -> Self {
+        self.data_cache = data;
+        self.instruction_cache = instruction;
+        self
+    }
+
+    /// Makes every writable region execute-never (SCTLR_EL1.WXN).
+    pub fn with_write_execute_never(mut self, enabled: bool) -> Self {
+        self.write_execute_never = enabled;
+        self
+    }
+
+    /// Enables SP alignment checking at EL1 (SCTLR_EL1.SA).
+    pub fn with_stack_alignment_check(mut self, enabled: bool) -> Self {
+        self.stack_alignment_check = enabled;
+        self
+    }
+}
+
+/// Returns whether the stage 1 MMU of the current PE is on.
+#[inline]
+pub fn is_enabled() -> bool {
+    SCTLR_EL1.matches_all(SCTLR_EL1::M::Enable)
+}
+
+/// Turns the MMU of the current PE on.
+///
+/// The local TLB is invalidated, then MAIR_EL1, TCR_EL1 and both TTBRs are written and
+/// synchronized before SCTLR_EL1 is updated. The I-Cache is invalidated afterwards, to discard
+/// instructions fetched while the MMU was off.
+///
+/// ## Safety
+///
+/// This function is unsafe because the page tables must map the code calling it at the address
+/// it is running from (usually by an identity mapping), as well as its stack.
+pub unsafe fn enable(config: &MmuConfig) {
+    #[cfg(target_arch = "aarch64")]
+    {
+        translation::local_invalidate_tlb_all();
+
+        MAIR_EL1.set(config.mair);
+        TCR_EL1.set(config.tcr.value());
+        // Each activation ends with an ISB, which also synchronizes MAIR_EL1 and TCR_EL1.
+        config.lower.activate();
+        config.upper.activate();
+
+        SCTLR_EL1.modify(
+            SCTLR_EL1::M::Enable
+                + SCTLR_EL1::C.val(config.data_cache as u64)
+                + SCTLR_EL1::I.val(config.instruction_cache as u64)
+                + SCTLR_EL1::WXN.val(config.write_execute_never as u64)
+                + SCTLR_EL1::SA.val(config.stack_alignment_check as u64),
+        );
+        barrier::isb(barrier::SY);
+
+        asm!("ic iallu; dsb nsh; isb");
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        let _ = config;
+        unimplemented!()
+    }
+}
+
+/// Turns the MMU and the caches of the current PE off.
+///
+/// The data cache is turned off first, then cleaned and invalidated to the Point of Coherency
+/// by set/way, so that memory is up to date once accesses become non-cacheable. This is what
+/// kexec and suspend need before handing memory over.
+///
+/// The whole sequence runs without touching memory, since the stack may be cached.
+///
+/// ## Safety
+///
+/// This function is unsafe because the caller must be running from an identity mapping, and
+/// every other PE sharing the caches must have stopped allocating into them.
+pub unsafe fn disable() {
+    #[cfg(target_arch = "aarch64")]
+    asm!(
+        "
+        mrs     x0, sctlr_el1
+        bic     x0, x0, #(1 << 2)
+        msr     sctlr_el1, x0
+        isb
+        ",
+        dcache_set_way_all!("cisw"),
+        "
+        mrs     x0, sctlr_el1
+        bic     x0, x0, #(1 << 0)
+        bic     x0, x0, #(1 << 12)
+        msr     sctlr_el1, x0
+        isb
+        ic      iallu
+        tlbi    vmalle1
+        dsb     nsh
+        isb
+        ",
+        out("x0") _, out("x1") _, out("x2") _, out("x3") _, out("x4") _, out("x5") _,
+        out("x6") _, out("x7") _, out("x9") _, out("x10") _, out("x11") _,
+    );
+
+    #[cfg(not(target_arch = "aarch64"))]
+    unimplemented!()
+}
@@ -0,0 +1,444 @@
+//! Memory Tagging Extension (FEAT_MTE2).
+//!
+//! Memory mapped as [`MairNormalTagged`] holds a 4-bit Allocation Tag for each granule of
+//! [`TAG_GRANULE`] bytes, and pointers hold a Logical Tag in bits 59:56. With tag checking
+//! enabled, see [`enable`] and [`MteState::tcf0`], an access through a pointer whose tag does
+//! not match the memory faults, e.g. after the memory is freed and tagged again.
+//!
+//! Tag checking also needs the top byte of the addresses to be ignored, see
+//! [`TcrConfig::with_top_byte_ignored`].
+//!
+//! # Example
+//! ```no_run
+//! # use aarch64::{addr::VirtAddr, mte};
+//! # let (addr, size) = (VirtAddr::new(0x4000), 0x100);
+//! // Allocation: a random tag, excluding the one of the previous allocation.
+//! let ptr = unsafe { mte::irg(addr, 1 << mte::tag_of(addr)) };
+//! unsafe { mte::set_tags(ptr, size) };
+//! ```
+//!
+//! [`MairNormalTagged`]: crate::paging::memory_attribute::MairNormalTagged
+//! [`TcrConfig::with_top_byte_ignored`]: crate::paging::tcr::TcrConfig::with_top_byte_ignored
+
+use crate::{
+    addr::VirtAddr,
+    paging::{Page, PageSize},
+    registers::{ReadWriteable, SCTLR_EL1},
+    trap::MAX_CPUS,
+};
+use bit_field::BitField;
+#[allow(unused_imports)]
+use core::arch::asm;
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The bytes of memory sharing an Allocation Tag.
+pub const TAG_GRANULE: usize = 16;
+
+/// Returns whether the Memory Tagging Extension is implemented, with tags stored in memory.
+pub fn is_supported() -> bool {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let pfr1: u64;
+        unsafe { asm!("mrs {}, id_aa64pfr1_el1", out(reg) pfr1) };
+        // MTE, 0b0001 being the instructions only
+        pfr1.get_bits(8..12) >= 0b0010
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    unimplemented!()
+}
+
+/// What happens on a Tag Check Fault.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum TagCheckFault {
+    /// Tags are not checked.
+    #[default]
+    Ignore = 0b00,
+    /// The access is aborted.
+    Sync = 0b01,
+    /// The fault is recorded in TFSR_EL1 or TFSRE0_EL1.
+    Async = 0b10,
+    /// Reads are aborted, writes recorded (FEAT_MTE3).
+    Asymmetric = 0b11,
+}
+
+/// GCR_EL1 of the kernel, restored when leaving user programs.
+static KERNEL_GCR: AtomicU64 = AtomicU64::new(0);
+
+/// RGSR_EL1 of the kernel on each CPU while a user program runs, restored when leaving it.
+#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+static KERNEL_RGSR: [AtomicU64; MAX_CPUS] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// Enables the tag instructions at EL0 and EL1, and tag checking in the kernel.
+///
+/// Tag checking at EL0 stays disabled for the user contexts without an [`MteState`].
+///
+/// The random tags of the kernel never take a value of `exclude`, a mask of tags.
+///
+/// # Safety
+///
+/// MTE must be implemented, see [`is_supported`], and the tags of the memory accessed by the
+/// kernel must match its pointers.
+pub unsafe fn enable(tcf: TagCheckFault, exclude: u16) {
+    let gcr = gcr_value(exclude);
+    KERNEL_GCR.store(gcr, Ordering::Relaxed);
+    write_gcr(gcr);
+    SCTLR_EL1.modify(
+        SCTLR_EL1::ATA::Enable
+            + SCTLR_EL1::ATA0::Enable
+            + SCTLR_EL1::TCF.val(tcf as u64)
+            + SCTLR_EL1::TCF0.val(TagCheckFault::Ignore as u64),
+    );
+    #[cfg(target_arch = "aarch64")]
+    {
+        asm!("msr S3_0_C5_C6_1, xzr");
+        crate::barrier::isb(crate::barrier::SY);
+    }
+}
+
+/// GCR_EL1 with the random tags enabled (RRND clear), excluding the tags of `exclude`.
+fn gcr_value(exclude: u16) -> u64 {
+    exclude as u64
+}
+
+/// Returns `addr` with the Logical Tag `tag`.
+pub fn with_tag(addr: VirtAddr, tag: u8) -> VirtAddr {
+    let mut addr = addr.as_u64();
+    addr.set_bits(56..60, tag as u64 & 0xf);
+    VirtAddr::new(addr)
+}
+
+/// Returns the Logical Tag of `addr`.
+pub fn tag_of(addr: VirtAddr) -> u8 {
+    addr.as_u64().get_bits(56..60) as u8
+}
+
+/// Returns `addr` with a random Logical Tag, not one of the mask `exclude` nor one excluded by
+/// GCR_EL1 (IRG).
+///
+/// # Safety
+///
+/// The tag instructions must be enabled, see [`enable`].
+pub unsafe fn irg(addr: VirtAddr, exclude: u64) -> VirtAddr {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let tagged: u64;
+        asm!(
+            ".arch_extension memtag",
+            "irg {}, {}, {}",
+            out(reg) tagged,
+            in(reg) addr.as_u64(),
+            in(reg) exclude,
+            options(nomem, nostack),
+        );
+        VirtAddr::new(tagged)
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        let _ = (addr, exclude);
+        unimplemented!()
+    }
+}
+
+/// Returns the mask `exclude` with the Logical Tag of `addr` added to it (GMI).
+///
+/// # Safety
+///
+/// The tag instructions must be enabled, see [`enable`].
+pub unsafe fn gmi(addr: VirtAddr, exclude: u64) -> u64 {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let mask: u64;
+        asm!(
+            ".arch_extension memtag",
+            "gmi {}, {}, {}",
+            out(reg) mask,
+            in(reg) addr.as_u64(),
+            in(reg) exclude,
+            options(nomem, nostack),
+        );
+        mask
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        let _ = (addr, exclude);
+        unimplemented!()
+    }
+}
+
+/// Returns `addr` with the Allocation Tag of its granule as Logical Tag (LDG).
+///
+/// # Safety
+///
+/// The tag instructions must be enabled, and `addr` mapped.
+pub unsafe fn ldg(addr: VirtAddr) -> VirtAddr {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let mut tagged = addr.as_u64();
+        asm!(
+            ".arch_extension memtag",
+            "ldg {0}, [{0}]",
+            inout(reg) tagged,
+            options(readonly, nostack),
+        );
+        VirtAddr::new(tagged)
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        let _ = addr;
+        unimplemented!()
+    }
+}
+
+/// Sets the Allocation Tag of the granule at `addr` to its Logical Tag (STG).
+///
+/// # Safety
+///
+/// The tag instructions must be enabled, and `addr` mapped and aligned to [`TAG_GRANULE`].
+/// Accesses to the granule through pointers with other tags fault afterwards.
+pub unsafe fn stg(addr: VirtAddr) {
+    #[cfg(target_arch = "aarch64")]
+    asm!(
+        ".arch_extension memtag",
+        "stg {0}, [{0}]",
+        in(reg) addr.as_u64(),
+        options(nostack),
+    );
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        let _ = addr;
+        unimplemented!()
+    }
+}
+
+/// Sets the Allocation Tags of the two granules at `addr` to its Logical Tag (ST2G).
+///
+/// # Safety
+///
+/// See [`stg`], `addr` being aligned to twice [`TAG_GRANULE`].
+pub unsafe fn st2g(addr: VirtAddr) {
+    #[cfg(target_arch = "aarch64")]
+    asm!(
+        ".arch_extension memtag",
+        "st2g {0}, [{0}]",
+        in(reg) addr.as_u64(),
+        options(nostack),
+    );
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        let _ = addr;
+        unimplemented!()
+    }
+}
+
+/// Sets the Allocation Tags of the `len` bytes at `addr` to its Logical Tag.
+///
+/// # Safety
+///
+/// See [`stg`], `len` being a multiple of [`TAG_GRANULE`].
+pub unsafe fn set_tags(addr: VirtAddr, len: usize) {
+    debug_assert!((addr.as_u64() as usize | len) & (TAG_GRANULE - 1) == 0);
+    let mut offset = 0;
+    if addr.as_u64() as usize & TAG_GRANULE != 0 && len != 0 {
+        stg(addr);
+        offset = TAG_GRANULE;
+    }
+    while len - offset >= 2 * TAG_GRANULE {
+        st2g(addr + offset as u64);
+        offset += 2 * TAG_GRANULE;
+    }
+    if offset != len {
+        stg(addr + offset as u64);
+    }
+}
+
+/// Zeroes the `len` bytes at `addr`, and sets their Allocation Tags to its Logical Tag (STZ2G).
+///
+/// # Safety
+///
+/// See [`stg`], `addr` and `len` being multiples of twice [`TAG_GRANULE`].
+pub unsafe fn zero_with_tags(addr: VirtAddr, len: usize) {
+    debug_assert!((addr.as_u64() as usize | len) & (2 * TAG_GRANULE - 1) == 0);
+    #[cfg(target_arch = "aarch64")]
+    for offset in (0..len).step_by(2 * TAG_GRANULE) {
+        asm!(
+            ".arch_extension memtag",
+            "stz2g {0}, [{0}]",
+            in(reg) addr.as_u64() + offset as u64,
+            options(nostack),
+        );
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    unimplemented!()
+}
+
+/// Returns the tagged address and length of `pages`.
+fn page_range<S: PageSize>(pages: &Range<Page<S>>, tag: u8) -> (VirtAddr, usize) {
+    let len = (pages.end.start_address().as_u64() - pages.start.start_address().as_u64()) as usize;
+    (with_tag(pages.start.start_address(), tag), len)
+}
+
+/// Sets the Allocation Tags of `pages` to `tag`.
+///
+/// # Safety
+///
+/// The tag instructions must be enabled, and the pages mapped as tagged memory.
+pub unsafe fn set_page_tags<S: PageSize>(pages: Range<Page<S>>, tag: u8) {
+    let (addr, len) = page_range(&pages, tag);
+    set_tags(addr, len);
+}
+
+/// Zeroes `pages` and sets their Allocation Tags to `tag`, e.g. before mapping them into a user
+/// program.
+///
+/// # Safety
+///
+/// See [`set_page_tags`].
+pub unsafe fn zero_pages<S: PageSize>(pages: Range<Page<S>>, tag: u8) {
+    let (addr, len) = page_range(&pages, tag);
+    zero_with_tags(addr, len);
+}
+
+/// Tag state of a user context.
+///
+/// [`UserContext::run`](crate::trap::UserContext::run) loads it while the program runs, and
+/// keeps the asynchronous Tag Check Faults of the program in [`tfsre0`](Self::tfsre0).
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+#[repr(C)]
+pub struct MteState {
+    /// Tag Control Register, GCR_EL1, excluding tags from IRG
+    pub gcr: u64,
+    /// Random Allocation Tag Seed Register, RGSR_EL1
+    pub rgsr: u64,
+    /// Tag Fault Status Register of EL0, TFSRE0_EL1
+    pub tfsre0: u64,
+    /// Tag Check Fault in EL0, SCTLR_EL1.TCF0
+    pub tcf0: TagCheckFault,
+}
+
+impl MteState {
+    /// Creates the state of a program checking tags with `tcf0`, with the random tags excluding
+    /// the mask `exclude`.
+    pub fn new(tcf0: TagCheckFault, exclude: u16) -> Self {
+        Self {
+            gcr: gcr_value(exclude),
+            tcf0,
+            ..Default::default()
+        }
+    }
+
+    /// Returns whether an asynchronous Tag Check Fault occurred, and clears it.
+    pub fn take_async_fault(&mut self) -> bool {
+        let fault = self.tfsre0.get_bit(0);
+        self.tfsre0.set_bit(0, false);
+        fault
+    }
+
+    /// Loads the registers into the PE, keeping RGSR_EL1 of the kernel.
+    ///
+    /// # Safety
+    ///
+    /// The tag instructions must be enabled, see [`enable`]. [`save`](Self::save) must be
+    /// called when leaving the user program.
+    pub unsafe fn restore(&self) {
+        write_gcr(self.gcr);
+        #[cfg(target_arch = "aarch64")]
+        {
+            let rgsr: u64;
+            asm!("mrs {}, S3_0_C1_C0_5", out(reg) rgsr);
+            KERNEL_RGSR[crate::asm::cpuid()].store(rgsr, Ordering::Relaxed);
+            asm!("msr S3_0_C1_C0_5, {}", in(reg) self.rgsr);
+            asm!("msr S3_0_C5_C6_1, {}", in(reg) self.tfsre0);
+        }
+        SCTLR_EL1.modify(SCTLR_EL1::TCF0.val(self.tcf0 as u64));
+        #[cfg(target_arch = "aarch64")]
+        crate::barrier::isb(crate::barrier::SY);
+    }
+
+    /// Saves the registers from the PE, and restores GCR_EL1 and RGSR_EL1 of the kernel.
+    ///
+    /// Tag checking at EL0 is disabled and TFSRE0_EL1 cleared, so that the programs without
+    /// an [`MteState`] neither check tags nor report the faults of this one.
+    ///
+    /// # Safety
+    ///
+    /// See [`restore`](Self::restore).
+    pub unsafe fn save(&mut self) {
+        #[cfg(target_arch = "aarch64")]
+        {
+            // Asynchronous faults of the program are recorded once its accesses complete.
+            crate::barrier::dsb(crate::barrier::SY);
+            crate::barrier::isb(crate::barrier::SY);
+            asm!("mrs {}, S3_0_C1_C0_5", out(reg) self.rgsr);
+            let rgsr = KERNEL_RGSR[crate::asm::cpuid()].load(Ordering::Relaxed);
+            asm!("msr S3_0_C1_C0_5, {}", in(reg) rgsr);
+            asm!("mrs {}, S3_0_C5_C6_1", out(reg) self.tfsre0);
+            asm!("msr S3_0_C5_C6_1, xzr");
+        }
+        write_gcr(KERNEL_GCR.load(Ordering::Relaxed));
+        SCTLR_EL1.modify(SCTLR_EL1::TCF0.val(TagCheckFault::Ignore as u64));
+    }
+}
+
+/// Writes GCR_EL1.
+unsafe fn write_gcr(gcr: u64) {
+    #[cfg(target_arch = "aarch64")]
+    asm!("msr S3_0_C1_C0_6, {}", in(reg) gcr);
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        let _ = gcr;
+        unimplemented!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        exception::ExceptionInfo,
+        trap::{TrapKind, TrapReason},
+    };
+
+    #[test]
+    pub fn test_mte() {
+        let addr = with_tag(VirtAddr::new(0xffff_0000_1234_5670), 0xa);
+        assert_eq!(addr.as_u64(), 0xfaff_0000_1234_5670);
+        assert_eq!(tag_of(addr), 0xa);
+
+        let pages = Page::<crate::paging::Size4KiB>::range_of(0x4000, 0x6000);
+        assert_eq!(
+            page_range(&pages, 3),
+            (VirtAddr::new(0x0300_0000_0000_4000), 0x2000)
+        );
+
+        let mut state = MteState::new(TagCheckFault::Sync, 1);
+        assert_eq!(state.gcr, 1);
+        state.tfsre0 = 1;
+        assert!(state.take_async_fault());
+        assert!(!state.take_async_fault());
+
+        // Synchronous tag check fault on a write, from EL0.
+        let info = ExceptionInfo::new(0x9200_0051, 0x0a00_0000_1000_0000);
+        assert_eq!(
+            TrapReason::new(TrapKind::Synchronous, info),
+            TrapReason::TagCheck {
+                addr: VirtAddr::new(0x0a00_0000_1000_0000)
+            }
+        );
+    }
+}
@@ -0,0 +1,115 @@
+//! An address space described by a root page table and an ASID.
+
+use crate::{
+    barrier,
+    paging::{
+        mapper::{MapToError, TryPhysToVirt},
+        Frame, MappedPageTable,
+    },
+    registers::*,
+    translation::{self, Ttbr},
+};
+
+/// A stage 1 address space of the EL1&0 translation regime.
+///
+/// It is made up of the frame of the level 4 page table, the ASID that tags its TLB entries and
+/// the translation table base register it gets installed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressSpace {
+    root: Frame,
+    asid: u16,
+    ttbr: Ttbr,
+}
+
+impl AddressSpace {
+    /// Creates a new address space with the level 4 page table `root`.
+    ///
+    /// The page table is not touched, it must be initialized by the caller (e.g. cleared) before
+    /// the address space is activated.
+    #[inline]
+    pub const fn new(ttbr: Ttbr, root: Frame, asid: u16) -> Self {
+        Self { root, asid, ttbr }
+    }
+
+    /// Returns the address space currently installed in the `ttbr` register.
+    #[inline]
+    pub fn current(ttbr: Ttbr) -> Self {
+        let (asid, root) = translation::ttbr_el1_read_asid(ttbr);
+        Self::new(ttbr, root, asid)
+    }
+
+    /// Returns the frame of the level 4 page table.
+    #[inline]
+    pub fn root_frame(&self) -> Frame {
+        self.root
+    }
+
+    /// Returns the ASID of this address space.
+    #[inline]
+    pub fn asid(&self) -> u16 {
+        self.asid
+    }
+
+    /// Sets the ASID of this address space, it takes effect on the next activation.
+    #[inline]
+    pub fn set_asid(&mut self, asid: u16) {
+        self.asid = asid;
+    }
+
+    /// Returns the translation table base register this address space is installed in.
+    #[inline]
+    pub fn ttbr(&self) -> Ttbr {
+        self.ttbr
+    }
+
+    /// Returns whether this address space is the one currently installed in its TTBR.
+    #[inline]
+    pub fn is_active(&self) -> bool {
+        translation::ttbr_el1_read(self.ttbr) == self.root
+    }
+
+    /// Returns a `MappedPageTable` for editing the page tables of this address space.
+    ///
+    /// Returns `MapToError::TableNotAccessible` if `phys_to_virt` doesn't map the root frame.
+    ///
+    /// ## Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed `phys_to_virt`
+    /// closure is correct, and that the root frame holds a valid page table hierarchy.
+    #[inline]
+    pub unsafe fn page_table<P: TryPhysToVirt>(
+        &mut self,
+        phys_to_virt: P,
+    ) -> Result<MappedPageTable<'_, P>, MapToError> {
+        let level_4_table = phys_to_virt
+            .try_phys_to_virt(self.root)
+            .ok_or_else(|| MapToError::TableNotAccessible(self.root.start_address()))?;
+        Ok(MappedPageTable::new(&mut *level_4_table, phys_to_virt))
+    }
+
+    /// Installs this address space in its TTBR of the current PE.
+    ///
+    /// The ASID is written together with the base address, which relies on TCR_EL1.A1 being 0
+    /// for TTBR0_EL1. CnP is set whenever the PE implements it, since the same page tables and
+    /// ASID are used by every PE that activates this address space.
+    ///
+    /// No TLB maintenance is done, stale entries tagged with the same ASID must have been
+    /// invalidated by the caller.
+    ///
+    /// ## Safety
+    ///
+    /// This function is unsafe because it changes the translation of the whole VA range
+    /// covered by the TTBR, the page tables must be valid and map the currently running code
+    /// and stack when activating the upper range.
+    #[inline]
+    pub unsafe fn activate(&self) {
+        translation::ttbr_el1_write_asid_cnp(self.ttbr, self.asid, self.root, cnp_supported());
+        barrier::isb(barrier::SY);
+    }
+}
+
+/// Returns whether the PE supports the Common not Private translations (FEAT_TTCNP).
+#[inline]
+fn cnp_supported() -> bool {
+    ID_AA64MMFR2_EL1.read(ID_AA64MMFR2_EL1::CnP) != 0
+}
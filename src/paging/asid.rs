@@ -0,0 +1,339 @@
+//! ASID allocation with generation rollover.
+//!
+//! This follows the scheme of the Linux arm64 port: an ASID is tagged with the generation it was
+//! allocated in, and remains valid until the ASID space is exhausted. Then the generation is
+//! bumped, every ASID except those currently running on some PE (which become reserved) is freed,
+//! and the TLBs are invalidated.
+
+use crate::{paging::AddressSpace, registers::*};
+use core::{
+    cell::UnsafeCell,
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+};
+
+/// The maximum number of ASIDs, for 16-bit ASIDs.
+const MAX_ASIDS: usize = 1 << 16;
+
+/// The number of words of the ASID bitmap.
+const MAP_WORDS: usize = MAX_ASIDS / 64;
+
+/// The number of ASID bits implemented by the PE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsidBits {
+    Bits8 = 8,
+    Bits16 = 16,
+}
+
+impl AsidBits {
+    /// Reads the number of ASID bits supported by the current PE from ID_AA64MMFR0_EL1.
+    ///
+    /// Note that 16-bit ASIDs are only used when TCR_EL1.AS is set.
+    #[inline]
+    pub fn current() -> Self {
+        match ID_AA64MMFR0_EL1.read_as_enum(ID_AA64MMFR0_EL1::ASIDBits) {
+            Some(ID_AA64MMFR0_EL1::ASIDBits::Value::Bits_16) => AsidBits::Bits16,
+            _ => AsidBits::Bits8,
+        }
+    }
+
+    /// Returns the number of ASIDs.
+    #[inline]
+    pub const fn count(self) -> usize {
+        1 << self as usize
+    }
+}
+
+/// The ASID of an address space together with the generation it was allocated in.
+///
+/// One of these is kept for every address space, e.g. in the process control block. A zero value
+/// means no ASID has been allocated yet.
+#[derive(Debug, Default)]
+pub struct AsidContext(AtomicU64);
+
+impl AsidContext {
+    /// Creates a context without an ASID.
+    #[inline]
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+}
+
+/// An SMP-safe ASID allocator for up to `CPUS` PEs.
+///
+/// ASID 0 is never handed out, so that it can be used with reserved (e.g. empty) TTBR0 tables.
+/// ASIDs are not freed individually, they are reclaimed at the next rollover.
+pub struct AsidAllocator<const CPUS: usize> {
+    /// The number of ASID bits in use
+    bits: AtomicU8,
+    /// The current generation, counted in units of the ASID count
+    generation: AtomicU64,
+    /// The ASID running on each PE, or 0 while a rollover is in progress
+    active: [AtomicU64; CPUS],
+    /// Protects `inner`
+    locked: AtomicBool,
+    inner: UnsafeCell<AllocatorInner<CPUS>>,
+}
+
+struct AllocatorInner<const CPUS: usize> {
+    /// Allocated ASIDs of the current generation
+    map: [u64; MAP_WORDS],
+    /// The ASID that was running on each PE at the last rollover
+    reserved: [u64; CPUS],
+    /// Where to start searching for a free ASID
+    cur_idx: usize,
+}
+
+unsafe impl<const CPUS: usize> Sync for AsidAllocator<CPUS> {}
+
+impl<const CPUS: usize> AsidAllocator<CPUS> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INACTIVE: AtomicU64 = AtomicU64::new(0);
+
+    /// Creates an allocator for 8-bit ASIDs.
+    ///
+    /// Call [`init`](AsidAllocator::init) before the first context switch to use the ASID size
+    /// of the PE.
+    pub const fn new() -> Self {
+        Self {
+            bits: AtomicU8::new(AsidBits::Bits8 as u8),
+            generation: AtomicU64::new(1 << AsidBits::Bits8 as u64),
+            active: [Self::INACTIVE; CPUS],
+            locked: AtomicBool::new(false),
+            inner: UnsafeCell::new(AllocatorInner {
+                map: [0; MAP_WORDS],
+                reserved: [0; CPUS],
+                cur_idx: 1,
+            }),
+        }
+    }
+
+    /// Sets the number of ASID bits to allocate from.
+    ///
+    /// This must be called before any ASID has been allocated, typically with
+    /// [`AsidBits::current`].
+    pub fn init(&self, bits: AsidBits) {
+        self.bits.store(bits as u8, Ordering::Relaxed);
+        self.generation
+            .store(bits.count() as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the number of ASIDs.
+    #[inline]
+    fn count(&self) -> usize {
+        1 << self.bits.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether `id` was allocated in the current generation.
+    #[inline]
+    fn generation_match(&self, id: u64) -> bool {
+        let bits = self.bits.load(Ordering::Relaxed);
+        (id ^ self.generation.load(Ordering::Relaxed)) >> bits == 0
+    }
+
+    /// Returns the hardware ASID of `context`, allocating a new one if its generation is stale,
+    /// and marks it as running on the PE `cpu`.
+    ///
+    /// Must be called with interrupts disabled, on the PE `cpu`.
+    pub fn check_and_switch(&self, cpu: usize, context: &AsidContext) -> u16 {
+        let id = context.0.load(Ordering::Relaxed);
+        let old_active = self.active[cpu].load(Ordering::Relaxed);
+
+        // Fast path: the ASID is still current, and no rollover has zeroed our active ASID in
+        // the meantime.
+        if old_active != 0
+            && self.generation_match(id)
+            && self.active[cpu]
+                .compare_exchange(old_active, id, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            return self.asid_of(id);
+        }
+
+        self.lock();
+        let inner = unsafe { &mut *self.inner.get() };
+        let mut id = context.0.load(Ordering::Relaxed);
+        if !self.generation_match(id) {
+            id = self.new_context(inner, id);
+            context.0.store(id, Ordering::Relaxed);
+        }
+        self.active[cpu].store(id, Ordering::Relaxed);
+        self.unlock();
+
+        self.asid_of(id)
+    }
+
+    /// Switches the PE `cpu` to `space`, whose ASID is tracked by `context`.
+    ///
+    /// This is the context switch path: the ASID is checked and refreshed if needed, then the
+    /// address space is written to its TTBR.
+    ///
+    /// ## Safety
+    ///
+    /// Same as [`AddressSpace::activate`]. Must be called with interrupts disabled, on the PE
+    /// `cpu`.
+    pub unsafe fn switch_to(&self, cpu: usize, context: &AsidContext, space: &mut AddressSpace) {
+        let asid = self.check_and_switch(cpu, context);
+        space.set_asid(asid);
+        space.activate();
+    }
+
+    #[inline]
+    fn asid_of(&self, id: u64) -> u16 {
+        (id & (self.count() as u64 - 1)) as u16
+    }
+
+    /// Allocates a new ASID for a context whose ASID is `id`, in the current generation.
+    fn new_context(&self, inner: &mut AllocatorInner<CPUS>, id: u64) -> u64 {
+        let count = self.count();
+        let mut generation = self.generation.load(Ordering::Relaxed);
+
+        if id != 0 {
+            let asid = id & (count as u64 - 1);
+            let new_id = generation | asid;
+
+            // If our current ASID was active during a rollover, we can continue to use it.
+            if inner.check_update_reserved(id, new_id) {
+                return new_id;
+            }
+            // We had a valid ASID in a previous life, so try to re-use it if possible.
+            if !inner.test_and_set(asid as usize) {
+                return new_id;
+            }
+        }
+
+        let idx = match inner.find_next_zero(count, inner.cur_idx) {
+            Some(idx) => idx,
+            None => {
+                // We're out of ASIDs, so increment the generation count and free everything
+                // that isn't running.
+                generation = self
+                    .generation
+                    .fetch_add(count as u64, Ordering::Relaxed)
+                    .wrapping_add(count as u64);
+                self.flush_context(inner);
+                inner
+                    .find_next_zero(count, 1)
+                    .expect("more PEs than available ASIDs")
+            }
+        };
+        inner.test_and_set(idx);
+        inner.cur_idx = idx;
+        generation | idx as u64
+    }
+
+    /// Resets the ASID map on rollover, keeping the ASIDs running on each PE reserved.
+    fn flush_context(&self, inner: &mut AllocatorInner<CPUS>) {
+        let mask = self.count() as u64 - 1;
+
+        inner.map.iter_mut().for_each(|word| *word = 0);
+        for (active, reserved) in self.active.iter().zip(inner.reserved.iter_mut()) {
+            let mut id = active.swap(0, Ordering::Relaxed);
+            // If this PE has already been through a rollover but hasn't run another task in the
+            // meantime, we must preserve its reserved ASID, as this is the only trace we have of
+            // the process it is still running.
+            if id == 0 {
+                id = *reserved;
+            }
+            let asid = (id & mask) as usize;
+            inner.map[asid / 64] |= 1 << (asid % 64);
+            *reserved = id;
+        }
+
+        // Entries of the previous generation must not match the reallocated ASIDs.
+        #[cfg(target_arch = "aarch64")]
+        crate::translation::invalidate_tlb_all();
+    }
+
+    fn lock(&self) {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+    }
+
+    fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+impl<const CPUS: usize> Default for AsidAllocator<CPUS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const CPUS: usize> AllocatorInner<CPUS> {
+    /// Replaces the reserved ASID `id` by `new_id` on every PE, and returns whether it was
+    /// found.
+    ///
+    /// Several PEs may hold the same reserved ASID, and all of them must be updated.
+    fn check_update_reserved(&mut self, id: u64, new_id: u64) -> bool {
+        let mut hit = false;
+        for reserved in self.reserved.iter_mut() {
+            if *reserved == id {
+                hit = true;
+                *reserved = new_id;
+            }
+        }
+        hit
+    }
+
+    /// Marks the ASID `idx` as allocated, and returns whether it already was.
+    fn test_and_set(&mut self, idx: usize) -> bool {
+        let (word, bit) = (idx / 64, 1 << (idx % 64));
+        let old = self.map[word] & bit != 0;
+        self.map[word] |= bit;
+        old
+    }
+
+    /// Finds the first free ASID in `[start, limit)`.
+    fn find_next_zero(&self, limit: usize, start: usize) -> Option<usize> {
+        let mut idx = start;
+        while idx < limit {
+            let word = !self.map[idx / 64] >> (idx % 64);
+            if word != 0 {
+                idx += word.trailing_zeros() as usize;
+                return Some(idx).filter(|&idx| idx < limit);
+            }
+            idx = (idx / 64 + 1) * 64;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_asid_rollover() {
+        let allocator = AsidAllocator::<2>::new();
+        allocator.init(AsidBits::Bits8);
+
+        let running = AsidContext::new();
+        let running_asid = allocator.check_and_switch(1, &running);
+        assert_eq!(running_asid, 1);
+
+        // Use up the remaining ASIDs on the other PE.
+        let contexts: [AsidContext; 254] = core::array::from_fn(|_| AsidContext::new());
+        for (i, context) in contexts.iter().enumerate() {
+            assert_eq!(allocator.check_and_switch(0, context), i as u16 + 2);
+        }
+        // A context keeps its ASID while the generation is current.
+        assert_eq!(allocator.check_and_switch(0, &contexts[7]), 9);
+
+        // Rollover: the ASIDs running on both PEs are reserved, everything else is freed.
+        let new = AsidContext::new();
+        let new_asid = allocator.check_and_switch(0, &new);
+        assert_eq!(new_asid, 2);
+        assert_eq!(allocator.check_and_switch(1, &running), running_asid);
+        assert_eq!(allocator.check_and_switch(0, &contexts[7]), 9);
+        // Stale contexts get their old ASID back if it is still free.
+        assert_eq!(allocator.check_and_switch(0, &contexts[100]), 102);
+        assert_eq!(allocator.check_and_switch(0, &contexts[0]), 3);
+    }
+}
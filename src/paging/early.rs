@@ -0,0 +1,314 @@
+//! Building page tables at early boot, before a frame allocator exists.
+//!
+//! The tables come from a [`TablePool`] reserved statically, and are accessed at their physical
+//! address. So the builder is meant to run with the MMU off (or with an identity mapping of the
+//! pool), and works whether the data cache is on or off.
+
+use crate::{
+    addr::{PhysAddr, VirtAddr},
+    paging::{
+        mapper::MapToError,
+        table::{PageTable, PageTableAttribute, PageTableEntry, PageTableFlags},
+        Frame, PageSize, Size1GiB, Size2MiB, Size4KiB,
+    },
+};
+
+/// A fixed number of page tables, reserved statically.
+///
+/// Being all zero, a pool defined as a `static` ends up in `.bss`:
+///
+/// ```no_run
+/// # use aarch64::paging::early::TablePool;
+/// static mut BOOT_TABLES: TablePool<16> = TablePool::new();
+/// ```
+#[repr(C)]
+pub struct TablePool<const N: usize> {
+    tables: [PageTable; N],
+    used: usize,
+}
+
+impl<const N: usize> TablePool<N> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: PageTable = PageTable::new();
+
+    /// Creates a pool of `N` empty page tables.
+    pub const fn new() -> Self {
+        Self {
+            tables: [Self::EMPTY; N],
+            used: 0,
+        }
+    }
+
+    /// Returns the number of tables handed out so far.
+    #[inline]
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Takes an unused table from the pool.
+    fn allocate(&mut self) -> Option<&mut PageTable> {
+        let table = self.tables.get_mut(self.used)?;
+        self.used += 1;
+        table.clear();
+        Some(table)
+    }
+
+    /// Returns the VA range `[start, end)` of the tables handed out so far.
+    #[cfg(target_arch = "aarch64")]
+    fn used_range(&self) -> (usize, usize) {
+        let start = self.tables.as_ptr() as usize;
+        (start, start + self.used * core::mem::size_of::<PageTable>())
+    }
+
+    /// Invalidates the D-Cache lines of the tables handed out so far, to the Point of
+    /// Coherency.
+    ///
+    /// With the data cache off, the tables are written straight to memory, but stale or
+    /// speculatively loaded lines may still be hit by the table walker once the MMU is on. The
+    /// lines are invalidated rather than cleaned, so that stale dirty lines are not written back
+    /// over the tables.
+    ///
+    /// This is only correct when the tables were written with the data cache off.
+    pub fn invalidate_dcache(&self) {
+        #[cfg(target_arch = "aarch64")]
+        {
+            use crate::cache::{Cache, DCache, Invalidate, PoC, SY};
+
+            let (start, end) = self.used_range();
+            DCache::<Invalidate, PoC>::flush_range(start, end, SY);
+        }
+    }
+
+    /// Cleans the D-Cache lines of the tables handed out so far to the Point of Coherency.
+    ///
+    /// This is needed when the tables were written with the data cache on, and the MMU is about
+    /// to be turned on with non-cacheable table walks.
+    pub fn clean_dcache(&self) {
+        #[cfg(target_arch = "aarch64")]
+        {
+            use crate::cache::{Cache, Clean, DCache, PoC, SY};
+
+            let (start, end) = self.used_range();
+            DCache::<Clean, PoC>::flush_range(start, end, SY);
+        }
+    }
+}
+
+impl<const N: usize> Default for TablePool<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A page table hierarchy built from a [`TablePool`].
+///
+/// Several hierarchies (e.g. an identity map for TTBR0_EL1 and a higher-half map for
+/// TTBR1_EL1) can share one pool.
+///
+/// # Example
+/// ```no_run
+/// # use aarch64::{
+/// #     addr::PhysAddr,
+/// #     paging::{
+/// #         early::{EarlyPageTable, TablePool},
+/// #         mapper::MapToError,
+/// #         memory_attribute::{MairNormal, MairType},
+/// #         PageTableFlags,
+/// #     },
+/// # };
+/// # static mut BOOT_TABLES: TablePool<16> = TablePool::new();
+/// # fn main() -> Result<(), MapToError> {
+/// # let (text_start, text_size) = (PhysAddr::new(0x4008_0000), 0x10_0000);
+/// # let (rodata_start, rodata_size) = (PhysAddr::new(0x4018_0000), 0x8_0000);
+/// # let (data_start, data_size) = (PhysAddr::new(0x4020_0000), 0x20_0000);
+/// let pool = unsafe { &mut BOOT_TABLES };
+/// let mut map = EarlyPageTable::new(pool).unwrap();
+/// let normal = MairNormal::attr_value();
+/// // .text: read-only, executable, built with BTI
+/// map.identity_map(
+///     text_start,
+///     text_size,
+///     PageTableFlags::AP_RO | PageTableFlags::UXN | PageTableFlags::GP,
+///     normal,
+/// )?;
+/// // .rodata: read-only
+/// map.identity_map(
+///     rodata_start,
+///     rodata_size,
+///     PageTableFlags::AP_RO | PageTableFlags::UXN | PageTableFlags::PXN,
+///     normal,
+/// )?;
+/// // .data and .bss: read-write
+/// map.identity_map(
+///     data_start,
+///     data_size,
+///     PageTableFlags::UXN | PageTableFlags::PXN,
+///     normal,
+/// )?;
+/// let root = map.root_frame();
+/// pool.invalidate_dcache();
+/// # Ok(())
+/// # }
+/// ```
+pub struct EarlyPageTable<'a, const N: usize> {
+    pool: &'a mut TablePool<N>,
+    root: *mut PageTable,
+}
+
+impl<'a, const N: usize> EarlyPageTable<'a, N> {
+    /// Creates an empty hierarchy, taking its level 4 table from `pool`.
+    pub fn new(pool: &'a mut TablePool<N>) -> Result<Self, MapToError> {
+        let root = pool.allocate().ok_or(MapToError::FrameAllocationFailed)? as *mut PageTable;
+        Ok(Self { pool, root })
+    }
+
+    /// Returns the frame of the level 4 table, to be written to a TTBR.
+    #[inline]
+    pub fn root_frame(&self) -> Frame {
+        Frame::containing_address(PhysAddr::new(self.root as u64))
+    }
+
+    /// Maps `[phys, phys + size)` at the same virtual address.
+    pub fn identity_map(
+        &mut self,
+        phys: PhysAddr,
+        size: u64,
+        flags: PageTableFlags,
+        attr: PageTableAttribute,
+    ) -> Result<(), MapToError> {
+        self.map_range(VirtAddr::new(phys.as_u64()), phys, size, flags, attr)
+    }
+
+    /// Maps `[virt, virt + size)` to `[phys, phys + size)`.
+    ///
+    /// 1GiB and 2MiB blocks are used wherever both addresses are suitably aligned and the
+    /// remaining size covers the whole block, 4KiB pages otherwise. `flags` only needs the
+    /// permission and software bits, `VALID`, `AF` and `TABLE_OR_PAGE` are set accordingly.
+    ///
+    /// Both addresses and `size` must be 4KiB aligned.
+    pub fn map_range(
+        &mut self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        size: u64,
+        flags: PageTableFlags,
+        attr: PageTableAttribute,
+    ) -> Result<(), MapToError> {
+        debug_assert!(virt.is_aligned(Size4KiB::SIZE));
+        debug_assert!(phys.is_aligned(Size4KiB::SIZE));
+        debug_assert!(size % Size4KiB::SIZE == 0);
+
+        let mut offset = 0;
+        while offset < size {
+            let (virt, phys, remaining) = (virt + offset, phys + offset, size - offset);
+            let block_fits = |block_size: u64| {
+                virt.is_aligned(block_size)
+                    && phys.is_aligned(block_size)
+                    && remaining >= block_size
+            };
+
+            let mapped = if block_fits(Size1GiB::SIZE) {
+                self.map_block::<Size1GiB>(virt, phys, flags, attr)?
+            } else if block_fits(Size2MiB::SIZE) {
+                self.map_block::<Size2MiB>(virt, phys, flags, attr)?
+            } else {
+                self.map_block::<Size4KiB>(virt, phys, flags, attr)?
+            };
+            offset += mapped;
+        }
+        Ok(())
+    }
+
+    /// Maps a single block or page of size `S`, and returns its size.
+    fn map_block<S: PageSize>(
+        &mut self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        flags: PageTableFlags,
+        attr: PageTableAttribute,
+    ) -> Result<u64, MapToError> {
+        let p4 = unsafe { &mut *self.root };
+        let p3 = self.create_next_table(&mut p4[virt.p4_index()])?;
+        let entry = if S::SIZE == Size1GiB::SIZE {
+            &mut p3[virt.p3_index()]
+        } else {
+            let p2 = self.create_next_table(&mut p3[virt.p3_index()])?;
+            if S::SIZE == Size2MiB::SIZE {
+                &mut p2[virt.p2_index()]
+            } else {
+                let p1 = self.create_next_table(&mut p2[virt.p2_index()])?;
+                &mut p1[virt.p1_index()]
+            }
+        };
+
+        if !entry.is_unused() {
+            return Err(MapToError::PageAlreadyMapped);
+        }
+        let flags = flags | PageTableFlags::VALID | PageTableFlags::AF;
+        if S::SIZE == Size4KiB::SIZE {
+            entry.set_addr(phys, flags | PageTableFlags::TABLE_OR_PAGE, attr);
+        } else {
+            entry.set_block::<S>(phys, flags - PageTableFlags::TABLE_OR_PAGE, attr);
+        }
+        Ok(S::SIZE)
+    }
+
+    /// Returns the table the `entry` points to, taking a new one from the pool if it is unused.
+    fn create_next_table<'b>(
+        &mut self,
+        entry: &'b mut PageTableEntry,
+    ) -> Result<&'b mut PageTable, MapToError> {
+        if entry.is_unused() {
+            let table = self
+                .pool
+                .allocate()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            let frame = Frame::containing_address(PhysAddr::new(table as *mut PageTable as u64));
+            entry.set_frame(
+                frame,
+                PageTableFlags::default_table(),
+                PageTableAttribute::new(0, 0, 0),
+            );
+        } else if entry.is_block() {
+            return Err(MapToError::ParentEntryHugePage);
+        }
+        Ok(unsafe { &mut *(entry.addr().as_u64() as *mut PageTable) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paging::memory_attribute::{MairNormal, MairType};
+
+    #[test]
+    pub fn test_early_block_mapping() {
+        let mut pool = TablePool::<8>::new();
+        let mut map = EarlyPageTable::new(&mut pool).unwrap();
+        let attr = MairNormal::attr_value();
+
+        // 4KiB up to the first 2MiB boundary, then a 2MiB block, then 4KiB again.
+        let start = PhysAddr::new(0x4000_0000 - 0x1000);
+        let size = 0x1000 + 0x20_0000 + 0x2000;
+        map.identity_map(start, size, PageTableFlags::AP_RO, attr)
+            .unwrap();
+        assert!(matches!(
+            map.identity_map(start, 0x1000, PageTableFlags::empty(), attr),
+            Err(MapToError::PageAlreadyMapped)
+        ));
+
+        let p4 = unsafe { &*map.root };
+        let p3 = unsafe { &*(p4[0].addr().as_u64() as *const PageTable) };
+        let p2 = unsafe { &*(p3[1].addr().as_u64() as *const PageTable) };
+        assert!(p2[0].is_block());
+        assert_eq!(p2[0].addr(), PhysAddr::new(0x4000_0000));
+        assert!(p2[0]
+            .flags()
+            .contains(PageTableFlags::AP_RO | PageTableFlags::AF));
+        assert!(!p2[1].is_block());
+        assert!(!p2[1].is_unused());
+        // One level 4 and level 3 table, two level 2 and level 1 tables.
+        drop(map);
+        assert_eq!(pool.used(), 6);
+    }
+}
@@ -3,9 +3,14 @@
 use crate::paging::{
     frame_alloc::FrameAllocator,
     mapper::*,
-    table::{FrameError, PageTable, PageTableAttribute, PageTableEntry, PageTableFlags},
+    table::{
+        publish_barrier, AtomicPageTableEntry, FrameError, PageTable, PageTableAttribute,
+        PageTableEntry, PageTableFlags,
+    },
     Frame, Page, Size1GiB, Size2MiB, Size4KiB,
 };
+use core::{cell::Cell, marker::PhantomData, ptr::addr_of_mut, sync::atomic::Ordering};
+use ux::u9;
 
 /// A Mapper implementation that relies on a PhysAddr to VirtAddr conversion function.
 ///
@@ -14,13 +19,20 @@ use crate::paging::{
 /// the virtual address space at some offset. Other mappings between physical and virtual
 /// memory are possible too, as long as they can be calculated as an `PhysAddr` to
 /// `VirtAddr` closure.
+///
+/// With a [`TryPhysToVirt`] conversion, tables outside of the mapped physical memory are
+/// reported as `TableNotAccessible` errors instead of being dereferenced.
 #[derive(Debug)]
-pub struct MappedPageTable<'a, P: PhysToVirt> {
+pub struct MappedPageTable<'a, P: TryPhysToVirt> {
     page_table_walker: PageTableWalker<P>,
-    level_4_table: &'a mut PageTable,
+    /// Not a reference, since the tables may be shared with mappers on other PEs
+    level_4_table: *mut PageTable,
+    _table: PhantomData<&'a mut PageTable>,
 }
 
-impl<'a, P: PhysToVirt> MappedPageTable<'a, P> {
+unsafe impl<'a, P: TryPhysToVirt + Send> Send for MappedPageTable<'a, P> {}
+
+impl<'a, P: TryPhysToVirt> MappedPageTable<'a, P> {
     /// Creates a new `MappedPageTable` that uses the passed closure for converting virtual
     /// to physical addresses.
     ///
@@ -35,13 +47,69 @@ impl<'a, P: PhysToVirt> MappedPageTable<'a, P> {
         Self {
             page_table_walker: PageTableWalker::new(phys_to_virt),
             level_4_table,
+            _table: PhantomData,
         }
     }
 
+    /// Creates a new `MappedPageTable` in lock-free mode, for a page table hierarchy shared with
+    /// mappers running concurrently on other PEs. See [`set_lock_free`](Self::set_lock_free).
+    ///
+    /// ## Safety
+    ///
+    /// Same as [`new`](Self::new). Moreover, only `map_to` may run concurrently with the other
+    /// mappers of the hierarchy, the other methods borrow the tables they walk and need
+    /// exclusive access.
+    #[inline]
+    pub unsafe fn new_shared(level_4_table: *mut PageTable, phys_to_virt: P) -> Self {
+        let mut mapper = Self {
+            page_table_walker: PageTableWalker::new(phys_to_virt),
+            level_4_table,
+            _table: PhantomData,
+        };
+        mapper.set_lock_free(true);
+        mapper
+    }
+
     /// Returns a mutable reference to the wrapped level 4 `PageTable` instance.
+    ///
+    /// ## Panics
+    ///
+    /// Panics in lock-free mode, since the table may be written by other PEs: use
+    /// [`level_4_table_ptr`](Self::level_4_table_ptr) instead.
     #[inline]
     pub fn level_4_table(&mut self) -> &mut PageTable {
-        &mut self.level_4_table
+        assert!(
+            !self.page_table_walker.lock_free,
+            "level 4 table borrowed in lock-free mode"
+        );
+        unsafe { &mut *self.level_4_table }
+    }
+
+    /// Returns a pointer to the wrapped level 4 `PageTable` instance.
+    #[inline]
+    pub fn level_4_table_ptr(&self) -> *mut PageTable {
+        self.level_4_table
+    }
+
+    /// Enables or disables the lock-free mode, for page tables shared with mappers running
+    /// concurrently on other PEs, see [`new_shared`](Self::new_shared).
+    ///
+    /// In this mode, intermediate tables are populated with compare-and-exchange, and a
+    /// creation race is resolved by using the table of the winner. The tables are only
+    /// accessed through raw pointers and atomic entries while mapping, and a page mapped
+    /// concurrently by another PE is reported as `PageAlreadyMapped`.
+    #[inline]
+    pub fn set_lock_free(&mut self, enabled: bool) {
+        self.page_table_walker.lock_free = enabled;
+    }
+
+    /// Returns the table frame left over from a lost creation race in lock-free mode, if any.
+    ///
+    /// It is used for the next table to create, and should be given back to the frame
+    /// allocator before the mapper is dropped.
+    #[inline]
+    pub fn take_spare_frame(&mut self) -> Option<Frame> {
+        self.page_table_walker.spare.take()
     }
 
     /// Helper function for implementing Mapper.
@@ -56,15 +124,11 @@ impl<'a, P: PhysToVirt> MappedPageTable<'a, P> {
     where
         A: FrameAllocator<Size4KiB>,
     {
-        let p4 = &mut self.level_4_table;
-        let p3 = self
-            .page_table_walker
-            .create_next_table(&mut p4[page.p4_index()], allocator)?;
+        let entry = self.create_entry(&[page.p4_index(), page.p3_index()], allocator)?;
 
-        if !p3[page.p3_index()].is_unused() {
-            return Err(MapToError::PageAlreadyMapped);
-        }
-        p3[page.p3_index()].set_block::<Size1GiB>(frame.start_address(), flags, attr);
+        let mut new = PageTableEntry::new();
+        new.set_block::<Size1GiB>(frame.start_address(), flags, attr);
+        unsafe { self.set_leaf(entry, new)? };
 
         Ok(MapperFlush::new(page))
     }
@@ -81,18 +145,14 @@ impl<'a, P: PhysToVirt> MappedPageTable<'a, P> {
     where
         A: FrameAllocator<Size4KiB>,
     {
-        let p4 = &mut self.level_4_table;
-        let p3 = self
-            .page_table_walker
-            .create_next_table(&mut p4[page.p4_index()], allocator)?;
-        let p2 = self
-            .page_table_walker
-            .create_next_table(&mut p3[page.p3_index()], allocator)?;
+        let entry = self.create_entry(
+            &[page.p4_index(), page.p3_index(), page.p2_index()],
+            allocator,
+        )?;
 
-        if !p2[page.p2_index()].is_unused() {
-            return Err(MapToError::PageAlreadyMapped);
-        }
-        p2[page.p2_index()].set_block::<Size2MiB>(frame.start_address(), flags, attr);
+        let mut new = PageTableEntry::new();
+        new.set_block::<Size2MiB>(frame.start_address(), flags, attr);
+        unsafe { self.set_leaf(entry, new)? };
 
         Ok(MapperFlush::new(page))
     }
@@ -109,27 +169,92 @@ impl<'a, P: PhysToVirt> MappedPageTable<'a, P> {
     where
         A: FrameAllocator<Size4KiB>,
     {
-        let p4 = &mut self.level_4_table;
-        let p3 = self
-            .page_table_walker
-            .create_next_table(&mut p4[page.p4_index()], allocator)?;
-        let p2 = self
-            .page_table_walker
-            .create_next_table(&mut p3[page.p3_index()], allocator)?;
-        let p1 = self
-            .page_table_walker
-            .create_next_table(&mut p2[page.p2_index()], allocator)?;
+        let entry = self.create_entry(
+            &[
+                page.p4_index(),
+                page.p3_index(),
+                page.p2_index(),
+                page.p1_index(),
+            ],
+            allocator,
+        )?;
+
+        let mut new = PageTableEntry::new();
+        new.set_frame(frame, flags, attr);
+        unsafe { self.set_leaf(entry, new)? };
 
-        if !p1[page.p1_index()].is_unused() {
-            return Err(MapToError::PageAlreadyMapped);
+        Ok(MapperFlush::new(page))
+    }
+
+    /// Internal helper function to get the entry at the end of the path `indices`, from the
+    /// level 4 table, creating the tables on the way if needed.
+    ///
+    /// The tables are only accessed through raw pointers, since they may be shared with other
+    /// PEs in lock-free mode.
+    fn create_entry<A>(
+        &mut self,
+        indices: &[u9],
+        allocator: &mut A,
+    ) -> Result<*mut PageTableEntry, MapToError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let (&last, path) = indices.split_last().expect("empty path");
+        let mut table = self.level_4_table;
+        for &index in path {
+            table = unsafe {
+                self.page_table_walker
+                    .create_next_table(entry_ptr(table, index), allocator)?
+            };
         }
-        p1[page.p1_index()].set_frame(frame, flags, attr);
+        Ok(unsafe { entry_ptr(table, last) })
+    }
 
-        Ok(MapperFlush::new(page))
+    /// Internal helper function to set the unused leaf entry at `entry` to `new`, with a
+    /// compare-and-exchange in lock-free mode.
+    ///
+    /// Returns `MapToError::PageAlreadyMapped` if the entry is used.
+    ///
+    /// ## Safety
+    ///
+    /// `entry` must point to an entry of a table of the hierarchy, see
+    /// [`create_entry`](Self::create_entry).
+    unsafe fn set_leaf(
+        &self,
+        entry: *mut PageTableEntry,
+        new: PageTableEntry,
+    ) -> Result<(), MapToError> {
+        if self.page_table_walker.lock_free {
+            AtomicPageTableEntry::from_ptr(entry)
+                .compare_exchange(
+                    PageTableEntry::new(),
+                    new,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                )
+                .map(|_| ())
+                .map_err(|_| MapToError::PageAlreadyMapped)
+        } else {
+            let entry = &mut *entry;
+            if !entry.is_unused() {
+                return Err(MapToError::PageAlreadyMapped);
+            }
+            *entry = new;
+            Ok(())
+        }
     }
 }
 
-impl<'a, P: PhysToVirt> Mapper<Size1GiB> for MappedPageTable<'a, P> {
+/// Returns a pointer to the entry `index` of `table`, without borrowing the table.
+///
+/// ## Safety
+///
+/// `table` must point to a page table.
+unsafe fn entry_ptr(table: *mut PageTable, index: u9) -> *mut PageTableEntry {
+    addr_of_mut!((*table).entries[usize::from(u16::from(index))])
+}
+
+impl<'a, P: TryPhysToVirt> Mapper<Size1GiB> for MappedPageTable<'a, P> {
     #[inline]
     unsafe fn map_to<A>(
         &mut self,
@@ -165,7 +290,7 @@ impl<'a, P: PhysToVirt> Mapper<Size1GiB> for MappedPageTable<'a, P> {
     }
 
     fn get_entry(&self, page: Page<Size1GiB>) -> Result<&PageTableEntry, EntryGetError> {
-        let p4 = &self.level_4_table;
+        let p4 = unsafe { &*self.level_4_table };
         let p3 = self.page_table_walker.next_table(&p4[page.p4_index()])?;
         Ok(&p3[page.p3_index()])
     }
@@ -174,7 +299,7 @@ impl<'a, P: PhysToVirt> Mapper<Size1GiB> for MappedPageTable<'a, P> {
         &mut self,
         page: Page<Size1GiB>,
     ) -> Result<&mut PageTableEntry, EntryGetError> {
-        let p4 = &mut self.level_4_table;
+        let p4 = unsafe { &mut *self.level_4_table };
         let p3 = self
             .page_table_walker
             .next_table_mut(&mut p4[page.p4_index()])?;
@@ -182,7 +307,7 @@ impl<'a, P: PhysToVirt> Mapper<Size1GiB> for MappedPageTable<'a, P> {
     }
 }
 
-impl<'a, P: PhysToVirt> Mapper<Size2MiB> for MappedPageTable<'a, P> {
+impl<'a, P: TryPhysToVirt> Mapper<Size2MiB> for MappedPageTable<'a, P> {
     #[inline]
     unsafe fn map_to<A>(
         &mut self,
@@ -218,7 +343,7 @@ impl<'a, P: PhysToVirt> Mapper<Size2MiB> for MappedPageTable<'a, P> {
     }
 
     fn get_entry(&self, page: Page<Size2MiB>) -> Result<&PageTableEntry, EntryGetError> {
-        let p4 = &self.level_4_table;
+        let p4 = unsafe { &*self.level_4_table };
         let p3 = self.page_table_walker.next_table(&p4[page.p4_index()])?;
         let p2 = self.page_table_walker.next_table(&p3[page.p3_index()])?;
         Ok(&p2[page.p2_index()])
@@ -228,7 +353,7 @@ impl<'a, P: PhysToVirt> Mapper<Size2MiB> for MappedPageTable<'a, P> {
         &mut self,
         page: Page<Size2MiB>,
     ) -> Result<&mut PageTableEntry, EntryGetError> {
-        let p4 = &mut self.level_4_table;
+        let p4 = unsafe { &mut *self.level_4_table };
         let p3 = self
             .page_table_walker
             .next_table_mut(&mut p4[page.p4_index()])?;
@@ -239,7 +364,7 @@ impl<'a, P: PhysToVirt> Mapper<Size2MiB> for MappedPageTable<'a, P> {
     }
 }
 
-impl<'a, P: PhysToVirt> Mapper<Size4KiB> for MappedPageTable<'a, P> {
+impl<'a, P: TryPhysToVirt> Mapper<Size4KiB> for MappedPageTable<'a, P> {
     #[inline]
     unsafe fn map_to<A>(
         &mut self,
@@ -275,7 +400,7 @@ impl<'a, P: PhysToVirt> Mapper<Size4KiB> for MappedPageTable<'a, P> {
     }
 
     fn get_entry(&self, page: Page<Size4KiB>) -> Result<&PageTableEntry, EntryGetError> {
-        let p4 = &self.level_4_table;
+        let p4 = unsafe { &*self.level_4_table };
         let p3 = self.page_table_walker.next_table(&p4[page.p4_index()])?;
         let p2 = self.page_table_walker.next_table(&p3[page.p3_index()])?;
         let p1 = self.page_table_walker.next_table(&p2[page.p2_index()])?;
@@ -286,7 +411,7 @@ impl<'a, P: PhysToVirt> Mapper<Size4KiB> for MappedPageTable<'a, P> {
         &mut self,
         page: Page<Size4KiB>,
     ) -> Result<&mut PageTableEntry, EntryGetError> {
-        let p4 = &mut self.level_4_table;
+        let p4 = unsafe { &mut *self.level_4_table };
         let p3 = self
             .page_table_walker
             .next_table_mut(&mut p4[page.p4_index()])?;
@@ -300,15 +425,18 @@ impl<'a, P: PhysToVirt> Mapper<Size4KiB> for MappedPageTable<'a, P> {
     }
 }
 
-impl<'a, P: PhysToVirt> MapperAllSizes for MappedPageTable<'a, P> {
+impl<'a, P: TryPhysToVirt> MapperAllSizes for MappedPageTable<'a, P> {
     fn translate(&self, addr: VirtAddr) -> TranslateResult {
-        let p4 = &self.level_4_table;
+        let p4 = unsafe { &*self.level_4_table };
         let p3 = match self.page_table_walker.next_table(&p4[addr.p4_index()]) {
             Ok(page_table) => page_table,
             Err(PageTableWalkError::NotMapped) => return TranslateResult::PageNotMapped,
             Err(PageTableWalkError::MappedToHugePage) => {
                 panic!("level 4 entry has huge page bit set")
             }
+            Err(PageTableWalkError::NotAccessible(addr)) => {
+                return TranslateResult::TableNotAccessible(addr)
+            }
         };
         let p2 = match self.page_table_walker.next_table(&p3[addr.p3_index()]) {
             Ok(page_table) => page_table,
@@ -318,6 +446,9 @@ impl<'a, P: PhysToVirt> MapperAllSizes for MappedPageTable<'a, P> {
                 let offset = addr.as_u64() & 0o7_777_777_777;
                 return TranslateResult::Frame1GiB { frame, offset };
             }
+            Err(PageTableWalkError::NotAccessible(addr)) => {
+                return TranslateResult::TableNotAccessible(addr)
+            }
         };
         let p1 = match self.page_table_walker.next_table(&p2[addr.p2_index()]) {
             Ok(page_table) => page_table,
@@ -327,6 +458,9 @@ impl<'a, P: PhysToVirt> MapperAllSizes for MappedPageTable<'a, P> {
                 let offset = addr.as_u64() & 0o7_777_777;
                 return TranslateResult::Frame2MiB { frame, offset };
             }
+            Err(PageTableWalkError::NotAccessible(addr)) => {
+                return TranslateResult::TableNotAccessible(addr)
+            }
         };
 
         let p1_entry = &p1[addr.p1_index()];
@@ -345,25 +479,43 @@ impl<'a, P: PhysToVirt> MapperAllSizes for MappedPageTable<'a, P> {
 }
 
 #[derive(Debug)]
-struct PageTableWalker<P: PhysToVirt> {
+struct PageTableWalker<P: TryPhysToVirt> {
     phys_to_virt: P,
+    /// Whether intermediate tables are created with compare-and-exchange
+    lock_free: bool,
+    /// A table frame that lost a creation race, to be used for the next table
+    spare: Cell<Option<Frame>>,
 }
 
-impl<P: PhysToVirt> PageTableWalker<P> {
+impl<P: TryPhysToVirt> PageTableWalker<P> {
     pub unsafe fn new(phys_to_virt: P) -> Self {
-        Self { phys_to_virt }
+        Self {
+            phys_to_virt,
+            lock_free: false,
+            spare: Cell::new(None),
+        }
+    }
+
+    /// Internal helper function to get a pointer to the page table in `frame`.
+    ///
+    /// Returns `PageTableWalkError::NotAccessible` if the frame isn't mapped.
+    fn table_ptr(&self, frame: Frame) -> Result<*mut PageTable, PageTableWalkError> {
+        self.phys_to_virt
+            .try_phys_to_virt(frame)
+            .ok_or_else(|| PageTableWalkError::NotAccessible(frame.start_address()))
     }
 
     /// Internal helper function to get a reference to the page table of the next level.
     ///
     /// Returns `PageTableWalkError::NotMapped` if the entry is unused. Returns
     /// `PageTableWalkError::MappedToHugePage` if the `HUGE_PAGE` flag is set
-    /// in the passed entry.
+    /// in the passed entry. Returns `PageTableWalkError::NotAccessible` if the next table
+    /// isn't mapped.
     fn next_table<'b>(
         &self,
         entry: &'b PageTableEntry,
     ) -> Result<&'b PageTable, PageTableWalkError> {
-        let page_table_ptr = self.phys_to_virt.phys_to_virt(entry.frame()?);
+        let page_table_ptr = self.table_ptr(entry.frame()?)?;
         let page_table: &PageTable = unsafe { &*page_table_ptr };
 
         Ok(page_table)
@@ -373,12 +525,13 @@ impl<P: PhysToVirt> PageTableWalker<P> {
     ///
     /// Returns `PageTableWalkError::NotMapped` if the entry is unused. Returns
     /// `PageTableWalkError::MappedToHugePage` if the `HUGE_PAGE` flag is set
-    /// in the passed entry.
+    /// in the passed entry. Returns `PageTableWalkError::NotAccessible` if the next table
+    /// isn't mapped.
     fn next_table_mut<'b>(
         &self,
         entry: &'b mut PageTableEntry,
     ) -> Result<&'b mut PageTable, PageTableWalkError> {
-        let page_table_ptr = self.phys_to_virt.phys_to_virt(entry.frame()?);
+        let page_table_ptr = self.table_ptr(entry.frame()?)?;
         let page_table: &mut PageTable = unsafe { &mut *page_table_ptr };
 
         Ok(page_table)
@@ -392,19 +545,34 @@ impl<P: PhysToVirt> PageTableWalker<P> {
     ///
     /// Returns `MapToError::FrameAllocationFailed` if the entry is unused and the allocator
     /// returned `None`. Returns `MapToError::ParentEntryHugePage` if the `HUGE_PAGE` flag is set
-    /// in the passed entry.
-    fn create_next_table<'b, A>(
+    /// in the passed entry. Returns `MapToError::TableNotAccessible` if the next table isn't
+    /// mapped, and `MapToError::FrameNotAccessible` with a newly allocated frame that isn't, in
+    /// which case the frame is not linked into the page table.
+    ///
+    /// ## Safety
+    ///
+    /// `entry` must point to an entry of a table of the hierarchy, only accessed by this mapper
+    /// unless in lock-free mode.
+    unsafe fn create_next_table<A>(
         &self,
-        entry: &'b mut PageTableEntry,
+        entry: *mut PageTableEntry,
         allocator: &mut A,
-    ) -> Result<&'b mut PageTable, PageTableCreateError>
+    ) -> Result<*mut PageTable, PageTableCreateError>
     where
         A: FrameAllocator<Size4KiB>,
     {
+        if self.lock_free {
+            return self.create_next_table_lock_free(entry, allocator);
+        }
+
+        let entry = &mut *entry;
         let created;
 
         if entry.is_unused() {
-            if let Some(frame) = allocator.allocate_frame() {
+            if let Some(frame) = self.spare.take().or_else(|| allocator.allocate_frame()) {
+                if self.phys_to_virt.try_phys_to_virt(frame).is_none() {
+                    return Err(PageTableCreateError::FrameNotAccessible(frame));
+                }
                 entry.set_frame(
                     frame,
                     PageTableFlags::default_table(),
@@ -423,30 +591,96 @@ impl<P: PhysToVirt> PageTableWalker<P> {
                 return Err(PageTableCreateError::MappedToHugePage);
             }
             Err(PageTableWalkError::NotMapped) => panic!("entry should be mapped at this point"),
+            Err(PageTableWalkError::NotAccessible(addr)) => {
+                return Err(PageTableCreateError::NotAccessible(addr));
+            }
             Ok(page_table) => page_table,
         };
 
         if created {
             #[cfg(target_arch = "aarch64")]
-            unsafe {
-                crate::barrier::dsb(crate::barrier::ISHST);
-            }
+            crate::barrier::dsb(crate::barrier::ISHST);
             page_table.clear();
         }
         Ok(page_table)
     }
+
+    /// Internal helper function to create the page table of the next level if needed, when
+    /// other PEs may do the same concurrently.
+    ///
+    /// The new table is cleared before it is published with a compare-and-exchange. If another
+    /// PE published a table first, that one is returned, and the new frame is kept as a spare
+    /// for the next table to create.
+    ///
+    /// ## Safety
+    ///
+    /// `entry` must point to an entry of a table of the hierarchy, only accessed atomically by
+    /// the other PEs.
+    unsafe fn create_next_table_lock_free<A>(
+        &self,
+        entry: *mut PageTableEntry,
+        allocator: &mut A,
+    ) -> Result<*mut PageTable, PageTableCreateError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let atomic = AtomicPageTableEntry::from_ptr(entry);
+        let mut current = atomic.load(Ordering::Acquire);
+
+        if current.is_unused() {
+            let frame = self
+                .spare
+                .take()
+                .or_else(|| allocator.allocate_frame())
+                .ok_or(PageTableCreateError::FrameAllocationFailed)?;
+            let page_table = match self.phys_to_virt.try_phys_to_virt(frame) {
+                Some(page_table) => page_table,
+                None => return Err(PageTableCreateError::FrameNotAccessible(frame)),
+            };
+            // Not published yet, so not accessed by other PEs.
+            (*page_table).clear();
+
+            let new = PageTableEntry::from_addr(
+                frame.start_address(),
+                PageTableFlags::default_table(),
+                PageTableAttribute::new(0, 0, 0),
+            );
+            current =
+                match atomic.compare_exchange(current, new, Ordering::Release, Ordering::Acquire) {
+                    Ok(_) => {
+                        publish_barrier();
+                        new
+                    }
+                    Err(winner) => {
+                        self.spare.set(Some(frame));
+                        winner
+                    }
+                };
+        }
+
+        let frame = match current.frame() {
+            Ok(frame) => frame,
+            Err(FrameError::HugeFrame) => return Err(PageTableCreateError::MappedToHugePage),
+            Err(FrameError::FrameNotPresent) => panic!("entry should be mapped at this point"),
+        };
+        self.table_ptr(frame)
+            .map_err(|_| PageTableCreateError::NotAccessible(frame.start_address()))
+    }
 }
 
 #[derive(Debug)]
 enum PageTableWalkError {
     NotMapped,
     MappedToHugePage,
+    NotAccessible(PhysAddr),
 }
 
 #[derive(Debug)]
 enum PageTableCreateError {
     MappedToHugePage,
     FrameAllocationFailed,
+    NotAccessible(PhysAddr),
+    FrameNotAccessible(Frame),
 }
 
 impl From<PageTableCreateError> for MapToError {
@@ -454,6 +688,10 @@ impl From<PageTableCreateError> for MapToError {
         match err {
             PageTableCreateError::MappedToHugePage => MapToError::ParentEntryHugePage,
             PageTableCreateError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+            PageTableCreateError::NotAccessible(addr) => MapToError::TableNotAccessible(addr),
+            PageTableCreateError::FrameNotAccessible(frame) => {
+                MapToError::FrameNotAccessible(frame)
+            }
         }
     }
 }
@@ -472,6 +710,7 @@ impl From<PageTableWalkError> for EntryGetError {
         match err {
             PageTableWalkError::MappedToHugePage => EntryGetError::ParentEntryHugePage,
             PageTableWalkError::NotMapped => EntryGetError::PageNotMapped,
+            PageTableWalkError::NotAccessible(addr) => EntryGetError::TableNotAccessible(addr),
         }
     }
 }
@@ -494,3 +733,255 @@ where
         self(frame)
     }
 }
+
+/// Trait for converting a physical address to a virtual one, when only part of the physical
+/// address space is mapped.
+///
+/// Every [`PhysToVirt`] conversion is also a `TryPhysToVirt` one that never fails.
+pub trait TryPhysToVirt {
+    /// Translate the given physical frame to a virtual page table pointer.
+    ///
+    /// Returns `None` if the frame isn't mapped, e.g. because it lies outside of the linear map
+    /// or in device memory.
+    fn try_phys_to_virt(&self, frame: Frame) -> Option<*mut PageTable>;
+}
+
+impl<T: PhysToVirt> TryPhysToVirt for T {
+    #[inline]
+    fn try_phys_to_virt(&self, frame: Frame) -> Option<*mut PageTable> {
+        Some(self.phys_to_virt(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::addr::VirtAddr;
+
+    /// Only the frame at `0x1000` is accessible, as `table`.
+    struct Sparse(*mut PageTable);
+
+    impl TryPhysToVirt for Sparse {
+        fn try_phys_to_virt(&self, frame: Frame) -> Option<*mut PageTable> {
+            (frame.start_address().as_u64() == 0x1000).then(|| self.0)
+        }
+    }
+
+    /// Allocates a single frame.
+    struct OneFrame(Option<Frame>);
+
+    unsafe impl FrameAllocator<Size4KiB> for OneFrame {
+        fn allocate_frame(&mut self) -> Option<Frame> {
+            self.0.take()
+        }
+    }
+
+    #[test]
+    pub fn test_table_not_accessible() {
+        let mut p4 = PageTable::new();
+        let mut p3 = PageTable::new();
+        let table_flags = PageTableFlags::default_table();
+        let no_attr = PageTableAttribute::new(0, 0, 0);
+        p4[0].set_frame(Frame::of_addr(0x1000), table_flags, no_attr);
+        p3[0].set_frame(Frame::of_addr(0x2000), table_flags, no_attr);
+
+        let mut mapper = unsafe { MappedPageTable::new(&mut p4, Sparse(&mut p3)) };
+        match mapper.translate(VirtAddr::new(0x1234)) {
+            TranslateResult::TableNotAccessible(addr) => assert_eq!(addr.as_u64(), 0x2000),
+            result => panic!("unexpected {:?}", result),
+        }
+        assert!(matches!(
+            Mapper::<Size4KiB>::get_entry(&mapper, Page::of_addr(0x1000)),
+            Err(EntryGetError::TableNotAccessible(_))
+        ));
+
+        // The new level 2 table is not accessible either, its frame is given back.
+        let mut allocator = OneFrame(Some(Frame::of_addr(0x3000)));
+        let result = unsafe {
+            mapper.map_to(
+                Page::<Size4KiB>::of_addr(0x4000_0000),
+                Frame::of_addr(0x8000),
+                PageTableFlags::default_page(),
+                no_attr,
+                &mut allocator,
+            )
+        };
+        assert!(matches!(
+            result,
+            Err(MapToError::FrameNotAccessible(frame)) if frame == Frame::of_addr(0x3000)
+        ));
+        assert!(mapper.level_4_table()[0]
+            .flags()
+            .contains(PageTableFlags::VALID));
+        assert!(mapper.take_spare_frame().is_none());
+    }
+
+    /// Tables are accessed at their physical address.
+    struct Identity;
+
+    impl PhysToVirt for Identity {
+        fn phys_to_virt(&self, frame: Frame) -> *mut PageTable {
+            frame.start_address().as_u64() as *mut PageTable
+        }
+    }
+
+    fn table(table: &mut PageTable) -> Frame {
+        Frame::of_addr(table as *mut PageTable as u64)
+    }
+
+    #[test]
+    pub fn test_young_and_dirty() {
+        let (mut p4, mut p3, mut p2, mut p1) = (
+            PageTable::new(),
+            PageTable::new(),
+            PageTable::new(),
+            PageTable::new(),
+        );
+        let table_flags = PageTableFlags::default_table();
+        let no_attr = PageTableAttribute::new(0, 0, 0);
+        p4[0].set_frame(table(&mut p3), table_flags, no_attr);
+        p3[0].set_frame(table(&mut p2), table_flags, no_attr);
+        p2[0].set_frame(table(&mut p1), table_flags, no_attr);
+        let page = PageTableFlags::default_page();
+        p1[0].set_frame(Frame::of_addr(0x1000), page | PageTableFlags::DBM, no_attr);
+        p1[1].set_frame(
+            Frame::of_addr(0x2000),
+            page | PageTableFlags::AP_RO,
+            no_attr,
+        );
+        p1[2].set_frame(Frame::of_addr(0x3000), page - PageTableFlags::AF, no_attr);
+        // A 2MiB block, skipped by 4KiB ranges.
+        p2[1].set_block::<Size2MiB>(
+            PhysAddr::new(0x20_0000),
+            PageTableFlags::default_block(),
+            no_attr,
+        );
+
+        let mut mapper = unsafe { MappedPageTable::new(&mut p4, Identity) };
+        let pages = Page::<Size4KiB>::of_addr(0)..Page::of_addr(0x40_0000);
+        let mut young = [0; 4];
+        let mut count = 0;
+        mapper
+            .test_and_clear_young_range(pages.clone(), |page, flush| {
+                young[count] = page.start_address().as_u64();
+                count += 1;
+                flush.ignore();
+            })
+            .unwrap();
+        assert_eq!(&young[..count], &[0, 0x1000]);
+
+        let mut dirty = 0;
+        mapper
+            .test_and_clear_dirty_range(pages.clone(), |page, flush| {
+                assert_eq!(page.start_address().as_u64(), 0);
+                dirty += 1;
+                flush.ignore();
+            })
+            .unwrap();
+        assert_eq!(dirty, 1);
+        let entry = *Mapper::<Size4KiB>::get_entry(&mapper, Page::of_addr(0)).unwrap();
+        assert!(!entry.is_dirty() && !entry.is_young());
+        assert!(entry
+            .flags()
+            .contains(PageTableFlags::DBM | PageTableFlags::AP_RO));
+
+        // Software fallback for the 4KiB page and the 2MiB block.
+        assert!(mapper
+            .resolve_access_flag_fault(VirtAddr::new(0x123))
+            .unwrap());
+        let entry = *Mapper::<Size4KiB>::get_entry(&mapper, Page::of_addr(0)).unwrap();
+        assert!(entry.is_young());
+        assert!(!mapper
+            .resolve_access_flag_fault(VirtAddr::new(0x5000))
+            .unwrap());
+        assert!(mapper
+            .resolve_access_flag_fault(VirtAddr::new(0x20_1000))
+            .unwrap());
+        // No level 3 table.
+        assert!(!mapper
+            .resolve_access_flag_fault(VirtAddr::new(0x80_0000_0000))
+            .unwrap());
+    }
+
+    #[test]
+    pub fn test_lock_free_race() {
+        /// Publishes `winner` in `entry` on the first allocation, as if another PE created the
+        /// same table concurrently.
+        struct Racing {
+            entry: *mut PageTableEntry,
+            winner: Frame,
+            frames: [Frame; 2],
+            allocated: usize,
+        }
+
+        unsafe impl FrameAllocator<Size4KiB> for Racing {
+            fn allocate_frame(&mut self) -> Option<Frame> {
+                if self.allocated == 0 {
+                    unsafe { AtomicPageTableEntry::from_ptr(self.entry) }.publish(
+                        PageTableEntry::from_addr(
+                            self.winner.start_address(),
+                            PageTableFlags::default_table(),
+                            PageTableAttribute::new(0, 0, 0),
+                        ),
+                    );
+                }
+                self.allocated += 1;
+                self.frames.get(self.allocated - 1).copied()
+            }
+        }
+
+        let (mut p4, mut p3, mut loser, mut p1) = (
+            PageTable::new(),
+            PageTable::new(),
+            PageTable::new(),
+            PageTable::new(),
+        );
+        // The racing PE and the mapper share the level 4 table.
+        let shared: *mut PageTable = &mut p4;
+        let mut allocator = Racing {
+            entry: unsafe { entry_ptr(shared, u9::new(0)) },
+            winner: table(&mut p3),
+            frames: [table(&mut loser), table(&mut p1)],
+            allocated: 0,
+        };
+
+        let mut mapper = unsafe { MappedPageTable::new_shared(shared, Identity) };
+        unsafe {
+            mapper.map_to(
+                Page::<Size4KiB>::of_addr(0x1000),
+                Frame::of_addr(0x8000),
+                PageTableFlags::default_page(),
+                PageTableAttribute::new(0, 0, 0),
+                &mut allocator,
+            )
+        }
+        .unwrap()
+        .ignore();
+
+        // The losing table was used for the level 2 table instead.
+        assert_eq!(allocator.allocated, 2);
+        assert!(mapper.take_spare_frame().is_none());
+        assert_eq!(
+            mapper
+                .translate_addr(VirtAddr::new(0x1234))
+                .unwrap()
+                .as_u64(),
+            0x8234
+        );
+
+        // A mapped page is left as is.
+        let result = unsafe {
+            mapper.map_to(
+                Page::<Size4KiB>::of_addr(0x1000),
+                Frame::of_addr(0x9000),
+                PageTableFlags::default_page(),
+                PageTableAttribute::new(0, 0, 0),
+                &mut allocator,
+            )
+        };
+        assert!(matches!(result, Err(MapToError::PageAlreadyMapped)));
+        assert_eq!(allocator.allocated, 2);
+        assert_eq!(p4[0].addr(), table(&mut p3).start_address());
+        assert_eq!(p3[0].addr(), table(&mut loser).start_address());
+    }
+}
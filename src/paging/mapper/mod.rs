@@ -1,6 +1,6 @@
 //! Abstractions for reading and modifying the mapping of pages.
 
-pub use mapped_page_table::{MappedPageTable, PhysToVirt};
+pub use mapped_page_table::{MappedPageTable, PhysToVirt, TryPhysToVirt};
 pub use offset_page_table::OffsetPageTable;
 pub use recursive_page_table::RecursivePageTable;
 
@@ -12,6 +12,7 @@ use crate::{
         Frame, Page, PageSize, Size1GiB, Size2MiB, Size4KiB,
     },
 };
+use core::ops::Range;
 
 mod mapped_page_table;
 mod offset_page_table;
@@ -37,12 +38,49 @@ pub trait MapperAllSizes: Mapper<Size4KiB> + Mapper<Size2MiB> + Mapper<Size1GiB>
     /// [`translate`](MapperAllSizes::translate) method.
     fn translate_addr(&self, addr: VirtAddr) -> Option<PhysAddr> {
         match self.translate(addr) {
-            TranslateResult::PageNotMapped | TranslateResult::InvalidFrameAddress(_) => None,
+            TranslateResult::PageNotMapped
+            | TranslateResult::InvalidFrameAddress(_)
+            | TranslateResult::TableNotAccessible(_) => None,
             TranslateResult::Frame4KiB { frame, offset } => Some(frame.start_address() + offset),
             TranslateResult::Frame2MiB { frame, offset } => Some(frame.start_address() + offset),
             TranslateResult::Frame1GiB { frame, offset } => Some(frame.start_address() + offset),
         }
     }
+
+    /// Resolves an access flag fault at `addr` by setting the Access flag of its mapping, for
+    /// PEs without hardware management of the Access flag.
+    ///
+    /// Returns `Ok(false)` if `addr` has no valid mapping, in which case the fault must be
+    /// handled as a translation fault. No TLB maintenance is needed, since entries without the
+    /// Access flag are not cached.
+    fn resolve_access_flag_fault(&mut self, addr: VirtAddr) -> Result<bool, EntryGetError> {
+        let entry = match Mapper::<Size4KiB>::get_entry_mut(self, Page::containing_address(addr)) {
+            Err(EntryGetError::ParentEntryHugePage) => {
+                match Mapper::<Size2MiB>::get_entry_mut(self, Page::containing_address(addr)) {
+                    Err(EntryGetError::ParentEntryHugePage) => {
+                        Mapper::<Size1GiB>::get_entry_mut(self, Page::containing_address(addr))
+                    }
+                    result => result,
+                }
+            }
+            result => result,
+        };
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(EntryGetError::PageNotMapped) => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        if !entry.flags().contains(PageTableFlags::VALID) {
+            return Ok(false);
+        }
+        entry.set_young();
+
+        #[cfg(target_arch = "aarch64")]
+        unsafe {
+            crate::barrier::dsb(crate::barrier::ISHST);
+        }
+        Ok(true)
+    }
 }
 
 /// The return value of the [`MapperAllSizes::translate`] function.
@@ -76,6 +114,9 @@ pub enum TranslateResult {
     PageNotMapped,
     /// The page table entry for the given page points to an invalid physical address.
     InvalidFrameAddress(PhysAddr),
+    /// A page table on the way to the given page is located at a physical address that can't be
+    /// accessed.
+    TableNotAccessible(PhysAddr),
 }
 
 /// A trait for common page table operations on pages of size `S`.
@@ -123,6 +164,62 @@ pub trait Mapper<S: PageSize> {
         Ok(MapperFlush::new(page))
     }
 
+    /// Clears the Access flag of every page mapped in `pages`, and calls `f` with each page
+    /// that was accessed.
+    ///
+    /// Pages that aren't mapped with size `S` are skipped. The entries are updated atomically,
+    /// so concurrent hardware updates are not lost. The TLB entries of accessed pages may be
+    /// flushed or left stale, they only delay the next hardware update of the Access flag.
+    fn test_and_clear_young_range<F>(
+        &mut self,
+        pages: Range<Page<S>>,
+        mut f: F,
+    ) -> Result<(), EntryGetError>
+    where
+        F: FnMut(Page<S>, MapperFlush<S>),
+    {
+        for page in pages {
+            match self.get_entry_mut(page) {
+                Ok(entry) if is_leaf::<S>(entry) => {
+                    if entry.test_and_clear_young() {
+                        f(page, MapperFlush::new(page));
+                    }
+                }
+                Ok(_) | Err(EntryGetError::PageNotMapped | EntryGetError::ParentEntryHugePage) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks every dirty page mapped in `pages` clean again, and calls `f` with each page that
+    /// was dirty.
+    ///
+    /// A page is dirty when it has `DBM` set and `AP_RO` cleared by hardware, it is made clean
+    /// by setting `AP_RO`. Pages that aren't mapped with size `S` are skipped. The TLB entries of
+    /// dirty pages must be flushed, otherwise further writes are not tracked.
+    fn test_and_clear_dirty_range<F>(
+        &mut self,
+        pages: Range<Page<S>>,
+        mut f: F,
+    ) -> Result<(), EntryGetError>
+    where
+        F: FnMut(Page<S>, MapperFlush<S>),
+    {
+        for page in pages {
+            match self.get_entry_mut(page) {
+                Ok(entry) if is_leaf::<S>(entry) => {
+                    if entry.test_and_clear_dirty() {
+                        f(page, MapperFlush::new(page));
+                    }
+                }
+                Ok(_) | Err(EntryGetError::PageNotMapped | EntryGetError::ParentEntryHugePage) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
     /// Return the frame that the specified page is mapped to.
     ///
     /// This function assumes that the page is mapped to a frame of size `S` and returns an
@@ -157,6 +254,12 @@ pub trait Mapper<S: PageSize> {
     }
 }
 
+/// Returns whether `entry` is a valid page or block descriptor for a page of size `S`.
+#[inline]
+fn is_leaf<S: PageSize>(entry: &PageTableEntry) -> bool {
+    entry.flags().contains(PageTableFlags::VALID) && entry.is_block() == (S::SIZE != Size4KiB::SIZE)
+}
+
 /// This type represents a page whose mapping has changed in the page table.
 ///
 /// The old mapping might be still cached in the translation lookaside buffer (TLB), so it needs
@@ -193,6 +296,12 @@ pub enum MapToError {
     ParentEntryHugePage,
     /// The given page is already mapped to a physical frame.
     PageAlreadyMapped,
+    /// A page table on the way to the given page is located at a physical address that can't be
+    /// accessed.
+    TableNotAccessible(PhysAddr),
+    /// The frame allocated for a new page table can't be accessed. It isn't used by the mapper,
+    /// and should be given back to the frame allocator.
+    FrameNotAccessible(Frame),
 }
 
 /// An error indicating that an `get_entry` or `get_entry_mut` call failed.
@@ -203,6 +312,9 @@ pub enum EntryGetError {
     /// An upper level page table entry has the `HUGE_PAGE` flag set, which means that the
     /// given page is part of a huge page and can't be freed individually.
     ParentEntryHugePage,
+    /// A page table on the way to the given page is located at a physical address that can't be
+    /// accessed.
+    TableNotAccessible(PhysAddr),
 }
 
 /// An error indicating that an `unmap` call failed.
@@ -215,6 +327,9 @@ pub enum UnmapError {
     PageNotMapped,
     /// The page table entry for the given page points to an invalid physical address.
     InvalidFrameAddress(PhysAddr),
+    /// A page table on the way to the given page is located at a physical address that can't be
+    /// accessed.
+    TableNotAccessible(PhysAddr),
 }
 
 /// An error indicating that an `update_flags` call failed.
@@ -225,6 +340,9 @@ pub enum FlagUpdateError {
     /// An upper level page table entry has the `HUGE_PAGE` flag set, which means that the
     /// given page is part of a huge page and can't be freed individually.
     ParentEntryHugePage,
+    /// A page table on the way to the given page is located at a physical address that can't be
+    /// accessed.
+    TableNotAccessible(PhysAddr),
 }
 
 /// An error indicating that an `translate` call failed.
@@ -237,6 +355,9 @@ pub enum TranslateError {
     ParentEntryHugePage,
     /// The page table entry for the given page points to an invalid physical address.
     InvalidFrameAddress(PhysAddr),
+    /// A page table on the way to the given page is located at a physical address that can't be
+    /// accessed.
+    TableNotAccessible(PhysAddr),
 }
 
 impl From<EntryGetError> for UnmapError {
@@ -244,6 +365,7 @@ impl From<EntryGetError> for UnmapError {
         match err {
             EntryGetError::ParentEntryHugePage => UnmapError::ParentEntryHugePage,
             EntryGetError::PageNotMapped => UnmapError::PageNotMapped,
+            EntryGetError::TableNotAccessible(addr) => UnmapError::TableNotAccessible(addr),
         }
     }
 }
@@ -253,6 +375,7 @@ impl From<EntryGetError> for FlagUpdateError {
         match err {
             EntryGetError::ParentEntryHugePage => FlagUpdateError::ParentEntryHugePage,
             EntryGetError::PageNotMapped => FlagUpdateError::PageNotMapped,
+            EntryGetError::TableNotAccessible(addr) => FlagUpdateError::TableNotAccessible(addr),
         }
     }
 }
@@ -262,6 +385,7 @@ impl From<EntryGetError> for TranslateError {
         match err {
             EntryGetError::ParentEntryHugePage => TranslateError::ParentEntryHugePage,
             EntryGetError::PageNotMapped => TranslateError::PageNotMapped,
+            EntryGetError::TableNotAccessible(addr) => TranslateError::TableNotAccessible(addr),
         }
     }
 }
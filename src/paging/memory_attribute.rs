@@ -1,6 +1,6 @@
 //! Memory region attributes (D4.5, page 2174)
 
-use crate::paging::table::{PageTableAttribute, MEMORY_ATTRIBUTE};
+use crate::paging::table::{PageTableAttribute, MEMORY_ATTRIBUTE, MEMORY_ATTR_MASK};
 
 tock_registers::register_bitfields! {u64,
     pub MAIR_ATTR [
@@ -17,6 +17,9 @@ tock_registers::register_bitfields! {u64,
             Device_GRE    = 0b1100
         ],
         Attr_LOW_MEMORY OFFSET(0) NUMBITS(4) [
+            /// Tagged Normal memory, with Attr_HIGH being
+            /// Memory_OuterWriteBack_NonTransient_ReadAlloc_WriteAlloc (FEAT_MTE2).
+            Tagged = 0b0000,
             InnerNonCacheable = 0b0100,
             InnerWriteThrough_NonTransient_ReadAlloc_WriteAlloc = 0b1011,
             InnerWriteBack_NonTransient_ReadAlloc_WriteAlloc = 0b1111
@@ -27,17 +30,40 @@ tock_registers::register_bitfields! {u64,
 pub trait MairType {
     const INDEX: u64;
 
+    /// The memory attribute fields of a descriptor using this memory type.
+    ///
+    /// Unlike [`attr_value`](MairType::attr_value), this can be used in constant expressions.
+    const ATTR: PageTableAttribute;
+
     fn config_value() -> u64;
 
-    fn attr_value() -> PageTableAttribute;
+    #[inline]
+    fn attr_value() -> PageTableAttribute {
+        Self::ATTR
+    }
+}
+
+/// Returns the memory attribute fields for the shareability `sh` and the MAIR_EL1 index `index`.
+const fn attr(sh: PageTableAttribute, index: u64) -> PageTableAttribute {
+    PageTableAttribute::new(
+        MEMORY_ATTR_MASK,
+        0,
+        sh.value | index << MEMORY_ATTRIBUTE::AttrIndx.shift,
+    )
 }
 
 pub enum MairDevice {}
 pub enum MairNormal {}
 pub enum MairNormalNonCacheable {}
+/// Normal Write-Back memory holding Allocation Tags, for the Memory Tagging Extension.
+///
+/// This type is not part of [`mair_value`]: its MAIR_EL1 encoding is reserved without
+/// FEAT_MTE2, see [`mte::is_supported`](crate::mte::is_supported).
+pub enum MairNormalTagged {}
 
 impl MairType for MairNormal {
     const INDEX: u64 = 0;
+    const ATTR: PageTableAttribute = attr(MEMORY_ATTRIBUTE::SH::InnerShareable, Self::INDEX);
 
     #[inline]
     fn config_value() -> u64 {
@@ -45,29 +71,21 @@ impl MairType for MairNormal {
             + MAIR_ATTR::Attr_LOW_MEMORY::InnerWriteBack_NonTransient_ReadAlloc_WriteAlloc)
             .value
     }
-
-    #[inline]
-    fn attr_value() -> PageTableAttribute {
-        MEMORY_ATTRIBUTE::SH::InnerShareable + MEMORY_ATTRIBUTE::AttrIndx.val(Self::INDEX)
-    }
 }
 
 impl MairType for MairDevice {
     const INDEX: u64 = 1;
+    const ATTR: PageTableAttribute = attr(MEMORY_ATTRIBUTE::SH::OuterShareable, Self::INDEX);
 
     #[inline]
     fn config_value() -> u64 {
         (MAIR_ATTR::Attr_HIGH::Device + MAIR_ATTR::Attr_LOW_DEVICE::Device_nGnRE).value
     }
-
-    #[inline]
-    fn attr_value() -> PageTableAttribute {
-        MEMORY_ATTRIBUTE::SH::OuterShareable + MEMORY_ATTRIBUTE::AttrIndx.val(Self::INDEX)
-    }
 }
 
 impl MairType for MairNormalNonCacheable {
     const INDEX: u64 = 2;
+    const ATTR: PageTableAttribute = attr(MEMORY_ATTRIBUTE::SH::OuterShareable, Self::INDEX);
 
     #[inline]
     fn config_value() -> u64 {
@@ -75,9 +93,28 @@ impl MairType for MairNormalNonCacheable {
             + MAIR_ATTR::Attr_LOW_MEMORY::InnerNonCacheable)
             .value
     }
+}
+
+impl MairType for MairNormalTagged {
+    const INDEX: u64 = 3;
+    const ATTR: PageTableAttribute = attr(MEMORY_ATTRIBUTE::SH::InnerShareable, Self::INDEX);
 
     #[inline]
-    fn attr_value() -> PageTableAttribute {
-        MEMORY_ATTRIBUTE::SH::OuterShareable + MEMORY_ATTRIBUTE::AttrIndx.val(Self::INDEX)
+    fn config_value() -> u64 {
+        (MAIR_ATTR::Attr_HIGH::Memory_OuterWriteBack_NonTransient_ReadAlloc_WriteAlloc
+            + MAIR_ATTR::Attr_LOW_MEMORY::Tagged)
+            .value
     }
 }
+
+/// Returns the `Attr<n>` field of MAIR_EL1 describing the memory type `M`.
+#[inline]
+pub fn mair_field<M: MairType>() -> u64 {
+    M::config_value() << (M::INDEX * 8)
+}
+
+/// Returns the value of MAIR_EL1 describing every memory type of this module.
+#[inline]
+pub fn mair_value() -> u64 {
+    mair_field::<MairNormal>() | mair_field::<MairDevice>() | mair_field::<MairNormalNonCacheable>()
+}
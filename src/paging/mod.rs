@@ -1,15 +1,26 @@
+pub use address_space::AddressSpace;
 pub use frame::Frame;
 pub use frame_alloc::{FrameAllocator, FrameDeallocator};
 pub use mapper::{MappedPageTable, Mapper, MapperAllSizes, RecursivePageTable};
 pub use page::Page;
-pub use table::{PageTable, PageTableAttribute, PageTableEntry, PageTableFlags};
+pub use table::{
+    AtomicPageTableEntry, PageTable, PageTableAttribute, PageTableEntry, PageTableFlags,
+};
 
+mod address_space;
+pub mod asid;
+pub mod early;
 pub mod frame;
 mod frame_alloc;
 pub mod mapper;
 pub mod memory_attribute;
 pub mod page;
+pub mod static_table;
 pub mod table;
+pub mod tcr;
+
+/// The number of VA bits translated by the 4-level page tables of this module.
+pub const VA_BITS: u32 = 48;
 
 /// Trait for abstracting over the three possible block/page sizes on aarch64, 4KiB, 2MiB, 1GiB.
 pub trait PageSize: Copy + Eq + PartialOrd + Ord {
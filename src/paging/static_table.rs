@@ -0,0 +1,274 @@
+//! Page tables built at compile time.
+//!
+//! A [`StaticPageTables`] is generated by constant evaluation from a list of [`Region`]s, so the
+//! whole hierarchy is baked into the image. Misaligned or overlapping regions, or too few tables,
+//! make the constant evaluation fail with a compile error.
+//!
+//! The descriptors hold the physical addresses of the next level tables, so the physical address
+//! the tables are loaded at must be known at build time, e.g. by placing them in a dedicated
+//! section of the linker script.
+
+use crate::{
+    addr::{PhysAddr, VirtAddr},
+    paging::{
+        table::{PageTable, PageTableAttribute, PageTableEntry, PageTableFlags, ENTRY_COUNT},
+        Frame, PageSize, Size1GiB, Size2MiB, Size4KiB,
+    },
+};
+
+/// A range of virtual memory to map, with its permissions and memory type.
+#[derive(Clone, Copy)]
+pub struct Region {
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    attr: PageTableAttribute,
+}
+
+impl Region {
+    /// Creates a region mapping `[virt, virt + size)` to `[phys, phys + size)`.
+    ///
+    /// `flags` only needs the permission and software bits, `VALID`, `AF` and `TABLE_OR_PAGE`
+    /// are set accordingly.
+    #[inline]
+    pub const fn new(
+        virt: VirtAddr,
+        phys: PhysAddr,
+        size: u64,
+        flags: PageTableFlags,
+        attr: PageTableAttribute,
+    ) -> Self {
+        Self {
+            virt,
+            phys,
+            size,
+            flags,
+            attr,
+        }
+    }
+
+    /// Creates a region mapping `[phys, phys + size)` at the same virtual address.
+    #[inline]
+    pub const fn identity(
+        phys: PhysAddr,
+        size: u64,
+        flags: PageTableFlags,
+        attr: PageTableAttribute,
+    ) -> Self {
+        Self::new(VirtAddr::new(phys.as_u64()), phys, size, flags, attr)
+    }
+}
+
+/// A page table hierarchy of up to `N` tables, the first one being the level 4 table.
+///
+/// # Example
+/// ```no_run
+/// # use aarch64::{
+/// #     addr::PhysAddr,
+/// #     paging::{
+/// #         memory_attribute::{MairDevice, MairNormal, MairType},
+/// #         static_table::{Region, StaticPageTables},
+/// #         PageTableFlags,
+/// #     },
+/// # };
+/// // Placed at 0x4008_0000 by the linker script.
+/// #[link_section = ".page_tables"]
+/// static TABLES: StaticPageTables<4> = StaticPageTables::build(
+///     PhysAddr::new(0x4008_0000),
+///     &[
+///         Region::identity(
+///             PhysAddr::new(0x0900_0000),
+///             0x1000,
+///             PageTableFlags::UXN.union(PageTableFlags::PXN),
+///             MairDevice::ATTR,
+///         ),
+///         Region::identity(
+///             PhysAddr::new(0x4000_0000),
+///             0x4000_0000,
+///             PageTableFlags::UXN,
+///             MairNormal::ATTR,
+///         ),
+///     ],
+/// );
+/// ```
+#[repr(C)]
+pub struct StaticPageTables<const N: usize> {
+    tables: [PageTable; N],
+    base: PhysAddr,
+}
+
+impl<const N: usize> StaticPageTables<N> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: PageTable = PageTable::new();
+
+    /// Builds the page tables mapping `regions`, for tables located at the physical address
+    /// `base`.
+    ///
+    /// 1GiB and 2MiB blocks are used wherever possible, as in
+    /// [`EarlyPageTable::map_range`](super::early::EarlyPageTable::map_range). Unused tables are
+    /// left empty.
+    ///
+    /// ## Panics
+    ///
+    /// Panics (fails to compile when evaluated as a constant) if `base` or a region isn't 4KiB
+    /// aligned, if two regions overlap, or if more than `N` tables are needed.
+    pub const fn build(base: PhysAddr, regions: &[Region]) -> Self {
+        if base.as_u64() % Size4KiB::SIZE != 0 {
+            panic!("page tables are not 4KiB aligned");
+        }
+        let mut this = Self {
+            tables: [Self::EMPTY; N],
+            base,
+        };
+        let mut used = 1;
+        let mut i = 0;
+        while i < regions.len() {
+            used = this.map_region(&regions[i], used);
+            i += 1;
+        }
+        this
+    }
+
+    /// Returns the level 4 table.
+    #[inline]
+    pub const fn root(&self) -> &PageTable {
+        &self.tables[0]
+    }
+
+    /// Returns the frame of the level 4 table, to be written to a TTBR.
+    #[inline]
+    pub fn root_frame(&self) -> Frame {
+        Frame::of_addr(self.base.as_u64())
+    }
+
+    /// Maps `region`, and returns the new number of used tables.
+    const fn map_region(&mut self, region: &Region, mut used: usize) -> usize {
+        let (virt, phys, size) = (region.virt.as_u64(), region.phys.as_u64(), region.size);
+        if (virt | phys | size) % Size4KiB::SIZE != 0 {
+            panic!("region is not 4KiB aligned");
+        }
+
+        let flags = region
+            .flags
+            .union(PageTableFlags::VALID)
+            .union(PageTableFlags::AF);
+        let mut offset = 0;
+        while offset < size {
+            let (virt, phys, remaining) = (virt + offset, phys + offset, size - offset);
+            // The level of the descriptor to write, 3 for 1GiB blocks down to 1 for pages.
+            let (level, block_size) = if block_fits(virt | phys, remaining, Size1GiB::SIZE) {
+                (3, Size1GiB::SIZE)
+            } else if block_fits(virt | phys, remaining, Size2MiB::SIZE) {
+                (2, Size2MiB::SIZE)
+            } else {
+                (1, Size4KiB::SIZE)
+            };
+
+            let mut table = 0;
+            let mut current = 4;
+            while current > level {
+                let index = table_index(virt, current);
+                let entry = self.tables[table].entries[index];
+                if entry.is_unused() {
+                    if used == N {
+                        panic!("not enough page tables");
+                    }
+                    let addr =
+                        self.base.as_u64() + (used * core::mem::size_of::<PageTable>()) as u64;
+                    self.tables[table].entries[index] = PageTableEntry::from_addr(
+                        PhysAddr::new(addr),
+                        PageTableFlags::default_table(),
+                        PageTableAttribute::new(0, 0, 0),
+                    );
+                    table = used;
+                    used += 1;
+                } else if entry.is_block() {
+                    panic!("overlapping regions");
+                } else {
+                    table = ((entry.addr().as_u64() - self.base.as_u64())
+                        / core::mem::size_of::<PageTable>() as u64)
+                        as usize;
+                }
+                current -= 1;
+            }
+
+            let index = table_index(virt, level);
+            if !self.tables[table].entries[index].is_unused() {
+                panic!("overlapping regions");
+            }
+            let flags = if level == 1 {
+                flags.union(PageTableFlags::TABLE_OR_PAGE)
+            } else {
+                flags.difference(PageTableFlags::TABLE_OR_PAGE)
+            };
+            self.tables[table].entries[index] =
+                PageTableEntry::from_addr(PhysAddr::new(phys), flags, region.attr);
+            offset += block_size;
+        }
+        used
+    }
+}
+
+/// Returns whether a block of `block_size` bytes can be used at `addr` with `remaining` bytes
+/// left to map.
+const fn block_fits(addr: u64, remaining: u64, block_size: u64) -> bool {
+    addr % block_size == 0 && remaining >= block_size
+}
+
+/// Returns the index of `virt` into a table of the given level.
+const fn table_index(virt: u64, level: u32) -> usize {
+    ((virt >> (12 + 9 * (level - 1))) as usize) % ENTRY_COUNT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paging::memory_attribute::{MairDevice, MairNormal, MairType};
+
+    const BASE: u64 = 0x4008_0000;
+
+    static TABLES: StaticPageTables<4> = StaticPageTables::build(
+        PhysAddr::new(BASE),
+        &[
+            Region::identity(
+                PhysAddr::new(0x0900_0000),
+                0x2000,
+                PageTableFlags::UXN.union(PageTableFlags::PXN),
+                MairDevice::ATTR,
+            ),
+            Region::identity(
+                PhysAddr::new(0x4000_0000),
+                0x4000_0000,
+                PageTableFlags::AP_RO,
+                MairNormal::ATTR,
+            ),
+        ],
+    );
+
+    #[test]
+    pub fn test_static_tables() {
+        let root = TABLES.root();
+        assert_eq!(TABLES.root_frame().start_address(), PhysAddr::new(BASE));
+        assert_eq!(root[0].addr(), PhysAddr::new(BASE + 0x1000));
+        assert_eq!(root[0].flags(), PageTableFlags::default_table());
+
+        // The device pages, through a level 2 and a level 1 table.
+        let p3 = &TABLES.tables[1];
+        assert_eq!(p3[0].addr(), PhysAddr::new(BASE + 0x2000));
+        let p1 = &TABLES.tables[3];
+        assert_eq!(TABLES.tables[2][0x48].addr(), PhysAddr::new(BASE + 0x3000));
+        assert_eq!(p1[0].addr(), PhysAddr::new(0x0900_0000));
+        assert_eq!(p1[1].addr(), PhysAddr::new(0x0900_1000));
+        assert_eq!(p1[1].attr().value, MairDevice::ATTR.value);
+        assert!(p1[2].is_unused());
+
+        // The 1GiB block shares the level 3 table.
+        assert!(p3[1].is_block());
+        assert_eq!(p3[1].addr(), PhysAddr::new(0x4000_0000));
+        assert_eq!(
+            p3[1].flags(),
+            PageTableFlags::default_block() | PageTableFlags::AP_RO
+        );
+    }
+}
@@ -6,6 +6,7 @@ use crate::addr::PhysAddr;
 use core::{
     fmt,
     ops::{Index, IndexMut},
+    sync::atomic::{AtomicU64, Ordering},
 };
 use tock_registers::fields::FieldValue;
 use ux::*;
@@ -39,9 +40,28 @@ pub struct PageTableEntry {
 }
 
 impl PageTableEntry {
+    /// Creates an unused (zero) entry.
+    #[inline]
+    pub const fn new() -> Self {
+        Self { entry: 0 }
+    }
+
+    /// Creates an entry mapping the specified physical address with the specified flags and
+    /// memory attribute.
+    #[inline]
+    pub const fn from_addr(
+        addr: PhysAddr,
+        flags: PageTableFlags,
+        attr: PageTableAttribute,
+    ) -> Self {
+        Self {
+            entry: addr.as_u64() | flags.bits() | attr.value,
+        }
+    }
+
     /// Returns whether this entry is zero.
     #[inline]
-    pub fn is_unused(self) -> bool {
+    pub const fn is_unused(self) -> bool {
         self.entry == 0
     }
 
@@ -53,13 +73,13 @@ impl PageTableEntry {
 
     /// Returns the flags of this entry.
     #[inline]
-    pub fn flags(self) -> PageTableFlags {
+    pub const fn flags(self) -> PageTableFlags {
         PageTableFlags::from_bits_truncate(self.entry)
     }
 
     /// Returns the physical address mapped by this entry, might be zero.
     #[inline]
-    pub fn addr(self) -> PhysAddr {
+    pub const fn addr(self) -> PhysAddr {
         PhysAddr::new(self.entry & ADDR_MASK)
     }
 
@@ -71,10 +91,25 @@ impl PageTableEntry {
 
     /// Returns whether this entry is mapped to a block.
     #[inline]
-    pub fn is_block(self) -> bool {
+    pub const fn is_block(self) -> bool {
         !self.flags().contains(PageTableFlags::TABLE_OR_PAGE)
     }
 
+    /// Returns whether the Access flag is set, i.e. the mapping has been accessed since the
+    /// flag was last cleared.
+    #[inline]
+    pub const fn is_young(self) -> bool {
+        self.flags().contains(PageTableFlags::AF)
+    }
+
+    /// Returns whether the mapping has been written, as tracked by hardware dirty state
+    /// management: `DBM` is set and `AP_RO` has been cleared.
+    #[inline]
+    pub const fn is_dirty(self) -> bool {
+        let flags = self.flags();
+        flags.contains(PageTableFlags::DBM) && !flags.contains(PageTableFlags::AP_RO)
+    }
+
     /// Returns the physical frame mapped by this entry.
     ///
     /// Returns the following errors:
@@ -97,7 +132,7 @@ impl PageTableEntry {
     /// attribute.
     pub fn set_addr(&mut self, addr: PhysAddr, flags: PageTableFlags, attr: PageTableAttribute) {
         debug_assert!(addr.is_aligned(Size4KiB::SIZE));
-        self.entry = (addr.as_u64()) | flags.bits() | attr.value;
+        *self = Self::from_addr(addr, flags, attr);
     }
 
     /// Map the entry to the specified physical frame with the specified flags and memory attribute.
@@ -120,6 +155,42 @@ impl PageTableEntry {
         self.set_addr(addr.align_down(S::SIZE), flags, attr);
     }
 
+    /// Clears the Access flag, and returns whether it was set.
+    ///
+    /// The entry is updated atomically, so that a concurrent update of the dirty state by
+    /// hardware is not lost.
+    #[inline]
+    pub fn test_and_clear_young(&mut self) -> bool {
+        AtomicPageTableEntry::from_mut(self)
+            .fetch_clear_flags(PageTableFlags::AF, Ordering::Relaxed)
+            .is_young()
+    }
+
+    /// Marks a dirty entry clean again by setting `AP_RO`, and returns whether it was dirty.
+    ///
+    /// The entry is updated with a compare-and-exchange loop, so that a concurrent update of
+    /// the Access flag by hardware is not lost. The next write sets the entry dirty again.
+    #[inline]
+    pub fn test_and_clear_dirty(&mut self) -> bool {
+        AtomicPageTableEntry::from_mut(self)
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |entry| {
+                entry.is_dirty().then(|| PageTableEntry {
+                    entry: entry.entry | PageTableFlags::AP_RO.bits(),
+                })
+            })
+            .is_ok()
+    }
+
+    /// Sets the Access flag, and returns whether it was already set.
+    ///
+    /// This is how access flag faults are resolved by software without FEAT_HAFDBS.
+    #[inline]
+    pub fn set_young(&mut self) -> bool {
+        AtomicPageTableEntry::from_mut(self)
+            .fetch_set_flags(PageTableFlags::AF, Ordering::Relaxed)
+            .is_young()
+    }
+
     /// Sets the flags of this entry.
     pub fn set_flags(&mut self, flags: PageTableFlags) {
         self.entry = (self.entry & !FLAGS_MASK) | flags.bits();
@@ -142,6 +213,149 @@ impl fmt::Debug for PageTableEntry {
     }
 }
 
+/// A page table entry that can be shared with the table walker and other PEs.
+///
+/// Every access is atomic, so that concurrent hardware updates of the Access flag and dirty
+/// state, or concurrent updates by other PEs, are neither lost nor racy. Updates that must be
+/// seen by the table walker are followed by [`publish_barrier`], see
+/// [`publish`](AtomicPageTableEntry::publish).
+#[repr(transparent)]
+pub struct AtomicPageTableEntry {
+    entry: AtomicU64,
+}
+
+impl AtomicPageTableEntry {
+    /// Creates a new atomic entry.
+    #[inline]
+    pub const fn new(entry: PageTableEntry) -> Self {
+        Self {
+            entry: AtomicU64::new(entry.entry),
+        }
+    }
+
+    /// Returns an atomic view of `entry`, e.g. to update it while the hardware table walker may
+    /// update its Access flag or dirty state.
+    ///
+    /// The entry is borrowed exclusively, so this is not for tables shared with other PEs: use
+    /// [`from_ptr`](Self::from_ptr) for those.
+    #[inline]
+    pub fn from_mut(entry: &mut PageTableEntry) -> &Self {
+        // Both types are a transparent `u64`.
+        unsafe { &*(entry as *mut PageTableEntry as *const Self) }
+    }
+
+    /// Returns an atomic view of the entry at `ptr`.
+    ///
+    /// ## Safety
+    ///
+    /// This function is unsafe because `ptr` must be valid for `'a`, and every concurrent
+    /// access to the entry must be atomic.
+    #[inline]
+    pub unsafe fn from_ptr<'a>(ptr: *mut PageTableEntry) -> &'a Self {
+        &*(ptr as *const Self)
+    }
+
+    /// Loads the entry.
+    ///
+    /// Use `Ordering::Acquire` before following a table descriptor written by another PE.
+    #[inline]
+    pub fn load(&self, order: Ordering) -> PageTableEntry {
+        PageTableEntry {
+            entry: self.entry.load(order),
+        }
+    }
+
+    /// Stores `entry`.
+    ///
+    /// Use `Ordering::Release` when `entry` points to a table that was just initialized.
+    #[inline]
+    pub fn store(&self, entry: PageTableEntry, order: Ordering) {
+        self.entry.store(entry.entry, order)
+    }
+
+    /// Stores `entry` with release ordering, and makes it visible to the table walker.
+    #[inline]
+    pub fn publish(&self, entry: PageTableEntry) {
+        self.store(entry, Ordering::Release);
+        publish_barrier();
+    }
+
+    /// Stores `new` if the entry is `current`, and returns the previous entry.
+    ///
+    /// See [`AtomicU64::compare_exchange`] for the orderings.
+    #[inline]
+    pub fn compare_exchange(
+        &self,
+        current: PageTableEntry,
+        new: PageTableEntry,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<PageTableEntry, PageTableEntry> {
+        self.entry
+            .compare_exchange(current.entry, new.entry, success, failure)
+            .map(|entry| PageTableEntry { entry })
+            .map_err(|entry| PageTableEntry { entry })
+    }
+
+    /// Updates the entry with `f` in a compare-and-exchange loop, until `f` returns `None` or
+    /// the update succeeds, and returns the previous entry.
+    ///
+    /// See [`AtomicU64::fetch_update`] for the orderings.
+    #[inline]
+    pub fn fetch_update<F>(
+        &self,
+        set_order: Ordering,
+        fetch_order: Ordering,
+        mut f: F,
+    ) -> Result<PageTableEntry, PageTableEntry>
+    where
+        F: FnMut(PageTableEntry) -> Option<PageTableEntry>,
+    {
+        self.entry
+            .fetch_update(set_order, fetch_order, |entry| {
+                f(PageTableEntry { entry }).map(|entry| entry.entry)
+            })
+            .map(|entry| PageTableEntry { entry })
+            .map_err(|entry| PageTableEntry { entry })
+    }
+
+    /// Sets `flags`, and returns the previous entry.
+    #[inline]
+    pub fn fetch_set_flags(&self, flags: PageTableFlags, order: Ordering) -> PageTableEntry {
+        PageTableEntry {
+            entry: self.entry.fetch_or(flags.bits(), order),
+        }
+    }
+
+    /// Clears `flags`, and returns the previous entry.
+    #[inline]
+    pub fn fetch_clear_flags(&self, flags: PageTableFlags, order: Ordering) -> PageTableEntry {
+        PageTableEntry {
+            entry: self.entry.fetch_and(!flags.bits(), order),
+        }
+    }
+}
+
+impl fmt::Debug for AtomicPageTableEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.load(Ordering::Relaxed).fmt(f)
+    }
+}
+
+/// Makes the preceding page table updates visible to the table walker of every PE in the
+/// Inner Shareable domain, and synchronizes the context of the current PE.
+///
+/// This is needed after creating a valid mapping that may be used right away. Removing or
+/// changing a mapping additionally requires TLB maintenance.
+#[inline]
+pub fn publish_barrier() {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        crate::barrier::dsb(crate::barrier::ISHST);
+        crate::barrier::isb(crate::barrier::SY);
+    }
+}
+
 tock_registers::register_bitfields! { u64,
     // Memory attribute fields in the VMSAv8-64 translation table format descriptors (Page 2148~2152)
     pub MEMORY_ATTRIBUTE [
@@ -176,6 +390,8 @@ bitflags::bitflags! {
         const AF =              1 << 10;
         /// not global bit
         const nG =              1 << 11;
+        /// Guarded Page: indirect branches must target BTI instructions (FEAT_BTI)
+        const GP =              1 << 50;
         /// Dirty Bit Modifier
         const DBM =             1 << 51;
 
@@ -214,46 +430,75 @@ bitflags::bitflags! {
 impl PageTableFlags {
     /// default flags for the table entry
     #[inline]
-    pub fn default_table() -> Self {
-        Self::VALID | Self::TABLE_OR_PAGE
+    pub const fn default_table() -> Self {
+        Self::VALID.union(Self::TABLE_OR_PAGE)
     }
 
     /// default flags for the block entry
     #[inline]
-    pub fn default_block() -> Self {
-        Self::VALID | Self::AF
+    pub const fn default_block() -> Self {
+        Self::VALID.union(Self::AF)
     }
 
     /// default flags for the page entry
     #[inline]
-    pub fn default_page() -> Self {
-        Self::VALID | Self::TABLE_OR_PAGE | Self::AF
+    pub const fn default_page() -> Self {
+        Self::VALID.union(Self::TABLE_OR_PAGE).union(Self::AF)
     }
 }
 
 /// The number of entries in a page table.
-const ENTRY_COUNT: usize = 512;
+pub(super) const ENTRY_COUNT: usize = 512;
 
 #[derive(Clone)]
 #[repr(align(4096))]
 #[repr(C)]
 pub struct PageTable {
-    entries: [PageTableEntry; ENTRY_COUNT],
+    pub(super) entries: [PageTableEntry; ENTRY_COUNT],
 }
 
 impl Default for PageTable {
     fn default() -> Self {
-        Self {
-            entries: [PageTableEntry::default(); ENTRY_COUNT],
-        }
+        Self::new()
     }
 }
 
 impl PageTable {
     /// Create a empty page table.
     #[inline]
-    pub fn new() -> Self {
-        Self::default()
+    pub const fn new() -> Self {
+        Self {
+            entries: [PageTableEntry::new(); ENTRY_COUNT],
+        }
+    }
+
+    /// Returns this table with the entry at `index` replaced by `entry`.
+    ///
+    /// This allows to write small page tables as constants:
+    ///
+    /// ```no_run
+    /// # use aarch64::{
+    /// #     addr::PhysAddr,
+    /// #     paging::{
+    /// #         memory_attribute::{MairDevice, MairNormal, MairType},
+    /// #         PageTable, PageTableEntry, PageTableFlags,
+    /// #     },
+    /// # };
+    /// const FLAGS: PageTableFlags = PageTableFlags::default_block();
+    /// const TABLE: PageTable = PageTable::new()
+    ///     .with_entry(
+    ///         0,
+    ///         PageTableEntry::from_addr(PhysAddr::new(0), FLAGS, MairDevice::ATTR),
+    ///     )
+    ///     .with_entry(
+    ///         1,
+    ///         PageTableEntry::from_addr(PhysAddr::new(0x4000_0000), FLAGS, MairNormal::ATTR),
+    ///     );
+    /// ```
+    #[inline]
+    pub const fn with_entry(mut self, index: usize, entry: PageTableEntry) -> Self {
+        self.entries[index] = entry;
+        self
     }
 
     /// Clears all entries.
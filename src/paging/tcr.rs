@@ -0,0 +1,549 @@
+//! Configuration of the Translation Control Register, TCR_EL1.
+
+use crate::{
+    barrier,
+    paging::{asid::AsidBits, VA_BITS},
+    registers::*,
+    translation::{Shareability, Ttbr},
+};
+use tock_registers::{fields::FieldValue, LocalRegisterCopy};
+
+/// Translation granule size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Granule {
+    Size4KiB,
+    Size16KiB,
+    Size64KiB,
+}
+
+impl Granule {
+    /// Returns whether the current PE supports this granule size for stage 1 translation.
+    #[inline]
+    pub fn is_supported(self) -> bool {
+        match self {
+            Granule::Size4KiB => ID_AA64MMFR0_EL1.matches_all(ID_AA64MMFR0_EL1::TGran4::Supported),
+            Granule::Size16KiB => {
+                ID_AA64MMFR0_EL1.matches_all(ID_AA64MMFR0_EL1::TGran16::Supported)
+            }
+            Granule::Size64KiB => {
+                ID_AA64MMFR0_EL1.matches_all(ID_AA64MMFR0_EL1::TGran64::Supported)
+            }
+        }
+    }
+
+    /// Returns the size of the granule in bytes.
+    #[inline]
+    pub const fn size(self) -> u64 {
+        match self {
+            Granule::Size4KiB => 4 << 10,
+            Granule::Size16KiB => 16 << 10,
+            Granule::Size64KiB => 64 << 10,
+        }
+    }
+}
+
+/// Cacheability attribute for memory accesses of translation table walks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cacheability {
+    /// Normal memory, Non-cacheable.
+    NonCacheable = 0b00,
+    /// Normal memory, Write-Back Read-Allocate Write-Allocate Cacheable.
+    WriteBack = 0b01,
+    /// Normal memory, Write-Through Read-Allocate No Write-Allocate Cacheable.
+    WriteThrough = 0b10,
+    /// Normal memory, Write-Back Read-Allocate No Write-Allocate Cacheable.
+    WriteBackNoWriteAllocate = 0b11,
+}
+
+/// Physical address size, as reported by ID_AA64MMFR0_EL1.PARange and configured by
+/// TCR_EL1.IPS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PaRange {
+    Bits32 = 0b000,
+    Bits36 = 0b001,
+    Bits40 = 0b010,
+    Bits42 = 0b011,
+    Bits44 = 0b100,
+    Bits48 = 0b101,
+    Bits52 = 0b110,
+}
+
+impl PaRange {
+    /// Reads the physical address range supported by the current PE.
+    #[inline]
+    pub fn current() -> Self {
+        Self::from_bits(ID_AA64MMFR0_EL1.read(ID_AA64MMFR0_EL1::PARange))
+    }
+
+    /// Decodes a PARange or IPS field. Reserved values are treated as 52 bits.
+    pub fn from_bits(bits: u64) -> Self {
+        match bits {
+            0b000 => PaRange::Bits32,
+            0b001 => PaRange::Bits36,
+            0b010 => PaRange::Bits40,
+            0b011 => PaRange::Bits42,
+            0b100 => PaRange::Bits44,
+            0b101 => PaRange::Bits48,
+            _ => PaRange::Bits52,
+        }
+    }
+
+    /// Returns the physical address width in bits.
+    #[inline]
+    pub const fn bits(self) -> u32 {
+        match self {
+            PaRange::Bits32 => 32,
+            PaRange::Bits36 => 36,
+            PaRange::Bits40 => 40,
+            PaRange::Bits42 => 42,
+            PaRange::Bits44 => 44,
+            PaRange::Bits48 => 48,
+            PaRange::Bits52 => 52,
+        }
+    }
+}
+
+/// The error returned by [`TcrConfig::build`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcrError {
+    /// The VA width of the given range is outside of `MIN_VA_BITS..=MAX_VA_BITS`.
+    InvalidVaBits(Ttbr),
+    /// Hardware management of dirty state was requested without hardware management of the
+    /// Access flag.
+    DirtyStateWithoutAccessFlag,
+    /// A 52-bit physical address size was requested without the 64KiB granule in both ranges.
+    PaRangeNeedsLargeGranule,
+}
+
+/// Configuration of one of the two VA ranges.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RegionConfig {
+    va_bits: u32,
+    granule: Granule,
+    inner: Cacheability,
+    outer: Cacheability,
+    shareability: Shareability,
+    walks_disabled: bool,
+    top_byte_ignored: bool,
+    match_all_unchecked: bool,
+}
+
+impl RegionConfig {
+    const fn new() -> Self {
+        Self {
+            va_bits: VA_BITS,
+            granule: Granule::Size4KiB,
+            inner: Cacheability::WriteBack,
+            outer: Cacheability::WriteBack,
+            shareability: Shareability::InnerShareable,
+            walks_disabled: false,
+            top_byte_ignored: false,
+            match_all_unchecked: false,
+        }
+    }
+}
+
+/// A builder for the value of TCR_EL1.
+///
+/// The default configuration matches the page tables of the `paging` module: both VA ranges are
+/// [`VA_BITS`] wide, use a 4KiB granule and Inner Shareable Write-Back table walks. The ASID is
+/// taken from TTBR0_EL1 and is 8 bits wide, the physical address size is 48 bits.
+///
+/// # Example
+/// ```no_run
+/// # use aarch64::{paging::{asid::AsidBits, tcr::{PaRange, TcrConfig}}, translation::Ttbr};
+/// let tcr = TcrConfig::new()
+///     .with_pa_range(PaRange::current().min(PaRange::Bits48))
+///     .with_asid_bits(AsidBits::current())
+///     .with_top_byte_ignored(Ttbr::Lower, true)
+///     .build()
+///     .unwrap();
+/// unsafe { tcr.write() };
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcrConfig {
+    lower: RegionConfig,
+    upper: RegionConfig,
+    asid_from: Ttbr,
+    asid_bits: AsidBits,
+    hardware_access_flag: bool,
+    hardware_dirty_state: bool,
+    pa_range: PaRange,
+}
+
+impl TcrConfig {
+    /// The minimum VA width of a range, i.e. the maximum T0SZ/T1SZ of 39.
+    pub const MIN_VA_BITS: u32 = 25;
+    /// The maximum VA width of a range, i.e. the minimum T0SZ/T1SZ of 16.
+    pub const MAX_VA_BITS: u32 = 48;
+
+    /// Creates the default configuration.
+    pub const fn new() -> Self {
+        Self {
+            lower: RegionConfig::new(),
+            upper: RegionConfig::new(),
+            asid_from: Ttbr::Lower,
+            asid_bits: AsidBits::Bits8,
+            hardware_access_flag: false,
+            hardware_dirty_state: false,
+            pa_range: PaRange::Bits48,
+        }
+    }
+
+    fn region(&mut self, ttbr: Ttbr) -> &mut RegionConfig {
+        match ttbr {
+            Ttbr::Lower => &mut self.lower,
+            Ttbr::Upper => &mut self.upper,
+        }
+    }
+
+    /// Sets the VA width of a range, i.e. T0SZ or T1SZ to `64 - va_bits`.
+    pub fn with_va_bits(mut self, ttbr: Ttbr, va_bits: u32) -> Self {
+        self.region(ttbr).va_bits = va_bits;
+        self
+    }
+
+    /// Sets the translation granule of a range (TG0 or TG1).
+    pub fn with_granule(mut self, ttbr: Ttbr, granule: Granule) -> Self {
+        self.region(ttbr).granule = granule;
+        self
+    }
+
+    /// Sets the inner and outer cacheability of table walks of a range (IRGNx and ORGNx).
+    pub fn with_cacheability(
+        mut self,
+        ttbr: Ttbr,
+        inner: Cacheability,
+        outer: Cacheability,
+    ) -> Self {
+        let region = self.region(ttbr);
+        region.inner = inner;
+        region.outer = outer;
+        self
+    }
+
+    /// Sets the shareability of table walks of a range (SH0 or SH1).
+    pub fn with_shareability(mut self, ttbr: Ttbr, shareability: Shareability) -> Self {
+        self.region(ttbr).shareability = shareability;
+        self
+    }
+
+    /// Disables table walks of a range (EPD0 or EPD1), so that a TLB miss generates a
+    /// translation fault.
+    pub fn with_walks_disabled(mut self, ttbr: Ttbr, disabled: bool) -> Self {
+        self.region(ttbr).walks_disabled = disabled;
+        self
+    }
+
+    /// Makes the top byte of addresses of a range ignored by translation (TBI0 or TBI1).
+    pub fn with_top_byte_ignored(mut self, ttbr: Ttbr, ignored: bool) -> Self {
+        self.region(ttbr).top_byte_ignored = ignored;
+        self
+    }
+
+    /// Makes the accesses of a range with the match-all logical tag Unchecked (TCMA0 or TCMA1).
+    ///
+    /// The match-all tag is 0b0000 in the lower range, and 0b1111 in the upper one. This needs
+    /// the top byte to be ignored, and FEAT_MTE2.
+    pub fn with_match_all_unchecked(mut self, ttbr: Ttbr, unchecked: bool) -> Self {
+        self.region(ttbr).match_all_unchecked = unchecked;
+        self
+    }
+
+    /// Selects whether TTBR0_EL1 or TTBR1_EL1 defines the ASID (A1).
+    pub fn with_asid_from(mut self, ttbr: Ttbr) -> Self {
+        self.asid_from = ttbr;
+        self
+    }
+
+    /// Sets the ASID size (AS).
+    pub fn with_asid_bits(mut self, bits: AsidBits) -> Self {
+        self.asid_bits = bits;
+        self
+    }
+
+    /// Enables hardware update of the Access flag (HA).
+    pub fn with_hardware_access_flag(mut self, enabled: bool) -> Self {
+        self.hardware_access_flag = enabled;
+        self
+    }
+
+    /// Enables hardware management of dirty state (HD), which requires HA.
+    pub fn with_hardware_dirty_state(mut self, enabled: bool) -> Self {
+        self.hardware_dirty_state = enabled;
+        self
+    }
+
+    /// Sets the physical address size (IPS), typically to [`PaRange::current`].
+    ///
+    /// 52-bit output addresses need the 64KiB granule in both ranges, other granules are limited
+    /// to [`PaRange::Bits48`].
+    pub fn with_pa_range(mut self, pa_range: PaRange) -> Self {
+        self.pa_range = pa_range;
+        self
+    }
+
+    /// Validates the configuration and returns the resulting register value.
+    pub fn build(&self) -> Result<Tcr, TcrError> {
+        for &ttbr in &[Ttbr::Lower, Ttbr::Upper] {
+            let va_bits = match ttbr {
+                Ttbr::Lower => self.lower.va_bits,
+                Ttbr::Upper => self.upper.va_bits,
+            };
+            if !(Self::MIN_VA_BITS..=Self::MAX_VA_BITS).contains(&va_bits) {
+                return Err(TcrError::InvalidVaBits(ttbr));
+            }
+        }
+        if self.hardware_dirty_state && !self.hardware_access_flag {
+            return Err(TcrError::DirtyStateWithoutAccessFlag);
+        }
+
+        if self.pa_range == PaRange::Bits52
+            && (self.lower.granule != Granule::Size64KiB
+                || self.upper.granule != Granule::Size64KiB)
+        {
+            return Err(TcrError::PaRangeNeedsLargeGranule);
+        }
+
+        let lower = &self.lower;
+        let upper = &self.upper;
+        let value: FieldValue<u64, TCR_EL1::Register> = TCR_EL1::T0SZ
+            .val(64 - lower.va_bits as u64)
+            + TCR_EL1::EPD0.val(lower.walks_disabled as u64)
+            + TCR_EL1::IRGN0.val(lower.inner as u64)
+            + TCR_EL1::ORGN0.val(lower.outer as u64)
+            + TCR_EL1::SH0.val(shareability_bits(lower.shareability))
+            + match lower.granule {
+                Granule::Size4KiB => TCR_EL1::TG0::KiB_4,
+                Granule::Size16KiB => TCR_EL1::TG0::KiB_16,
+                Granule::Size64KiB => TCR_EL1::TG0::KiB_64,
+            }
+            + TCR_EL1::T1SZ.val(64 - upper.va_bits as u64)
+            + TCR_EL1::EPD1.val(upper.walks_disabled as u64)
+            + TCR_EL1::IRGN1.val(upper.inner as u64)
+            + TCR_EL1::ORGN1.val(upper.outer as u64)
+            + TCR_EL1::SH1.val(shareability_bits(upper.shareability))
+            + match upper.granule {
+                Granule::Size4KiB => TCR_EL1::TG1::KiB_4,
+                Granule::Size16KiB => TCR_EL1::TG1::KiB_16,
+                Granule::Size64KiB => TCR_EL1::TG1::KiB_64,
+            }
+            + TCR_EL1::A1.val((self.asid_from == Ttbr::Upper) as u64)
+            + TCR_EL1::IPS.val(self.pa_range as u64)
+            + TCR_EL1::AS.val((self.asid_bits == AsidBits::Bits16) as u64)
+            + TCR_EL1::TBI0.val(lower.top_byte_ignored as u64)
+            + TCR_EL1::TBI1.val(upper.top_byte_ignored as u64)
+            + TCR_EL1::TCMA0.val(lower.match_all_unchecked as u64)
+            + TCR_EL1::TCMA1.val(upper.match_all_unchecked as u64)
+            + TCR_EL1::HA.val(self.hardware_access_flag as u64)
+            + TCR_EL1::HD.val(self.hardware_dirty_state as u64);
+
+        Ok(Tcr(LocalRegisterCopy::new(value.value)))
+    }
+}
+
+impl Default for TcrConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[inline]
+fn shareability_bits(shareability: Shareability) -> u64 {
+    match shareability {
+        Shareability::NonShareable => 0b00,
+        Shareability::OuterShareable => 0b10,
+        Shareability::InnerShareable => 0b11,
+    }
+}
+
+/// Support for hardware management of the Access flag and dirty state (FEAT_HAFDBS).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Hafdbs {
+    /// The Access flag and dirty state are managed by software.
+    Unsupported,
+    /// The Access flag can be set by hardware (TCR_EL1.HA).
+    AccessFlag,
+    /// The Access flag and dirty state can be updated by hardware (TCR_EL1.HA and TCR_EL1.HD).
+    AccessFlagAndDirty,
+}
+
+impl Hafdbs {
+    /// Reads the support of the current PE from ID_AA64MMFR1_EL1.
+    #[inline]
+    pub fn current() -> Self {
+        // The field is read as a raw value, the `AccessOnly` value of cortex-a is misspelt.
+        match ID_AA64MMFR1_EL1.read(ID_AA64MMFR1_EL1::HAFDBS) {
+            0 => Hafdbs::Unsupported,
+            1 => Hafdbs::AccessFlag,
+            _ => Hafdbs::AccessFlagAndDirty,
+        }
+    }
+}
+
+/// Enables hardware management of the Access flag and dirty state on the current PE, up to
+/// `max` and as far as the PE supports it, and returns what was enabled.
+///
+/// When this returns `Hafdbs::Unsupported`, access flag faults must be resolved by software,
+/// e.g. with [`MapperAllSizes::resolve_access_flag_fault`](super::MapperAllSizes::resolve_access_flag_fault).
+///
+/// ## Safety
+///
+/// This function is unsafe because it changes TCR_EL1. Once HD is enabled, writable entries
+/// with `DBM` set and `AP_RO` set are made writable by hardware, so software must not rely on
+/// them to be read-only.
+pub unsafe fn enable_hafdbs(max: Hafdbs) -> Hafdbs {
+    let enabled = max.min(Hafdbs::current());
+    TCR_EL1.modify(
+        TCR_EL1::HA.val((enabled >= Hafdbs::AccessFlag) as u64)
+            + TCR_EL1::HD.val((enabled == Hafdbs::AccessFlagAndDirty) as u64),
+    );
+    barrier::isb(barrier::SY);
+    // TLB entries may cache the old TCR_EL1.HA/HD.
+    #[cfg(target_arch = "aarch64")]
+    crate::translation::local_invalidate_tlb_all();
+    enabled
+}
+
+/// A value of TCR_EL1.
+#[derive(Clone, Copy)]
+pub struct Tcr(LocalRegisterCopy<u64, TCR_EL1::Register>);
+
+impl Tcr {
+    /// Reads the value of TCR_EL1 of the current PE.
+    #[inline]
+    pub fn current() -> Self {
+        Tcr(LocalRegisterCopy::new(TCR_EL1.get()))
+    }
+
+    /// Returns the raw value.
+    #[inline]
+    pub fn value(&self) -> u64 {
+        self.0.get()
+    }
+
+    /// Returns the number of VA bits translated in the given range.
+    #[inline]
+    pub fn va_bits(&self, ttbr: Ttbr) -> u32 {
+        let txsz = match ttbr {
+            Ttbr::Lower => self.0.read(TCR_EL1::T0SZ),
+            Ttbr::Upper => self.0.read(TCR_EL1::T1SZ),
+        };
+        64 - txsz as u32
+    }
+
+    /// Returns the translation granule of the given range.
+    #[inline]
+    pub fn granule(&self, ttbr: Ttbr) -> Granule {
+        match ttbr {
+            Ttbr::Lower => match self.0.read_as_enum(TCR_EL1::TG0) {
+                Some(TCR_EL1::TG0::Value::KiB_16) => Granule::Size16KiB,
+                Some(TCR_EL1::TG0::Value::KiB_64) => Granule::Size64KiB,
+                _ => Granule::Size4KiB,
+            },
+            Ttbr::Upper => match self.0.read_as_enum(TCR_EL1::TG1) {
+                Some(TCR_EL1::TG1::Value::KiB_16) => Granule::Size16KiB,
+                Some(TCR_EL1::TG1::Value::KiB_64) => Granule::Size64KiB,
+                _ => Granule::Size4KiB,
+            },
+        }
+    }
+
+    /// Returns the physical address size.
+    #[inline]
+    pub fn pa_range(&self) -> PaRange {
+        PaRange::from_bits(self.0.read(TCR_EL1::IPS))
+    }
+
+    /// Returns the number of physical address bits.
+    #[inline]
+    pub fn pa_bits(&self) -> u32 {
+        self.pa_range().bits()
+    }
+
+    /// Returns which of the Access flag and dirty state are managed by hardware.
+    #[inline]
+    pub fn hafdbs(&self) -> Hafdbs {
+        match (self.0.is_set(TCR_EL1::HA), self.0.is_set(TCR_EL1::HD)) {
+            (true, true) => Hafdbs::AccessFlagAndDirty,
+            (true, false) => Hafdbs::AccessFlag,
+            _ => Hafdbs::Unsupported,
+        }
+    }
+
+    /// Returns whether the given range can be edited with the mappers of the `paging` module,
+    /// which walk 4 levels of 4KiB tables.
+    #[inline]
+    pub fn is_paging_compatible(&self, ttbr: Ttbr) -> bool {
+        self.granule(ttbr) == Granule::Size4KiB && self.va_bits(ttbr) == VA_BITS
+    }
+
+    /// Writes this value to TCR_EL1 of the current PE.
+    ///
+    /// ## Safety
+    ///
+    /// This function is unsafe because it changes how every address is translated. TLB
+    /// entries that depend on the old configuration must be invalidated by the caller.
+    #[inline]
+    pub unsafe fn write(&self) {
+        TCR_EL1.set(self.value());
+        barrier::isb(barrier::SY);
+    }
+}
+
+impl core::fmt::Debug for Tcr {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("Tcr")
+            .field("value", &self.value())
+            .field("lower_va_bits", &self.va_bits(Ttbr::Lower))
+            .field("upper_va_bits", &self.va_bits(Ttbr::Upper))
+            .field("pa_bits", &self.pa_bits())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_tcr_default() {
+        let tcr = TcrConfig::new().build().unwrap();
+        // T0SZ = T1SZ = 16, 4KiB granules, IPS = 48 bits, Inner Shareable Write-Back walks.
+        assert_eq!(tcr.value(), 0x0000_0005_b510_3510);
+        assert_eq!(tcr.va_bits(Ttbr::Lower), 48);
+        assert_eq!(tcr.va_bits(Ttbr::Upper), 48);
+        assert_eq!(tcr.pa_bits(), 48);
+        assert!(tcr.is_paging_compatible(Ttbr::Upper));
+    }
+
+    #[test]
+    pub fn test_tcr_validation() {
+        let config = TcrConfig::new().with_va_bits(Ttbr::Upper, 52);
+        assert_eq!(
+            config.build().unwrap_err(),
+            TcrError::InvalidVaBits(Ttbr::Upper)
+        );
+
+        let config = TcrConfig::new().with_hardware_dirty_state(true);
+        assert_eq!(
+            config.build().unwrap_err(),
+            TcrError::DirtyStateWithoutAccessFlag
+        );
+
+        let config = TcrConfig::new().with_pa_range(PaRange::Bits52);
+        assert_eq!(
+            config.build().unwrap_err(),
+            TcrError::PaRangeNeedsLargeGranule
+        );
+
+        let tcr = TcrConfig::new()
+            .with_va_bits(Ttbr::Lower, 39)
+            .with_pa_range(PaRange::Bits48)
+            .build()
+            .unwrap();
+        assert_eq!(tcr.va_bits(Ttbr::Lower), 39);
+        assert_eq!(tcr.pa_bits(), 48);
+        assert!(!tcr.is_paging_compatible(Ttbr::Lower));
+    }
+}
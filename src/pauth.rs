@@ -0,0 +1,220 @@
+//! Pointer authentication (FEAT_PAuth).
+//!
+//! Each user context has its own keys, see [`UserContext::pauth`], switched by
+//! [`UserContext::run`]: programs built with `-mbranch-protection=pac-ret` sign their return
+//! addresses with them. The kernel may use its own keys, see [`set_kernel_keys`], which are
+//! loaded back on every trap from EL0.
+//!
+//! Once pointer authentication is in use, i.e. after [`enable`] or [`set_kernel_keys`], the
+//! keys of EL0 are loaded on every run: a context without keys runs with zero keys, rather than
+//! with the keys of the kernel or of the previous program, which it could use to sign pointers.
+//!
+//! [`UserContext::pauth`]: crate::trap::UserContext::pauth
+//! [`UserContext::run`]: crate::trap::UserContext::run
+
+use crate::registers::{ReadWriteable, SCTLR_EL1};
+use bit_field::BitField;
+#[allow(unused_imports)]
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// The pointer authentication keys, each one being `Key<Hi>:Key<Lo>`.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+#[repr(C)]
+pub struct PauthKeys {
+    /// Instruction key A, APIAKey_EL1, used by `pac-ret`
+    pub apia: u128,
+    /// Instruction key B, APIBKey_EL1
+    pub apib: u128,
+    /// Data key A, APDAKey_EL1
+    pub apda: u128,
+    /// Data key B, APDBKey_EL1
+    pub apdb: u128,
+    /// Generic key, APGAKey_EL1
+    pub apga: u128,
+}
+
+/// A key used to authenticate a pointer.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PauthKey {
+    IA,
+    IB,
+    DA,
+    DB,
+}
+
+impl PauthKey {
+    /// Decodes the key from the ISS of an FPAC exception.
+    pub fn from_fpac_iss(iss: u32) -> Self {
+        match (iss.get_bit(1), iss.get_bit(0)) {
+            (false, false) => PauthKey::IA,
+            (false, true) => PauthKey::IB,
+            (true, false) => PauthKey::DA,
+            (true, true) => PauthKey::DB,
+        }
+    }
+}
+
+/// Returns ID_AA64ISAR1_EL1 and ID_AA64ISAR2_EL1.
+fn isar() -> (u64, u64) {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let (isar1, isar2): (u64, u64);
+        unsafe {
+            asm!("mrs {}, id_aa64isar1_el1", out(reg) isar1);
+            // ID_AA64ISAR2_EL1, RAZ before Armv8.7
+            asm!("mrs {}, S3_0_C0_C6_2", out(reg) isar2);
+        }
+        (isar1, isar2)
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    unimplemented!()
+}
+
+/// Returns whether address authentication is implemented, with the APIA, APIB, APDA and APDB
+/// keys.
+pub fn has_address_auth() -> bool {
+    let (isar1, isar2) = isar();
+    // APA, API and APA3
+    isar1.get_bits(4..8) != 0 || isar1.get_bits(8..12) != 0 || isar2.get_bits(12..16) != 0
+}
+
+/// Returns whether generic authentication is implemented, with the APGA key.
+pub fn has_generic_auth() -> bool {
+    let (isar1, isar2) = isar();
+    // GPA, GPI and GPA3
+    isar1.get_bits(24..28) != 0 || isar1.get_bits(28..32) != 0 || isar2.get_bits(8..12) != 0
+}
+
+/// Returns whether authentication failures trap (FEAT_FPAC), rather than corrupting the pointer.
+pub fn has_fpac() -> bool {
+    let (isar1, isar2) = isar();
+    [
+        isar1.get_bits(4..8),
+        isar1.get_bits(8..12),
+        isar2.get_bits(12..16),
+    ]
+    .iter()
+    .any(|&field| field >= 0b0100)
+}
+
+/// Enables address authentication with the four keys, at EL0 and EL1.
+///
+/// # Safety
+///
+/// Address authentication must be implemented. Once enabled, the EL1 code built with
+/// `pac-ret` must run with the same keys from the signing to the authentication of its return
+/// addresses: set kernel keys with [`set_kernel_keys`].
+pub unsafe fn enable() {
+    IN_USE.store(true, Ordering::Release);
+    SCTLR_EL1.modify(
+        SCTLR_EL1::EnIA::Enable
+            + SCTLR_EL1::EnIB::Enable
+            + SCTLR_EL1::EnDA::Enable
+            + SCTLR_EL1::EnDB::Enable,
+    );
+    #[cfg(target_arch = "aarch64")]
+    crate::barrier::isb(crate::barrier::SY);
+}
+
+/// Whether the keys are switched, i.e. pointer authentication is implemented and in use.
+static IN_USE: AtomicBool = AtomicBool::new(false);
+
+/// The keys of the user contexts without their own.
+static ZERO_KEYS: PauthKeys = PauthKeys {
+    apia: 0,
+    apib: 0,
+    apda: 0,
+    apdb: 0,
+    apga: 0,
+};
+
+/// Returns the keys loaded to run a user context with `keys`: the zero keys if it has none, or
+/// none while pointer authentication is not in use.
+pub(crate) fn user_keys(keys: Option<&PauthKeys>) -> Option<&PauthKeys> {
+    if IN_USE.load(Ordering::Acquire) {
+        Some(keys.unwrap_or(&ZERO_KEYS))
+    } else {
+        keys
+    }
+}
+
+/// Kernel keys, shared with trap.S.
+#[repr(C)]
+struct KernelKeys {
+    /// Whether the keys are loaded on traps from EL0
+    enabled: usize,
+    _reserved: usize,
+    keys: PauthKeys,
+}
+
+#[export_name = "__pauth_kernel_keys"]
+static mut KERNEL_KEYS: KernelKeys = KernelKeys {
+    enabled: 0,
+    _reserved: 0,
+    keys: PauthKeys {
+        apia: 0,
+        apib: 0,
+        apda: 0,
+        apdb: 0,
+        apga: 0,
+    },
+};
+
+/// Sets the keys of the kernel, and loads them.
+///
+/// They are loaded back on every trap from EL0, before returning to the caller of
+/// [`UserContext::run`](crate::trap::UserContext::run).
+///
+/// # Safety
+///
+/// Pointer authentication must be implemented. This must be called before any user program is
+/// run, and not from a function whose return address is signed.
+pub unsafe fn set_kernel_keys(keys: &PauthKeys) {
+    KERNEL_KEYS.keys = *keys;
+    KERNEL_KEYS.enabled = 1;
+    IN_USE.store(true, Ordering::Release);
+    pauth_load_keys(keys);
+}
+
+extern "C" {
+    fn pauth_load_keys(keys: &PauthKeys);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        exception::ExceptionInfo,
+        trap::{TrapKind, TrapReason},
+    };
+    use core::{mem::size_of, ptr::addr_of};
+
+    #[test]
+    pub fn test_pauth() {
+        assert_eq!(size_of::<PauthKeys>(), 80);
+        let offset =
+            unsafe { addr_of!(KERNEL_KEYS.keys) as usize - addr_of!(KERNEL_KEYS) as usize };
+        assert_eq!(offset, 16);
+
+        // AUTDA failure
+        let info = ExceptionInfo::new(0x7200_0002, 0);
+        assert_eq!(
+            TrapReason::new(TrapKind::Synchronous, info),
+            TrapReason::PointerAuth(PauthKey::DA)
+        );
+
+        // Contexts without keys run with zero keys once the keys are switched
+        let keys = PauthKeys {
+            apia: 1,
+            ..Default::default()
+        };
+        assert_eq!(user_keys(None), None);
+        assert_eq!(user_keys(Some(&keys)), Some(&keys));
+        IN_USE.store(true, Ordering::Release);
+        assert_eq!(user_keys(None), Some(&PauthKeys::default()));
+        assert_eq!(user_keys(Some(&keys)), Some(&keys));
+        IN_USE.store(false, Ordering::Release);
+    }
+}
@@ -0,0 +1,60 @@
+//! Architectural Feature Access Control Register - EL1
+//!
+//! Controls access to trace, SME, SVE, and Advanced SIMD and floating-point functionality.
+
+use tock_registers::{
+    interfaces::{Readable, Writeable},
+    register_bitfields,
+};
+
+register_bitfields! {u64,
+    pub CPACR_EL1 [
+        /// Traps EL0 and EL1 System register accesses to all implemented trace registers.
+        TTA OFFSET(28) NUMBITS(1) [],
+
+        /// Traps execution at EL1 and EL0 of SME instructions, SVE instructions when
+        /// FEAT_SVE is not implemented or the PE is in Streaming SVE mode, and instructions
+        /// that directly access the SVCR or SMCR_EL1 System registers.
+        SMEN OFFSET(24) NUMBITS(2) [
+            TrapEl0El1 = 0b00,
+            TrapEl0 = 0b01,
+            TrapEl1El0 = 0b10,
+            TrapNothing = 0b11
+        ],
+
+        /// Traps execution at EL1 and EL0 of Advanced SIMD and floating-point instructions.
+        FPEN OFFSET(20) NUMBITS(2) [
+            TrapEl0El1 = 0b00,
+            TrapEl0 = 0b01,
+            TrapEl1El0 = 0b10,
+            TrapNothing = 0b11
+        ],
+
+        /// Traps execution at EL1 and EL0 of SVE instructions, and instructions that directly
+        /// access the ZCR_EL1 System register.
+        ZEN OFFSET(16) NUMBITS(2) [
+            TrapEl0El1 = 0b00,
+            TrapEl0 = 0b01,
+            TrapEl1El0 = 0b10,
+            TrapNothing = 0b11
+        ]
+    ]
+}
+
+pub struct Reg;
+
+impl Readable for Reg {
+    type T = u64;
+    type R = CPACR_EL1::Register;
+
+    sys_coproc_read_raw!(u64, "CPACR_EL1", "x");
+}
+
+impl Writeable for Reg {
+    type T = u64;
+    type R = CPACR_EL1::Register;
+
+    sys_coproc_write_raw!(u64, "CPACR_EL1", "x");
+}
+
+pub static CPACR_EL1: Reg = Reg {};
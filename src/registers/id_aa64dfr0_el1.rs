@@ -0,0 +1,39 @@
+//! AArch64 Debug Feature Register 0 - EL1
+//!
+//! Provides top level information about the debug system in AArch64 state.
+
+use tock_registers::{interfaces::Readable, register_bitfields};
+
+register_bitfields! {u64,
+    pub ID_AA64DFR0_EL1 [
+        /// Number of breakpoints that are context-aware, minus 1.
+        CTX_CMPs OFFSET(28) NUMBITS(4) [],
+
+        /// Number of watchpoints, minus 1.
+        WRPs OFFSET(20) NUMBITS(4) [],
+
+        /// Number of breakpoints, minus 1.
+        BRPs OFFSET(12) NUMBITS(4) [],
+
+        /// Debug architecture version.
+        DebugVer OFFSET(0) NUMBITS(4) [
+            Armv8 = 0b0110,
+            Armv8_VHE = 0b0111,
+            Armv8p2 = 0b1000,
+            Armv8p4 = 0b1001,
+            Armv8p8 = 0b1010,
+            Armv8p9 = 0b1011
+        ]
+    ]
+}
+
+pub struct Reg;
+
+impl Readable for Reg {
+    type T = u64;
+    type R = ID_AA64DFR0_EL1::Register;
+
+    sys_coproc_read_raw!(u64, "ID_AA64DFR0_EL1", "x");
+}
+
+pub static ID_AA64DFR0_EL1: Reg = Reg {};
@@ -0,0 +1,52 @@
+//! Monitor Debug System Control Register - EL1
+//!
+//! Main control register for the debug implementation.
+
+use tock_registers::{
+    interfaces::{Readable, Writeable},
+    register_bitfields,
+};
+
+register_bitfields! {u64,
+    pub MDSCR_EL1 [
+        /// Monitor debug events. Enables Breakpoint, Watchpoint, and Vector Catch exceptions.
+        MDE OFFSET(15) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ],
+
+        /// Local (kernel) debug enable. Enables debug exceptions from EL1, when PSTATE.D is
+        /// clear.
+        KDE OFFSET(13) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ],
+
+        /// Traps EL0 accesses to the Debug Communication Channel registers to EL1.
+        TDCC OFFSET(12) NUMBITS(1) [],
+
+        /// Software step control. Enables Software Step exceptions.
+        SS OFFSET(0) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ]
+    ]
+}
+
+pub struct Reg;
+
+impl Readable for Reg {
+    type T = u64;
+    type R = MDSCR_EL1::Register;
+
+    sys_coproc_read_raw!(u64, "MDSCR_EL1", "x");
+}
+
+impl Writeable for Reg {
+    type T = u64;
+    type R = MDSCR_EL1::Register;
+
+    sys_coproc_write_raw!(u64, "MDSCR_EL1", "x");
+}
+
+pub static MDSCR_EL1: Reg = Reg {};
@@ -1,6 +1,7 @@
 #[macro_use]
 mod macros;
 
+mod cpacr_el1;
 mod ctr_el0;
 mod icc_ctlr_el1;
 mod icc_eoir1_el1;
@@ -8,11 +9,16 @@ mod icc_iar1_el1;
 mod icc_igrpen1_el1;
 mod icc_pmr_el1;
 mod icc_sre_el1;
+mod id_aa64dfr0_el1;
+mod mdscr_el1;
+mod par_el1;
+mod sctlr_el1;
+mod tcr_el1;
 mod ttbr0_el1;
 mod ttbr1_el1;
 
-pub use tock_registers::interfaces::{Readable, Writeable, ReadWriteable};
 pub use cortex_a::registers::*;
+pub use cpacr_el1::CPACR_EL1;
 pub use ctr_el0::CTR_EL0;
 pub use icc_ctlr_el1::ICC_CTLR_EL1;
 pub use icc_eoir1_el1::ICC_EOIR1_EL1;
@@ -20,5 +26,11 @@ pub use icc_iar1_el1::ICC_IAR1_EL1;
 pub use icc_igrpen1_el1::ICC_IGRPEN1_EL1;
 pub use icc_pmr_el1::ICC_PMR_EL1;
 pub use icc_sre_el1::ICC_SRE_EL1;
+pub use id_aa64dfr0_el1::ID_AA64DFR0_EL1;
+pub use mdscr_el1::MDSCR_EL1;
+pub use par_el1::PAR_EL1;
+pub use sctlr_el1::SCTLR_EL1;
+pub use tcr_el1::TCR_EL1;
+pub use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 pub use ttbr0_el1::TTBR0_EL1;
 pub use ttbr1_el1::TTBR1_EL1;
@@ -0,0 +1,72 @@
+//! Physical Address Register - EL1
+//!
+//! Returns the output address (OA) from an Address translation instruction that
+//! executed successfully, or fault information if the instruction did not
+//! execute successfully.
+
+use tock_registers::{
+    interfaces::{Readable, Writeable},
+    register_bitfields,
+};
+
+register_bitfields! {u64,
+    pub PAR_EL1 [
+        /// Memory attributes for the returned output address, in the MAIR_EL1
+        /// encoding. Valid when F is 0.
+        ATTR OFFSET(56) NUMBITS(8) [],
+
+        /// Output address, bits [51:12]. Valid when F is 0.
+        PA   OFFSET(12) NUMBITS(40) [],
+
+        /// Non-secure. Valid when F is 0.
+        NS   OFFSET(9)  NUMBITS(1) [],
+
+        /// Shareability attribute for the returned output address. Valid when
+        /// F is 0.
+        SH   OFFSET(7)  NUMBITS(2) [
+            NonShareable = 0b00,
+            OuterShareable = 0b10,
+            InnerShareable = 0b11
+        ],
+
+        /// Indicates the translation stage at which the translation aborted.
+        /// Valid when F is 1.
+        S    OFFSET(9)  NUMBITS(1) [
+            Stage1 = 0,
+            Stage2 = 1
+        ],
+
+        /// Indicates whether the abort was a stage 2 fault on a stage 1
+        /// translation table walk. Valid when F is 1.
+        PTW  OFFSET(8)  NUMBITS(1) [],
+
+        /// Fault status code, as in the DFSC field of ESR_ELx. Valid when F is
+        /// 1.
+        FST  OFFSET(1)  NUMBITS(6) [],
+
+        /// Indicates whether the instruction performed a successful address
+        /// translation.
+        F    OFFSET(0)  NUMBITS(1) [
+            TranslationSuccessfull = 0,
+            TranslationAborted = 1
+        ]
+    ]
+}
+
+pub struct Reg;
+
+impl Readable for Reg {
+    type T = u64;
+    type R = PAR_EL1::Register;
+
+    sys_coproc_read_raw!(u64, "PAR_EL1", "x");
+}
+
+impl Writeable for Reg {
+    type T = u64;
+    type R = PAR_EL1::Register;
+
+    sys_coproc_write_raw!(u64, "PAR_EL1", "x");
+}
+
+pub static PAR_EL1: Reg = Reg {};
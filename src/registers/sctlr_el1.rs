@@ -0,0 +1,198 @@
+//! System Control Register - EL1
+//!
+//! Provides top level control of the system, including its memory system, at EL1 and EL0.
+
+use tock_registers::{
+    interfaces::{Readable, Writeable},
+    register_bitfields,
+};
+
+register_bitfields! {u64,
+    pub SCTLR_EL1 [
+        /// Allocation Tag Access in EL1, allowing the tag instructions (FEAT_MTE2).
+        ATA OFFSET(43) NUMBITS(1) [
+            Disable = 0,
+            Enable = 1
+        ],
+
+        /// Allocation Tag Access in EL0, allowing the tag instructions (FEAT_MTE2).
+        ATA0 OFFSET(42) NUMBITS(1) [
+            Disable = 0,
+            Enable = 1
+        ],
+
+        /// Tag Check Fault in EL1.
+        TCF OFFSET(40) NUMBITS(2) [
+            Ignore = 0b00,
+            Sync = 0b01,
+            Async = 0b10,
+            Asymmetric = 0b11
+        ],
+
+        /// Tag Check Fault in EL0.
+        TCF0 OFFSET(38) NUMBITS(2) [
+            Ignore = 0b00,
+            Sync = 0b01,
+            Async = 0b10,
+            Asymmetric = 0b11
+        ],
+
+        /// Makes the exception entries synchronize TFSR_EL1 and TFSRE0_EL1 with the
+        /// asynchronous Tag Check Faults.
+        ITFSB OFFSET(37) NUMBITS(1) [],
+
+        /// Makes PACIASP and PACIBSP incompatible with PSTATE.BTYPE 0b11 at EL1, as BTI c
+        /// (FEAT_BTI).
+        BT1 OFFSET(36) NUMBITS(1) [],
+
+        /// Makes PACIASP and PACIBSP incompatible with PSTATE.BTYPE 0b11 at EL0, as BTI c
+        /// (FEAT_BTI).
+        BT0 OFFSET(35) NUMBITS(1) [],
+
+        /// Enables pointer authentication of instruction addresses with the APIAKey_EL1 key,
+        /// at EL0 and EL1.
+        EnIA OFFSET(31) NUMBITS(1) [
+            Disable = 0,
+            Enable = 1
+        ],
+
+        /// Enables pointer authentication of instruction addresses with the APIBKey_EL1 key,
+        /// at EL0 and EL1.
+        EnIB OFFSET(30) NUMBITS(1) [
+            Disable = 0,
+            Enable = 1
+        ],
+
+        /// Enables pointer authentication of data addresses with the APDAKey_EL1 key, at EL0 and
+        /// EL1.
+        EnDA OFFSET(27) NUMBITS(1) [
+            Disable = 0,
+            Enable = 1
+        ],
+
+        /// Traps EL0 execution of cache maintenance instructions to EL1, from AArch64 state only.
+        UCI OFFSET(26) NUMBITS(1) [
+            Trap = 0,
+            DontTrap = 1,
+        ],
+
+        /// Endianness of data accesses at EL1, and stage 1 translation table walks in the EL1&0
+        /// translation regime.
+        EE OFFSET(25) NUMBITS(1) [
+            LittleEndian = 0,
+            BigEndian = 1,
+        ],
+
+        /// Endianness of data accesses at EL0.
+        E0E OFFSET(24) NUMBITS(1) [
+            LittleEndian = 0,
+            BigEndian = 1,
+        ],
+
+        /// Write permission implies XN (Execute-never).
+        WXN OFFSET(19) NUMBITS(1) [
+            Disable = 0,
+            Enable = 1,
+        ],
+
+        /// Traps EL0 execution of WFE instructions to EL1, from both Execution states.
+        NTWE OFFSET(18) NUMBITS(1) [
+            Trap = 0,
+            DontTrap = 1,
+        ],
+
+        /// Traps EL0 executions of WFI instructions to EL1, from both execution states.
+        NTWI OFFSET(16) NUMBITS(1) [
+            Trap = 0,
+            DontTrap = 1,
+        ],
+
+        /// Traps EL0 accesses to the CTR_EL0 to EL1, from AArch64 state only.
+        UCT OFFSET(15) NUMBITS(1) [
+            Trap = 0,
+            DontTrap = 1,
+        ],
+
+        /// Traps EL0 execution of DC ZVA instructions to EL1, from AArch64 state only.
+        DZE OFFSET(14) NUMBITS(1) [
+            Trap = 0,
+            DontTrap = 1,
+        ],
+
+        /// Enables pointer authentication of data addresses with the APDBKey_EL1 key, at EL0 and
+        /// EL1.
+        EnDB OFFSET(13) NUMBITS(1) [
+            Disable = 0,
+            Enable = 1
+        ],
+
+        /// Instruction access Cacheability control, for accesses at EL0 and EL1.
+        I OFFSET(12) NUMBITS(1) [
+            NonCacheable = 0,
+            Cacheable = 1
+        ],
+
+        /// User Mask Access. Traps EL0 execution of MSR and MRS instructions that access the
+        /// PSTATE.{D, A, I, F} masks to EL1, from AArch64 state only.
+        UMA OFFSET(9) NUMBITS(1) [
+            Trap = 0,
+            DontTrap = 1,
+        ],
+
+        /// Non-aligned access. Controls generation of Alignment faults at EL1 and EL0 for the
+        /// load-acquire/store-release instructions.
+        NAA OFFSET(6) NUMBITS(1) [
+            Disable = 0,
+            Enable = 1
+        ],
+
+        /// SP Alignment check enable for EL0.
+        SA0 OFFSET(4) NUMBITS(1) [
+            Disable = 0,
+            Enable = 1
+        ],
+
+        /// SP Alignment check enable.
+        SA OFFSET(3) NUMBITS(1) [
+            Disable = 0,
+            Enable = 1
+        ],
+
+        /// Cacheability control, for data accesses.
+        C OFFSET(2) NUMBITS(1) [
+            NonCacheable = 0,
+            Cacheable = 1
+        ],
+
+        /// Alignment check enable. This is the enable bit for Alignment fault checking at EL1 and
+        /// EL0.
+        A OFFSET(1) NUMBITS(1) [
+            Disable = 0,
+            Enable = 1
+        ],
+
+        /// MMU enable for EL1 and EL0 stage 1 address translation.
+        M OFFSET(0) NUMBITS(1) [
+            Disable = 0,
+            Enable = 1
+        ]
+    ]
+}
+
+pub struct Reg;
+
+impl Readable for Reg {
+    type T = u64;
+    type R = SCTLR_EL1::Register;
+
+    sys_coproc_read_raw!(u64, "SCTLR_EL1", "x");
+}
+
+impl Writeable for Reg {
+    type T = u64;
+    type R = SCTLR_EL1::Register;
+
+    sys_coproc_write_raw!(u64, "SCTLR_EL1", "x");
+}
+
+pub static SCTLR_EL1: Reg = Reg {};
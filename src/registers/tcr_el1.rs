@@ -0,0 +1,175 @@
+//! Translation Control Register - EL1
+//!
+//! The control register for stage 1 of the EL1&0 translation regime.
+
+use tock_registers::{
+    interfaces::{Readable, Writeable},
+    register_bitfields,
+};
+
+register_bitfields! {u64,
+    pub TCR_EL1 [
+        /// Makes accesses with the logical tag 0b1111 in the TTBR1_EL1 range Unchecked
+        /// (FEAT_MTE2).
+        TCMA1 OFFSET(58) NUMBITS(1) [],
+
+        /// Makes accesses with the logical tag 0b0000 in the TTBR0_EL1 range Unchecked
+        /// (FEAT_MTE2).
+        TCMA0 OFFSET(57) NUMBITS(1) [],
+
+        /// Makes TBI1 apply to data accesses only.
+        TBID1 OFFSET(52) NUMBITS(1) [],
+
+        /// Makes TBI0 apply to data accesses only.
+        TBID0 OFFSET(51) NUMBITS(1) [],
+
+        /// Hardware management of dirty state in stage 1 translations.
+        HD OFFSET(40) NUMBITS(1) [
+            Disable = 0,
+            Enable = 1,
+        ],
+
+        /// Hardware Access flag update in stage 1 translations.
+        HA OFFSET(39) NUMBITS(1) [
+            Disable = 0,
+            Enable = 1,
+        ],
+
+        /// Top Byte ignored in the TTBR1_EL1 range.
+        TBI1 OFFSET(38) NUMBITS(1) [
+            Used = 0,
+            Ignored = 1
+        ],
+
+        /// Top Byte ignored in the TTBR0_EL1 range.
+        TBI0 OFFSET(37) NUMBITS(1) [
+            Used = 0,
+            Ignored = 1
+        ],
+
+        /// ASID Size.
+        AS OFFSET(36) NUMBITS(1) [
+            ASID8Bits = 0,
+            ASID16Bits = 1
+        ],
+
+        /// Intermediate Physical Address Size.
+        IPS OFFSET(32) NUMBITS(3) [
+            Bits_32 = 0b000,
+            Bits_36 = 0b001,
+            Bits_40 = 0b010,
+            Bits_42 = 0b011,
+            Bits_44 = 0b100,
+            Bits_48 = 0b101,
+            Bits_52 = 0b110
+        ],
+
+        /// Granule size for the TTBR1_EL1.
+        TG1 OFFSET(30) NUMBITS(2) [
+            KiB_4 = 0b10,
+            KiB_16 = 0b01,
+            KiB_64 = 0b11
+        ],
+
+        /// Shareability attribute for memory associated with translation table walks using
+        /// TTBR1_EL1.
+        SH1 OFFSET(28) NUMBITS(2) [
+            None = 0b00,
+            Outer = 0b10,
+            Inner = 0b11
+        ],
+
+        /// Outer cacheability attribute for memory associated with translation table walks
+        /// using TTBR1_EL1.
+        ORGN1 OFFSET(26) NUMBITS(2) [
+            NonCacheable = 0b00,
+            WriteBack_ReadAlloc_WriteAlloc_Cacheable = 0b01,
+            WriteThrough_ReadAlloc_NoWriteAlloc_Cacheable = 0b10,
+            WriteBack_ReadAlloc_NoWriteAlloc_Cacheable = 0b11
+        ],
+
+        /// Inner cacheability attribute for memory associated with translation table walks
+        /// using TTBR1_EL1.
+        IRGN1 OFFSET(24) NUMBITS(2) [
+            NonCacheable = 0b00,
+            WriteBack_ReadAlloc_WriteAlloc_Cacheable = 0b01,
+            WriteThrough_ReadAlloc_NoWriteAlloc_Cacheable = 0b10,
+            WriteBack_ReadAlloc_NoWriteAlloc_Cacheable = 0b11
+        ],
+
+        /// Translation table walk disable for translations using TTBR1_EL1.
+        EPD1 OFFSET(23) NUMBITS(1) [
+            EnableTTBR1Walks = 0,
+            DisableTTBR1Walks = 1
+        ],
+
+        /// Selects whether TTBR0_EL1 or TTBR1_EL1 defines the ASID.
+        A1 OFFSET(22) NUMBITS(1) [
+            TTBR0 = 0,
+            TTBR1 = 1
+        ],
+
+        /// The size offset of the memory region addressed by TTBR1_EL1.
+        T1SZ OFFSET(16) NUMBITS(6) [],
+
+        /// Granule size for the TTBR0_EL1.
+        TG0 OFFSET(14) NUMBITS(2) [
+            KiB_4 = 0b00,
+            KiB_16 = 0b10,
+            KiB_64 = 0b01
+        ],
+
+        /// Shareability attribute for memory associated with translation table walks using
+        /// TTBR0_EL1.
+        SH0 OFFSET(12) NUMBITS(2) [
+            None = 0b00,
+            Outer = 0b10,
+            Inner = 0b11
+        ],
+
+        /// Outer cacheability attribute for memory associated with translation table walks
+        /// using TTBR0_EL1.
+        ORGN0 OFFSET(10) NUMBITS(2) [
+            NonCacheable = 0b00,
+            WriteBack_ReadAlloc_WriteAlloc_Cacheable = 0b01,
+            WriteThrough_ReadAlloc_NoWriteAlloc_Cacheable = 0b10,
+            WriteBack_ReadAlloc_NoWriteAlloc_Cacheable = 0b11
+        ],
+
+        /// Inner cacheability attribute for memory associated with translation table walks
+        /// using TTBR0_EL1.
+        IRGN0 OFFSET(8) NUMBITS(2) [
+            NonCacheable = 0b00,
+            WriteBack_ReadAlloc_WriteAlloc_Cacheable = 0b01,
+            WriteThrough_ReadAlloc_NoWriteAlloc_Cacheable = 0b10,
+            WriteBack_ReadAlloc_NoWriteAlloc_Cacheable = 0b11
+        ],
+
+        /// Translation table walk disable for translations using TTBR0_EL1.
+        EPD0 OFFSET(7) NUMBITS(1) [
+            EnableTTBR0Walks = 0,
+            DisableTTBR0Walks = 1
+        ],
+
+        /// The size offset of the memory region addressed by TTBR0_EL1.
+        T0SZ OFFSET(0) NUMBITS(6) []
+    ]
+}
+
+pub struct Reg;
+
+impl Readable for Reg {
+    type T = u64;
+    type R = TCR_EL1::Register;
+
+    sys_coproc_read_raw!(u64, "TCR_EL1", "x");
+}
+
+impl Writeable for Reg {
+    type T = u64;
+    type R = TCR_EL1::Register;
+
+    sys_coproc_write_raw!(u64, "TCR_EL1", "x");
+}
+
+pub static TCR_EL1: Reg = Reg {};
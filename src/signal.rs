@@ -0,0 +1,468 @@
+//! Linux compatible signal frames.
+//!
+//! [`setup_rt_frame`] builds the frame a Linux AArch64 program expects on its stack when a signal
+//! handler is called, and [`sigreturn`] restores the context from it when the handler returns
+//! through `rt_sigreturn`.
+//!
+//! The frames are built in kernel memory: copying them to and from the user stack, and checking
+//! that the user is allowed to access it, is up to the caller.
+
+use crate::trap::{FpRegs, UserContext};
+use core::mem::size_of;
+
+/// `sa_flags`: the handler takes a [`SigInfo`] and a [`UContext`].
+pub const SA_SIGINFO: u64 = 0x0000_0004;
+/// `sa_flags`: `sa_restorer` is valid.
+pub const SA_RESTORER: u64 = 0x0400_0000;
+/// `sa_flags`: the handler runs on the alternate signal stack.
+pub const SA_ONSTACK: u64 = 0x0800_0000;
+/// `sa_flags`: syscalls interrupted by the signal are restarted.
+pub const SA_RESTART: u64 = 0x1000_0000;
+
+/// `ss_flags`: the alternate signal stack is in use.
+pub const SS_ONSTACK: i32 = 1;
+/// `ss_flags`: the alternate signal stack is disabled.
+pub const SS_DISABLE: i32 = 2;
+
+/// Magic of the [`FpsimdContext`] record.
+pub const FPSIMD_MAGIC: u32 = 0x4650_8001;
+/// Magic of the `esr_context` record.
+pub const ESR_MAGIC: u32 = 0x4553_5201;
+
+/// PSTATE bits a program may set through `sigreturn`: NZCV, TCO, DIT, SS, SSBS and BTYPE. M[4:0]
+/// and DAIF must be clear.
+const PSTATE_USER_MASK: u64 = 0xf000_0000 | 1 << 25 | 1 << 24 | 1 << 21 | 1 << 12 | 0b11 << 10;
+
+/// Kernel `struct sigaction`.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+#[repr(C)]
+pub struct SigAction {
+    pub handler: usize,
+    pub flags: u64,
+    pub restorer: usize,
+    pub mask: u64,
+}
+
+/// `stack_t`, describing the alternate signal stack.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+#[repr(C)]
+pub struct StackT {
+    pub sp: usize,
+    pub flags: i32,
+    pub size: usize,
+}
+
+impl StackT {
+    /// Returns whether `sp` is on this stack.
+    pub fn contains(&self, sp: usize) -> bool {
+        self.flags & SS_DISABLE == 0 && sp > self.sp && sp - self.sp <= self.size
+    }
+}
+
+/// `siginfo_t`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[repr(C)]
+pub struct SigInfo {
+    pub signo: i32,
+    pub errno: i32,
+    pub code: i32,
+    _pad: i32,
+    /// The signal specific fields, e.g. `si_addr` for SIGSEGV.
+    pub fields: [u64; 14],
+}
+
+impl SigInfo {
+    /// Creates a `siginfo_t` with no signal specific fields.
+    pub const fn new(signo: i32, code: i32) -> Self {
+        Self {
+            signo,
+            errno: 0,
+            code,
+            _pad: 0,
+            fields: [0; 14],
+        }
+    }
+
+    /// Returns `si_addr`, for the signals generated by a fault: SIGILL, SIGTRAP, SIGBUS, SIGFPE
+    /// and SIGSEGV.
+    pub fn addr(&self) -> Option<usize> {
+        matches!(self.signo, 4 | 5 | 7 | 8 | 11).then(|| self.fields[0] as usize)
+    }
+
+    /// Creates a `siginfo_t` for a fault at `addr`, e.g. SIGSEGV or SIGBUS.
+    pub const fn fault(signo: i32, code: i32, addr: usize) -> Self {
+        let mut info = Self::new(signo, code);
+        info.fields[0] = addr as u64;
+        info
+    }
+}
+
+/// `struct sigcontext`.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, align(16))]
+pub struct SigContext {
+    pub fault_address: u64,
+    pub regs: [u64; 31],
+    pub sp: u64,
+    pub pc: u64,
+    pub pstate: u64,
+    _pad: u64,
+    /// Records, each starting with an [`AArch64Ctx`], up to a null one.
+    pub reserved: [u8; 4096],
+}
+
+/// `struct _aarch64_ctx`, the header of the records in [`SigContext::reserved`].
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+#[repr(C)]
+pub struct AArch64Ctx {
+    pub magic: u32,
+    pub size: u32,
+}
+
+/// `struct fpsimd_context`, the record holding the FP/SIMD registers.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+#[repr(C, align(16))]
+pub struct FpsimdContext {
+    pub head: AArch64Ctx,
+    pub fpsr: u32,
+    pub fpcr: u32,
+    pub vregs: [u128; 32],
+}
+
+/// `struct ucontext`.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct UContext {
+    pub flags: u64,
+    pub link: usize,
+    pub stack: StackT,
+    pub sigmask: u64,
+    _unused: [u8; 120],
+    pub mcontext: SigContext,
+}
+
+/// `struct frame_record`, linking the signal frame into the frame pointer chain.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+#[repr(C)]
+pub struct FrameRecord {
+    pub fp: u64,
+    pub lr: u64,
+}
+
+/// `struct rt_sigframe`, followed by its frame record as pushed by Linux.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, align(16))]
+pub struct RtSigFrame {
+    pub info: SigInfo,
+    pub uc: UContext,
+    pub record: FrameRecord,
+}
+
+/// An error indicating that a signal frame can't be restored.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SigreturnError {
+    /// The stack pointer isn't 16 bytes aligned, so it doesn't point to a frame.
+    MisalignedFrame,
+    /// The saved PSTATE isn't EL0 in AArch64 state with all exceptions unmasked.
+    InvalidPstate,
+    /// The records are malformed, have an unknown magic, or lack the FP/SIMD one.
+    InvalidRecord,
+}
+
+/// An error indicating that a signal frame can't be set up.
+///
+/// Like Linux, the program should then get a SIGSEGV.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SigframeError {
+    /// The frame doesn't fit below the stack pointer, or the alternate stack wraps around.
+    InvalidStack,
+}
+
+/// Builds the frame for a signal handler, and redirects `ctx` to the handler.
+///
+/// `blocked` are the signals blocked before the signal, restored by `sigreturn`, and `altstack`
+/// is the alternate signal stack of the thread. The handler is called with x0 set to the signal
+/// number, x1 to the `siginfo_t` and x2 to the `ucontext`, and returns to `action.restorer`,
+/// which is expected to call `rt_sigreturn`.
+///
+/// Returns the frame, and the address on the user stack it must be copied to. `ctx` is left
+/// unchanged on error.
+///
+/// # Example
+/// ```no_run
+/// # use aarch64::{addr::VirtAddr, signal::*, trap::UserContext};
+/// # const SIGSEGV: i32 = 11;
+/// # const SEGV_MAPERR: i32 = 1;
+/// # #[derive(Debug)]
+/// # struct Efault;
+/// # impl From<SigreturnError> for Efault {
+/// #     fn from(_: SigreturnError) -> Self {
+/// #         Efault
+/// #     }
+/// # }
+/// # impl From<SigframeError> for Efault {
+/// #     fn from(_: SigframeError) -> Self {
+/// #         Efault
+/// #     }
+/// # }
+/// # fn copy_to_user<T>(addr: usize, value: &T) -> Result<(), Efault> { unimplemented!() }
+/// # fn copy_from_user<T>(addr: usize) -> Result<T, Efault> { unimplemented!() }
+/// # fn main() -> Result<(), Efault> {
+/// # let (mut context, action, altstack) =
+/// #     (UserContext::default(), SigAction::default(), StackT::default());
+/// # let (addr, mut blocked) = (VirtAddr::new(0), 0);
+/// let info = SigInfo::fault(SIGSEGV, SEGV_MAPERR, addr.as_u64() as usize);
+/// let (addr, frame) = setup_rt_frame(&mut context, &info, &action, blocked, &altstack)?;
+/// copy_to_user(addr, &frame)?;
+/// // on rt_sigreturn
+/// let frame = copy_from_user::<RtSigFrame>(context.get_sp())?;
+/// blocked = sigreturn(&mut context, &frame)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn setup_rt_frame(
+    ctx: &mut UserContext,
+    info: &SigInfo,
+    action: &SigAction,
+    blocked: u64,
+    altstack: &StackT,
+) -> Result<(usize, RtSigFrame), SigframeError> {
+    let mut sp = ctx.sp;
+    if action.flags & SA_ONSTACK != 0 && altstack.flags & SS_DISABLE == 0 && !altstack.contains(sp)
+    {
+        sp = altstack
+            .sp
+            .checked_add(altstack.size)
+            .ok_or(SigframeError::InvalidStack)?;
+    }
+    let addr = sp
+        .checked_sub(size_of::<RtSigFrame>())
+        .ok_or(SigframeError::InvalidStack)?
+        & !15;
+
+    let mut stack = *altstack;
+    if stack.contains(ctx.sp) {
+        stack.flags |= SS_ONSTACK;
+    }
+    let mut frame = RtSigFrame {
+        info: *info,
+        uc: UContext {
+            flags: 0,
+            link: 0,
+            stack,
+            sigmask: blocked,
+            _unused: [0; 120],
+            mcontext: SigContext {
+                fault_address: info.addr().unwrap_or(0) as u64,
+                regs: [0; 31],
+                sp: ctx.sp as u64,
+                pc: ctx.elr as u64,
+                pstate: ctx.spsr as u64,
+                _pad: 0,
+                reserved: [0; 4096],
+            },
+        },
+        record: FrameRecord {
+            fp: ctx.general.x29 as u64,
+            lr: ctx.general.x30 as u64,
+        },
+    };
+    frame.uc.mcontext.regs = general_regs(ctx).map(|x| x as u64);
+    write_fpsimd(&mut frame.uc.mcontext.reserved, &ctx.fp);
+
+    ctx.general.x0 = info.signo as usize;
+    ctx.general.x1 = addr;
+    ctx.general.x2 = addr + size_of::<SigInfo>();
+    ctx.general.x29 = addr + size_of::<SigInfo>() + size_of::<UContext>();
+    ctx.general.x30 = action.restorer;
+    ctx.sp = addr;
+    ctx.elr = action.handler;
+    // The handler is entered as by a BLR, with PSTATE.BTYPE = 0b10 like Linux (PSR_BTYPE_C),
+    // so that its BTI C or PACIASP landing pad is checked.
+    ctx.set_btype(0b10);
+    Ok((addr, frame))
+}
+
+/// Restores `ctx` from the signal frame, copied from the user stack pointer, and returns the
+/// signals blocked before the signal.
+///
+/// `ctx` is left unchanged on error, in which case Linux sends SIGSEGV. The FP/SIMD registers
+/// are enabled if the frame changes them.
+pub fn sigreturn(ctx: &mut UserContext, frame: &RtSigFrame) -> Result<u64, SigreturnError> {
+    if ctx.sp % 16 != 0 {
+        return Err(SigreturnError::MisalignedFrame);
+    }
+    let mcontext = &frame.uc.mcontext;
+    // Anything but EL0t in AArch64 state with D, A, I and F clear would run the program
+    // privileged, or with exceptions masked.
+    if mcontext.pstate & !PSTATE_USER_MASK != 0 {
+        return Err(SigreturnError::InvalidPstate);
+    }
+    let fp = read_fpsimd(&mcontext.reserved).ok_or(SigreturnError::InvalidRecord)?;
+
+    for (reg, value) in general_regs_mut(ctx).iter_mut().zip(mcontext.regs) {
+        **reg = value as usize;
+    }
+    ctx.sp = mcontext.sp as usize;
+    ctx.elr = mcontext.pc as usize;
+    ctx.spsr = mcontext.pstate as usize;
+    if ctx.fp != fp {
+        ctx.fp = fp;
+        ctx.fp_enabled = true;
+    }
+    Ok(frame.uc.sigmask)
+}
+
+/// Returns x0-x30 of `ctx`, in order.
+fn general_regs(ctx: &UserContext) -> [usize; 31] {
+    let g = &ctx.general;
+    [
+        g.x0, g.x1, g.x2, g.x3, g.x4, g.x5, g.x6, g.x7, g.x8, g.x9, g.x10, g.x11, g.x12, g.x13,
+        g.x14, g.x15, g.x16, g.x17, g.x18, g.x19, g.x20, g.x21, g.x22, g.x23, g.x24, g.x25, g.x26,
+        g.x27, g.x28, g.x29, g.x30,
+    ]
+}
+
+/// Returns references to x0-x30 of `ctx`, in order.
+fn general_regs_mut(ctx: &mut UserContext) -> [&mut usize; 31] {
+    let g = &mut ctx.general;
+    [
+        &mut g.x0, &mut g.x1, &mut g.x2, &mut g.x3, &mut g.x4, &mut g.x5, &mut g.x6, &mut g.x7,
+        &mut g.x8, &mut g.x9, &mut g.x10, &mut g.x11, &mut g.x12, &mut g.x13, &mut g.x14,
+        &mut g.x15, &mut g.x16, &mut g.x17, &mut g.x18, &mut g.x19, &mut g.x20, &mut g.x21,
+        &mut g.x22, &mut g.x23, &mut g.x24, &mut g.x25, &mut g.x26, &mut g.x27, &mut g.x28,
+        &mut g.x29, &mut g.x30,
+    ]
+}
+
+/// Writes the FP/SIMD record and the terminator at the start of `reserved`.
+fn write_fpsimd(reserved: &mut [u8; 4096], fp: &FpRegs) {
+    let record = FpsimdContext {
+        head: AArch64Ctx {
+            magic: FPSIMD_MAGIC,
+            size: size_of::<FpsimdContext>() as u32,
+        },
+        fpsr: fp.fpsr,
+        fpcr: fp.fpcr,
+        vregs: fp.q,
+    };
+    unsafe { (reserved.as_mut_ptr() as *mut FpsimdContext).write_unaligned(record) };
+    // The terminator is already zero.
+}
+
+/// Reads the records in `reserved`, and returns the FP/SIMD registers.
+fn read_fpsimd(reserved: &[u8; 4096]) -> Option<FpRegs> {
+    let mut fp = None;
+    let mut offset = 0;
+    loop {
+        if offset + size_of::<AArch64Ctx>() > reserved.len() {
+            return None;
+        }
+        let head = unsafe { (reserved.as_ptr().add(offset) as *const AArch64Ctx).read_unaligned() };
+        let size = head.size as usize;
+        match head.magic {
+            0 if size == 0 => return fp,
+            FPSIMD_MAGIC if size == size_of::<FpsimdContext>() && fp.is_none() => {
+                let record = unsafe {
+                    (reserved.as_ptr().add(offset) as *const FpsimdContext).read_unaligned()
+                };
+                fp = Some(FpRegs {
+                    q: record.vregs,
+                    fpcr: record.fpcr,
+                    fpsr: record.fpsr,
+                });
+            }
+            ESR_MAGIC if size == 16 => {}
+            _ => return None,
+        }
+        if offset + size > reserved.len() {
+            return None;
+        }
+        offset += size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_rt_sigframe() {
+        assert_eq!(size_of::<SigInfo>(), 128);
+        assert_eq!(size_of::<SigContext>(), 4384);
+        assert_eq!(size_of::<UContext>(), 4560);
+        assert_eq!(size_of::<RtSigFrame>(), 4704);
+
+        let mut ctx = UserContext {
+            sp: 0x8000_0008,
+            elr: 0x1000,
+            spsr: 0x2000_0000,
+            ..Default::default()
+        };
+        ctx.general.x3 = 3;
+        ctx.general.x30 = 0x1234;
+        ctx.fp.q[31] = 31;
+        let saved = ctx;
+        let action = SigAction {
+            handler: 0x2000,
+            flags: SA_SIGINFO | SA_RESTORER,
+            restorer: 0x3000,
+            mask: 0,
+        };
+        let info = SigInfo::fault(11, 1, 0xdead);
+        let (addr, frame) =
+            setup_rt_frame(&mut ctx, &info, &action, 0b100, &StackT::default()).unwrap();
+        assert_eq!(addr, (0x8000_0008 - 4704) & !15);
+        assert_eq!((ctx.sp, ctx.elr), (addr, 0x2000));
+        assert_eq!((ctx.general.x0, ctx.general.x1), (11, addr));
+        assert_eq!(ctx.general.x2, addr + 128);
+        assert_eq!(ctx.general.x29, addr + 4688);
+        assert_eq!(ctx.general.x30, 0x3000);
+        assert_eq!(ctx.btype(), 0b10);
+        assert_eq!(frame.uc.mcontext.regs[30], 0x1234);
+        assert_eq!(frame.uc.mcontext.fault_address, 0xdead);
+
+        // The handler returns through rt_sigreturn.
+        ctx.general.x3 = 0;
+        ctx.sp = addr;
+        assert_eq!(sigreturn(&mut ctx, &frame), Ok(0b100));
+        assert_eq!(ctx, saved);
+
+        // Returning to EL1h is refused.
+        ctx.sp = addr;
+        let mut bad = frame;
+        bad.uc.mcontext.pstate = 0b0101;
+        assert_eq!(
+            sigreturn(&mut ctx, &bad),
+            Err(SigreturnError::InvalidPstate)
+        );
+        let mut bad = frame;
+        bad.uc.mcontext.reserved[0] = 0;
+        assert_eq!(
+            sigreturn(&mut ctx, &bad),
+            Err(SigreturnError::InvalidRecord)
+        );
+
+        // A stack too low for the frame, or an alternate stack wrapping around, is refused.
+        ctx.sp = 0x1000;
+        let saved = ctx;
+        assert_eq!(
+            setup_rt_frame(&mut ctx, &info, &action, 0, &StackT::default()).map(|_| ()),
+            Err(SigframeError::InvalidStack)
+        );
+        let altstack = StackT {
+            sp: usize::MAX - 0xfff,
+            flags: 0,
+            size: 0x2000,
+        };
+        let action = SigAction {
+            flags: action.flags | SA_ONSTACK,
+            ..action
+        };
+        assert_eq!(
+            setup_rt_frame(&mut ctx, &info, &action, 0, &altstack).map(|_| ()),
+            Err(SigframeError::InvalidStack)
+        );
+        assert_eq!(ctx, saved);
+    }
+}
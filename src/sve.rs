@@ -0,0 +1,224 @@
+//! Scalable Vector Extension state.
+//!
+//! The size of the SVE registers depends on the vector length (VL), which is only known at
+//! runtime, so they are kept in a buffer provided by the caller, see [`SveRegs`].
+
+use crate::trap::FpRegs;
+#[allow(unused_imports)]
+use core::arch::asm;
+
+/// The largest vector length allowed by the architecture, in bytes.
+pub const SVE_VL_MAX: usize = 256;
+
+/// The smallest vector length, in bytes.
+pub const SVE_VL_MIN: usize = 16;
+
+/// Returns the largest vector length in bytes supported by the PE, or `None` if SVE is not
+/// implemented.
+///
+/// This changes ZCR_EL1 and enables SVE at EL1.
+pub fn max_vl() -> Option<usize> {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let pfr0: u64;
+        unsafe { asm!("mrs {}, id_aa64pfr0_el1", out(reg) pfr0) };
+        if (pfr0 >> 32) & 0xf == 0 {
+            return None;
+        }
+        Some(probe_vl(SVE_VL_MAX))
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    unimplemented!()
+}
+
+/// Sets ZCR_EL1.LEN for the vector length `vl`, and returns the effective vector length.
+///
+/// The PE may not support every multiple of 16 bytes, in which case a smaller vector length is
+/// used.
+pub fn probe_vl(vl: usize) -> usize {
+    set_vl(vl);
+    unsafe { sve_vl() }
+}
+
+/// Sets ZCR_EL1.LEN for the vector length `vl`, enabling SVE at EL1 if needed.
+fn set_vl(vl: usize) {
+    use crate::registers::{ReadWriteable, Readable, CPACR_EL1};
+
+    debug_assert!(vl % SVE_VL_MIN == 0 && (SVE_VL_MIN..=SVE_VL_MAX).contains(&vl));
+    // ZEN is 0b01 or 0b11 when EL1 is not trapped.
+    if CPACR_EL1.read(CPACR_EL1::ZEN) & 1 == 0 {
+        CPACR_EL1.modify(CPACR_EL1::ZEN::TrapEl0);
+    }
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        // ZCR_EL1
+        asm!("msr S3_0_C1_C2_0, {}", in(reg) vl / SVE_VL_MIN - 1);
+        crate::barrier::isb(crate::barrier::SY);
+    }
+}
+
+/// The SVE registers of a user context, for a given vector length.
+///
+/// The buffer holds Z0-Z31, then P0-P15 and FFR. The low 128 bits of the Z registers alias the
+/// FP/SIMD registers, which hold the state until the program first uses SVE.
+///
+/// # Example
+/// ```no_run
+/// # extern crate alloc;
+/// # use aarch64::{
+/// #     sve::{max_vl, SveRegs},
+/// #     trap::{TrapReason, UserContext},
+/// # };
+/// # use core::alloc::Layout;
+/// # const SYS_PRCTL: usize = 167;
+/// # let mut context = UserContext::default();
+/// // Sized for the largest vector length, so that it may be changed later on.
+/// let size = SveRegs::size(max_vl().unwrap());
+/// let buf = unsafe { alloc::alloc::alloc_zeroed(Layout::from_size_align(size, 16).unwrap()) };
+/// let mut sve = SveRegs::new(unsafe { core::slice::from_raw_parts_mut(buf, size) }, 64);
+/// loop {
+///     match context.run_sve(&mut sve) {
+///         // prctl(PR_SVE_SET_VL)
+///         TrapReason::Syscall if context.get_syscall_num() == SYS_PRCTL => {
+///             let vl = sve.set_vl(context.get_syscall_args()[1]);
+///             context.set_syscall_ret(vl);
+///         }
+///         // ...
+///         _ => {}
+///     }
+/// }
+/// ```
+pub struct SveRegs<'a> {
+    buf: &'a mut [u8],
+    vl: usize,
+    live: bool,
+}
+
+impl<'a> SveRegs<'a> {
+    /// Returns the size of the buffer needed for the vector length `vl`, in bytes.
+    pub const fn size(vl: usize) -> usize {
+        // 32 Z registers of VL bytes, and 17 predicates of VL / 8 bytes.
+        (32 * vl + 17 * (vl / 8) + 15) & !15
+    }
+
+    /// Creates the SVE registers in `buf`, for the vector length `vl`.
+    ///
+    /// `buf` must be 16 bytes aligned, and large enough for `vl`. SVE is not in use until the
+    /// first SVE instruction.
+    pub fn new(buf: &'a mut [u8], vl: usize) -> Self {
+        assert_eq!(buf.as_ptr() as usize % 16, 0, "SVE buffer is not aligned");
+        let mut this = Self {
+            buf,
+            vl: 0,
+            live: false,
+        };
+        this.set_vl_unchecked(vl);
+        this
+    }
+
+    /// Returns the vector length in bytes.
+    #[inline]
+    pub fn vl(&self) -> usize {
+        self.vl
+    }
+
+    /// Returns whether the program uses SVE, i.e. whether the state is held here rather than in
+    /// the FP/SIMD registers.
+    #[inline]
+    pub fn is_live(&self) -> bool {
+        self.live
+    }
+
+    /// Sets the vector length, like `prctl(PR_SVE_SET_VL)`, and returns the one in use.
+    ///
+    /// `vl` is rounded down to one supported by both the PE and the buffer. The SVE state is
+    /// discarded, the FP/SIMD registers are kept.
+    pub fn set_vl(&mut self, vl: usize) -> usize {
+        let vl = probe_vl(self.clamp_vl(vl));
+        self.set_vl_unchecked(vl);
+        vl
+    }
+
+    /// Rounds `vl` down to a multiple of 16 bytes fitting in the buffer.
+    fn clamp_vl(&self, vl: usize) -> usize {
+        let mut vl = vl.clamp(SVE_VL_MIN, SVE_VL_MAX) & !(SVE_VL_MIN - 1);
+        while vl > SVE_VL_MIN && Self::size(vl) > self.buf.len() {
+            vl -= SVE_VL_MIN;
+        }
+        vl
+    }
+
+    fn set_vl_unchecked(&mut self, vl: usize) {
+        assert!(Self::size(vl) <= self.buf.len(), "SVE buffer is too small");
+        self.vl = vl;
+        self.live = false;
+    }
+
+    /// Returns the Z register `n`.
+    pub fn z(&self, n: usize) -> &[u8] {
+        assert!(n < 32);
+        &self.buf[n * self.vl..(n + 1) * self.vl]
+    }
+
+    /// Returns the predicate register `n`, FFR being 16.
+    pub fn p(&self, n: usize) -> &[u8] {
+        assert!(n <= 16);
+        let start = 32 * self.vl + n * (self.vl / 8);
+        &self.buf[start..start + self.vl / 8]
+    }
+
+    /// Starts using SVE: the Z registers are loaded from the FP/SIMD registers `fp`, with their
+    /// upper bits and the predicates zeroed.
+    pub(crate) fn load_fp(&mut self, fp: &FpRegs) {
+        let size = Self::size(self.vl);
+        self.buf[..size].fill(0);
+        for (i, q) in fp.q.iter().enumerate() {
+            self.buf[i * self.vl..i * self.vl + 16].copy_from_slice(&q.to_le_bytes());
+        }
+        self.live = true;
+    }
+
+    /// Sets the vector length of the PE, and returns the buffers of the Z registers, and of the P
+    /// registers and FFR, which `run_user` loads before entering the program and saves on the
+    /// trap.
+    ///
+    /// # Safety
+    ///
+    /// SVE must be implemented, and the state must be live.
+    pub(crate) unsafe fn prepare_run(&mut self) -> (*mut u8, *mut u8) {
+        debug_assert!(self.live);
+        set_vl(self.vl);
+        let z = self.buf.as_mut_ptr();
+        (z, z.add(32 * self.vl))
+    }
+}
+
+extern "C" {
+    fn sve_vl() -> usize;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_sve_regs_layout() {
+        let mut buf = [0u128; 48];
+        let buf = unsafe { core::slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, 48 * 16) };
+        assert_eq!(SveRegs::size(16), 560);
+        let mut sve = SveRegs::new(buf, 16);
+        assert_eq!(sve.clamp_vl(64), 16);
+        assert_eq!(sve.clamp_vl(0), 16);
+
+        let mut fp = FpRegs::default();
+        fp.q[1] = 0x0f0e_0d0c_0b0a_0908_0706_0504_0302_0100;
+        sve.load_fp(&fp);
+        assert!(sve.is_live());
+        assert_eq!(
+            sve.z(1),
+            &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+        );
+        assert_eq!(sve.p(16), &[0, 0]);
+    }
+}
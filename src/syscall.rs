@@ -0,0 +1,307 @@
+//! Syscall calling conventions.
+//!
+//! [`UserContext`] has helpers for the Linux AArch64 convention. Programs following other
+//! conventions, e.g. AArch32 ones or a native ABI of the kernel, are served through
+//! [`SyscallAbi`], which also handles the restart of interrupted syscalls with
+//! [`handle_restart`].
+
+use crate::{
+    signal::{SigAction, SA_RESTART},
+    trap::UserContext,
+};
+use bit_field::BitField;
+
+/// The most arguments a syscall may take.
+pub const MAX_SYSCALL_ARGS: usize = 7;
+
+/// Interrupted syscall.
+pub const EINTR: i32 = 4;
+/// Restart the syscall if the signal handler has `SA_RESTART`, or if there is no handler.
+pub const ERESTARTSYS: i32 = 512;
+/// Always restart the syscall.
+pub const ERESTARTNOINTR: i32 = 513;
+/// Restart the syscall if there is no signal handler.
+pub const ERESTARTNOHAND: i32 = 514;
+/// Call the restart syscall instead if there is no signal handler, e.g. for `nanosleep`.
+pub const ERESTART_RESTARTBLOCK: i32 = 516;
+
+/// Where a syscall convention holds the number, arguments and result of a syscall.
+///
+/// # Example
+/// ```no_run
+/// # use aarch64::syscall::{LinuxAbi, SyscallAbi, MAX_SYSCALL_ARGS};
+/// # use aarch64::trap::{TrapReason, UserContext};
+/// # const SYS_RESTART: usize = 0x100;
+/// # const NATIVE_SVC: u16 = 1;
+/// # fn dispatch<A: SyscallAbi>(ctx: &mut UserContext) {}
+/// // A native ABI with 7 arguments, returning an error in X1.
+/// struct NativeAbi;
+///
+/// impl SyscallAbi for NativeAbi {
+///     const RESTART_SYSCALL: usize = SYS_RESTART;
+///
+///     fn num(ctx: &UserContext) -> usize {
+///         ctx.general.x16
+///     }
+///     fn args(ctx: &UserContext) -> [usize; MAX_SYSCALL_ARGS] {
+///         let g = &ctx.general;
+///         [g.x0, g.x1, g.x2, g.x3, g.x4, g.x5, g.x6]
+///     }
+///     fn set_ret(ctx: &mut UserContext, ret: u64) {
+///         ctx.general.x0 = ret as usize;
+///         ctx.general.x1 = 0;
+///     }
+///     fn set_error(ctx: &mut UserContext, errno: i32) {
+///         ctx.general.x1 = errno as usize;
+///     }
+///     fn error(ctx: &UserContext) -> Option<i32> {
+///         (ctx.general.x1 != 0).then(|| ctx.general.x1 as i32)
+///     }
+///     // ...
+/// #   fn set_num(ctx: &mut UserContext, num: usize) {}
+/// #   fn set_args(ctx: &mut UserContext, args: &[usize]) {}
+/// #   fn restart(ctx: &mut UserContext) {}
+/// }
+///
+/// # let mut context = UserContext::default();
+/// # let svc_imm = NATIVE_SVC;
+/// match context.run() {
+///     TrapReason::Syscall if svc_imm == NATIVE_SVC => dispatch::<NativeAbi>(&mut context),
+///     TrapReason::Syscall => dispatch::<LinuxAbi>(&mut context),
+///     // ...
+/// #   _ => {}
+/// }
+/// ```
+pub trait SyscallAbi {
+    /// The syscall executed instead of one returning [`ERESTART_RESTARTBLOCK`].
+    const RESTART_SYSCALL: usize;
+
+    /// Returns the syscall number.
+    fn num(ctx: &UserContext) -> usize;
+
+    /// Sets the syscall number.
+    fn set_num(ctx: &mut UserContext, num: usize);
+
+    /// Returns the arguments, the ones not passed by the convention being 0.
+    fn args(ctx: &UserContext) -> [usize; MAX_SYSCALL_ARGS];
+
+    /// Sets the arguments of a call into user space, e.g. an upcall.
+    fn set_args(ctx: &mut UserContext, args: &[usize]);
+
+    /// Sets a successful result.
+    fn set_ret(ctx: &mut UserContext, ret: u64);
+
+    /// Sets a failed result, with the positive error number `errno`.
+    fn set_error(ctx: &mut UserContext, errno: i32);
+
+    /// Returns the error number of the result, if it is a failure.
+    fn error(ctx: &UserContext) -> Option<i32>;
+
+    /// Moves back to the SVC instruction and restores the arguments, to execute the syscall
+    /// again.
+    fn restart(ctx: &mut UserContext);
+
+    /// Sets the result of the syscall.
+    fn set_result(ctx: &mut UserContext, result: Result<u64, i32>) {
+        match result {
+            Ok(ret) => Self::set_ret(ctx, ret),
+            Err(errno) => Self::set_error(ctx, errno),
+        }
+    }
+}
+
+/// The Linux AArch64 convention: the number is in X8, the arguments in X0-X5, and the result in
+/// X0, errors being `-errno`.
+pub struct LinuxAbi;
+
+impl SyscallAbi for LinuxAbi {
+    const RESTART_SYSCALL: usize = 128;
+
+    fn num(ctx: &UserContext) -> usize {
+        ctx.get_syscall_num()
+    }
+
+    fn set_num(ctx: &mut UserContext, num: usize) {
+        ctx.general.x8 = num;
+    }
+
+    fn args(ctx: &UserContext) -> [usize; MAX_SYSCALL_ARGS] {
+        let [a0, a1, a2, a3, a4, a5] = ctx.get_syscall_args();
+        [a0, a1, a2, a3, a4, a5, 0]
+    }
+
+    fn set_args(ctx: &mut UserContext, args: &[usize]) {
+        ctx.set_syscall_args(args);
+    }
+
+    fn set_ret(ctx: &mut UserContext, ret: u64) {
+        ctx.set_syscall_ret(ret as usize);
+    }
+
+    fn set_error(ctx: &mut UserContext, errno: i32) {
+        ctx.set_syscall_ret(-(errno as isize) as usize);
+    }
+
+    fn error(ctx: &UserContext) -> Option<i32> {
+        let ret = ctx.get_syscall_ret() as isize;
+        (-4095..0).contains(&ret).then(|| -ret as i32)
+    }
+
+    fn restart(ctx: &mut UserContext) {
+        ctx.restart_syscall();
+    }
+}
+
+/// The Linux AArch32 EABI convention: the number is in R7, the arguments in R0-R6, and the
+/// result in R0, or R0 and R1 for 64-bit results, errors being `-errno`.
+///
+/// This is the convention of [`CompatContext`](crate::compat::CompatContext), working on its
+/// inner context.
+pub struct LinuxCompatAbi;
+
+impl SyscallAbi for LinuxCompatAbi {
+    const RESTART_SYSCALL: usize = 0;
+
+    fn num(ctx: &UserContext) -> usize {
+        ctx.general.x7 as u32 as usize
+    }
+
+    fn set_num(ctx: &mut UserContext, num: usize) {
+        ctx.general.x7 = num as u32 as usize;
+    }
+
+    fn args(ctx: &UserContext) -> [usize; MAX_SYSCALL_ARGS] {
+        let g = &ctx.general;
+        [g.x0, g.x1, g.x2, g.x3, g.x4, g.x5, g.x6].map(|r| r as u32 as usize)
+    }
+
+    fn set_args(ctx: &mut UserContext, args: &[usize]) {
+        assert!(args.len() <= MAX_SYSCALL_ARGS, "too many arguments");
+        let mut args32 = [0; MAX_SYSCALL_ARGS];
+        for (arg32, &arg) in args32.iter_mut().zip(args) {
+            *arg32 = arg as u32 as usize;
+        }
+        ctx.set_syscall_args(&args32[..args.len()]);
+    }
+
+    fn set_ret(ctx: &mut UserContext, ret: u64) {
+        ctx.general.x0 = ret.get_bits(0..32) as usize;
+        ctx.general.x1 = ret.get_bits(32..64) as usize;
+    }
+
+    fn set_error(ctx: &mut UserContext, errno: i32) {
+        ctx.general.x0 = (-errno) as u32 as usize;
+    }
+
+    fn error(ctx: &UserContext) -> Option<i32> {
+        let ret = ctx.general.x0 as u32 as i32;
+        (-4095..0).contains(&ret).then(|| -ret)
+    }
+
+    fn restart(ctx: &mut UserContext) {
+        // SVC is 2 bytes long in T32 state.
+        let len = if ctx.spsr.get_bit(5) { 2 } else { 4 };
+        ctx.general.x0 = ctx.orig_x0 as u32 as usize;
+        ctx.elr = ctx.elr.wrapping_sub(len) as u32 as usize;
+    }
+}
+
+/// What became of a syscall returning one of the `ERESTART*` errors, see [`handle_restart`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Restart {
+    /// The syscall was not interrupted, the result is kept.
+    None,
+    /// The syscall is executed again, or replaced by the restart syscall.
+    Restarted,
+    /// The syscall fails with [`EINTR`].
+    Interrupted,
+}
+
+/// Handles a syscall returning one of the `ERESTART*` errors, which never reach user space.
+///
+/// This must be called once the result of the syscall is set, before going back to user space:
+/// `action` is the action of the signal whose handler is about to be called, or `None` if no
+/// handler runs. The signal frame must be built afterwards, so that the handler returns to the
+/// restarted syscall.
+pub fn handle_restart<A: SyscallAbi>(ctx: &mut UserContext, action: Option<&SigAction>) -> Restart {
+    let errno = match A::error(ctx) {
+        Some(errno @ (ERESTARTSYS | ERESTARTNOINTR | ERESTARTNOHAND | ERESTART_RESTARTBLOCK)) => {
+            errno
+        }
+        _ => return Restart::None,
+    };
+    let restart = match action {
+        Some(action) => match errno {
+            ERESTARTNOINTR => true,
+            ERESTARTSYS => action.flags & SA_RESTART != 0,
+            _ => false,
+        },
+        None => true,
+    };
+    if !restart {
+        A::set_error(ctx, EINTR);
+        return Restart::Interrupted;
+    }
+    A::restart(ctx);
+    if errno == ERESTART_RESTARTBLOCK {
+        A::set_num(ctx, A::RESTART_SYSCALL);
+    }
+    Restart::Restarted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_syscall_restart() {
+        let mut ctx = UserContext {
+            elr: 0x1004,
+            orig_x0: 3,
+            ..Default::default()
+        };
+        ctx.general.x8 = 101;
+
+        LinuxAbi::set_error(&mut ctx, ERESTARTSYS);
+        assert_eq!(LinuxAbi::error(&ctx), Some(ERESTARTSYS));
+        let mut action = SigAction::default();
+        assert_eq!(
+            handle_restart::<LinuxAbi>(&mut ctx, Some(&action)),
+            Restart::Interrupted
+        );
+        assert_eq!(LinuxAbi::error(&ctx), Some(EINTR));
+        assert_eq!(handle_restart::<LinuxAbi>(&mut ctx, None), Restart::None);
+
+        LinuxAbi::set_error(&mut ctx, ERESTARTSYS);
+        action.flags = SA_RESTART;
+        assert_eq!(
+            handle_restart::<LinuxAbi>(&mut ctx, Some(&action)),
+            Restart::Restarted
+        );
+        assert_eq!((ctx.general.x0, ctx.elr), (3, 0x1000));
+        assert_eq!(LinuxAbi::num(&ctx), 101);
+
+        ctx.elr = 0x1004;
+        LinuxAbi::set_error(&mut ctx, ERESTART_RESTARTBLOCK);
+        assert_eq!(
+            handle_restart::<LinuxAbi>(&mut ctx, None),
+            Restart::Restarted
+        );
+        assert_eq!(LinuxAbi::num(&ctx), LinuxAbi::RESTART_SYSCALL);
+
+        // T32 program, with a 64-bit result.
+        ctx.spsr = 0b11_0000;
+        ctx.elr = 0x8006;
+        LinuxCompatAbi::set_args(&mut ctx, &[1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(LinuxCompatAbi::args(&ctx), [1, 2, 3, 4, 5, 6, 7]);
+        LinuxCompatAbi::set_ret(&mut ctx, 0x1_0000_0002);
+        assert_eq!((ctx.general.x0, ctx.general.x1), (2, 1));
+        LinuxCompatAbi::set_error(&mut ctx, ERESTARTNOINTR);
+        assert_eq!(ctx.general.x0, 0xffff_fdff);
+        assert_eq!(
+            handle_restart::<LinuxCompatAbi>(&mut ctx, Some(&action)),
+            Restart::Restarted
+        );
+        assert_eq!((ctx.general.x0, ctx.elr), (3, 0x8004));
+    }
+}
@@ -1,65 +1,294 @@
 use crate::{
-    addr::{PhysAddr, VirtAddr},
+    addr::{PhysAddr, VirtAddr, VirtAddrRange},
     barrier,
     paging::Frame,
     registers::*,
 };
 use core::arch::asm;
 
-/// Address Translate (Stage 1 EL1 Read).
+/// Address translation operations, i.e. the `<op>` of `AT <op>, <Xt>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtOp {
+    /// Stage 1 translation as if for an EL1 read.
+    S1E1R,
+    /// Stage 1 translation as if for an EL1 write.
+    S1E1W,
+    /// Stage 1 translation as if for an EL0 read.
+    S1E0R,
+    /// Stage 1 translation as if for an EL0 write.
+    S1E0W,
+    /// Stage 1 translation as if for an EL1 read, taking PSTATE.PAN into account (FEAT_PAN2).
+    S1E1RP,
+    /// Stage 1 translation as if for an EL1 write, taking PSTATE.PAN into account (FEAT_PAN2).
+    S1E1WP,
+    /// Stage 1 and 2 translation as if for an EL1 read. Only executable at EL2 or above.
+    S12E1R,
+    /// Stage 1 and 2 translation as if for an EL1 write. Only executable at EL2 or above.
+    S12E1W,
+}
+
+/// Shareability of a memory location.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shareability {
+    NonShareable,
+    OuterShareable,
+    InnerShareable,
+}
+
+impl Shareability {
+    /// Decodes a 2-bit SH field. The reserved value `0b01` is treated as Non-shareable.
+    #[inline]
+    pub fn from_bits(bits: u64) -> Self {
+        match bits & 0b11 {
+            0b10 => Shareability::OuterShareable,
+            0b11 => Shareability::InnerShareable,
+            _ => Shareability::NonShareable,
+        }
+    }
+}
+
+/// The stage of translation at which a fault was generated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranslationStage {
+    Stage1,
+    Stage2,
+}
+
+/// Fault status code, as reported in PAR_EL1.FST and in the DFSC/IFSC field of ESR_ELx.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultStatus {
+    /// Address size fault at the given lookup level.
+    AddressSize { level: u8 },
+    /// Translation fault at the given lookup level.
+    Translation { level: u8 },
+    /// Access flag fault at the given lookup level.
+    AccessFlag { level: u8 },
+    /// Permission fault at the given lookup level.
+    Permission { level: u8 },
+    /// Synchronous External abort, not on translation table walk.
+    SyncExternal,
+    /// Synchronous Tag Check Fault (FEAT_MTE2).
+    SyncTagCheck,
+    /// Synchronous External abort on translation table walk at the given lookup level.
+    SyncExternalOnWalk { level: u8 },
+    /// Synchronous parity or ECC error, not on translation table walk.
+    SyncParity,
+    /// Synchronous parity or ECC error on translation table walk at the given lookup level.
+    SyncParityOnWalk { level: u8 },
+    /// Alignment fault.
+    Alignment,
+    /// TLB conflict abort.
+    TlbConflict,
+    /// Unsupported atomic hardware update fault (FEAT_HAFDBS).
+    UnsupportedAtomicUpdate,
+    /// IMPLEMENTATION DEFINED fault (Lockdown).
+    Lockdown,
+    /// IMPLEMENTATION DEFINED fault (Unsupported Exclusive or Atomic access).
+    UnsupportedExclusive,
+    /// Reserved or otherwise unrecognized fault status code.
+    Unknown(u8),
+}
+
+impl FaultStatus {
+    /// Decodes a 6-bit fault status code.
+    pub fn from_code(code: u8) -> Self {
+        use FaultStatus::*;
+
+        let level = code & 0b11;
+        match code & 0b11_1111 {
+            0b00_0000..=0b00_0011 => AddressSize { level },
+            0b00_0100..=0b00_0111 => Translation { level },
+            0b00_1001..=0b00_1011 => AccessFlag { level },
+            0b00_1101..=0b00_1111 => Permission { level },
+            0b01_0000 => SyncExternal,
+            0b01_0001 => SyncTagCheck,
+            0b01_0100..=0b01_0111 => SyncExternalOnWalk { level },
+            0b01_1000 => SyncParity,
+            0b01_1100..=0b01_1111 => SyncParityOnWalk { level },
+            0b10_0001 => Alignment,
+            0b11_0000 => TlbConflict,
+            0b11_0001 => UnsupportedAtomicUpdate,
+            0b11_0100 => Lockdown,
+            0b11_0101 => UnsupportedExclusive,
+            code => Unknown(code),
+        }
+    }
+
+    /// Returns the lookup level associated with the fault, if any.
+    pub fn level(self) -> Option<u8> {
+        use FaultStatus::*;
+
+        match self {
+            AddressSize { level }
+            | Translation { level }
+            | AccessFlag { level }
+            | Permission { level }
+            | SyncExternalOnWalk { level }
+            | SyncParityOnWalk { level } => Some(level),
+            _ => None,
+        }
+    }
+}
+
+/// The result of a successful address translation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TranslatedAddress {
+    /// The output physical address, including the page offset of the input address.
+    pub addr: PhysAddr,
+    /// Shareability attribute of the output address.
+    pub shareability: Shareability,
+    /// Memory attributes of the output address, in the MAIR_EL1 `Attr<n>` encoding.
+    pub attr: u8,
+    /// Whether the output address is in the Non-secure physical address space.
+    pub non_secure: bool,
+}
+
+/// The result of an aborted address translation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TranslationFault {
+    /// The fault status code.
+    pub status: FaultStatus,
+    /// The translation stage at which the translation aborted.
+    pub stage: TranslationStage,
+    /// Whether the abort was a stage 2 fault on a stage 1 translation table walk.
+    pub ptw: bool,
+}
+
+/// Decodes a PAR_EL1 value produced by translating `vaddr`.
+pub fn decode_par(par: u64, vaddr: VirtAddr) -> Result<TranslatedAddress, TranslationFault> {
+    use tock_registers::LocalRegisterCopy;
+
+    let par = LocalRegisterCopy::<u64, PAR_EL1::Register>::new(par);
+    if par.is_set(PAR_EL1::F) {
+        let stage = match par.read_as_enum(PAR_EL1::S) {
+            Some(PAR_EL1::S::Value::Stage2) => TranslationStage::Stage2,
+            _ => TranslationStage::Stage1,
+        };
+        Err(TranslationFault {
+            status: FaultStatus::from_code(par.read(PAR_EL1::FST) as u8),
+            stage,
+            ptw: par.is_set(PAR_EL1::PTW),
+        })
+    } else {
+        Ok(TranslatedAddress {
+            addr: PhysAddr::new((par.read(PAR_EL1::PA) << 12) | u64::from(vaddr.page_offset())),
+            shareability: Shareability::from_bits(par.read(PAR_EL1::SH)),
+            attr: par.read(PAR_EL1::ATTR) as u8,
+            non_secure: par.is_set(PAR_EL1::NS),
+        })
+    }
+}
+
+/// Address Translate.
+///
+/// Performs the translation `op` of `vaddr` by the hardware and decodes the result from
+/// PAR_EL1. For Raspi 3, it always return the result of a translation table walk, regardless
+/// of the TLB caching.
 ///
-/// For Raspi 3, it always return the result of a translation table walk,
-/// regardless of the TLB caching.
+/// PAR_EL1 is not preserved across exceptions, so the caller should not let an interrupt
+/// handler issue its own `AT` in between.
 #[inline]
-pub fn address_translate(vaddr: usize) -> usize {
+pub fn address_translate(op: AtOp, vaddr: VirtAddr) -> Result<TranslatedAddress, TranslationFault> {
+    let va = vaddr.as_u64();
     unsafe {
-        asm!("at S1E1R, {}", in(reg) vaddr);
+        match op {
+            AtOp::S1E1R => asm!("at s1e1r, {}", in(reg) va),
+            AtOp::S1E1W => asm!("at s1e1w, {}", in(reg) va),
+            AtOp::S1E0R => asm!("at s1e0r, {}", in(reg) va),
+            AtOp::S1E0W => asm!("at s1e0w, {}", in(reg) va),
+            // AT S1E1RP and AT S1E1WP, encoded as SYS so that FEAT_PAN2 needn't be enabled in
+            // the assembler.
+            AtOp::S1E1RP => asm!("sys #0, c7, c9, #0, {}", in(reg) va),
+            AtOp::S1E1WP => asm!("sys #0, c7, c9, #1, {}", in(reg) va),
+            AtOp::S12E1R => asm!("at s12e1r, {}", in(reg) va),
+            AtOp::S12E1W => asm!("at s12e1w, {}", in(reg) va),
+        }
         barrier::isb(barrier::SY);
     }
-    PAR_EL1.get() as usize
+    decode_par(PAR_EL1.get(), vaddr)
+}
+
+/// Returns whether `vaddr` is readable from EL0 according to the stage 1 translation.
+#[inline]
+pub fn is_user_readable(vaddr: VirtAddr) -> bool {
+    address_translate(AtOp::S1E0R, vaddr).is_ok()
+}
+
+/// Returns whether `vaddr` is writable from EL0 according to the stage 1 translation.
+#[inline]
+pub fn is_user_writable(vaddr: VirtAddr) -> bool {
+    address_translate(AtOp::S1E0W, vaddr).is_ok()
+}
+
+/// Selects one of the two translation table base registers of the EL1&0 translation regime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ttbr {
+    /// TTBR0_EL1, for the lower VA range.
+    Lower,
+    /// TTBR1_EL1, for the upper VA range.
+    Upper,
+}
+
+impl From<VirtAddrRange> for Ttbr {
+    fn from(range: VirtAddrRange) -> Self {
+        match range {
+            VirtAddrRange::Bottom => Ttbr::Lower,
+            VirtAddrRange::Top => Ttbr::Upper,
+        }
+    }
 }
 
 /// Read TTBRx_EL1 as Frame
 #[inline]
-pub fn ttbr_el1_read(which: u8) -> Frame {
+pub fn ttbr_el1_read(which: Ttbr) -> Frame {
     let baddr = match which {
-        0 => TTBR0_EL1.get_baddr(),
-        1 => TTBR1_EL1.get_baddr(),
-        _ => 0,
+        Ttbr::Lower => TTBR0_EL1.get_baddr(),
+        Ttbr::Upper => TTBR1_EL1.get_baddr(),
     };
     Frame::containing_address(PhysAddr::new(baddr))
 }
 
 /// Write TTBRx_EL1 from Frame
 #[inline]
-pub fn ttbr_el1_write(which: u8, frame: Frame) {
+pub fn ttbr_el1_write(which: Ttbr, frame: Frame) {
     let baddr = frame.start_address().as_u64();
     match which {
-        0 => TTBR0_EL1.set_baddr(baddr),
-        1 => TTBR1_EL1.set_baddr(baddr),
-        _ => {}
+        Ttbr::Lower => TTBR0_EL1.set_baddr(baddr),
+        Ttbr::Upper => TTBR1_EL1.set_baddr(baddr),
     };
 }
 
 /// Read TTBRx_EL1 as Frame and ASID
 #[inline]
-pub fn ttbr_el1_read_asid(which: u8) -> (u16, Frame) {
+pub fn ttbr_el1_read_asid(which: Ttbr) -> (u16, Frame) {
     let (asid, baddr) = match which {
-        0 => (TTBR0_EL1.get_asid(), TTBR0_EL1.get_baddr()),
-        1 => (TTBR1_EL1.get_asid(), TTBR1_EL1.get_baddr()),
-        _ => (0, 0),
+        Ttbr::Lower => (TTBR0_EL1.get_asid(), TTBR0_EL1.get_baddr()),
+        Ttbr::Upper => (TTBR1_EL1.get_asid(), TTBR1_EL1.get_baddr()),
     };
     (asid, Frame::containing_address(PhysAddr::new(baddr)))
 }
 
 /// write TTBRx_EL1 from Frame and ASID
 #[inline]
-pub fn ttbr_el1_write_asid(which: u8, asid: u16, frame: Frame) {
+pub fn ttbr_el1_write_asid(which: Ttbr, asid: u16, frame: Frame) {
+    ttbr_el1_write_asid_cnp(which, asid, frame, false);
+}
+
+/// write TTBRx_EL1 from Frame, ASID and the Common not Private bit
+#[inline]
+pub fn ttbr_el1_write_asid_cnp(which: Ttbr, asid: u16, frame: Frame, cnp: bool) {
     let baddr = frame.start_address().as_u64();
     match which {
-        0 => TTBR0_EL1.write(TTBR0_EL1::ASID.val(asid as u64) + TTBR0_EL1::BADDR.val(baddr >> 1)),
-        1 => TTBR1_EL1.write(TTBR1_EL1::ASID.val(asid as u64) + TTBR1_EL1::BADDR.val(baddr >> 1)),
-        _ => {}
+        Ttbr::Lower => TTBR0_EL1.write(
+            TTBR0_EL1::ASID.val(asid as u64)
+                + TTBR0_EL1::BADDR.val(baddr >> 1)
+                + TTBR0_EL1::CnP.val(cnp as u64),
+        ),
+        Ttbr::Upper => TTBR1_EL1.write(
+            TTBR1_EL1::ASID.val(asid as u64)
+                + TTBR1_EL1::BADDR.val(baddr >> 1)
+                + TTBR1_EL1::CnP.val(cnp as u64),
+        ),
     };
 }
 
@@ -107,3 +336,35 @@ pub fn invalidate_tlb_vaddr(vaddr: VirtAddr) {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_decode_par_success() {
+        let par = (0xffu64 << 56) | 0x4008_2000 | (0b11 << 7) | (1 << 11);
+        let result = decode_par(par, VirtAddr::new(0xffff_0000_1234_5678)).unwrap();
+        assert_eq!(result.addr, PhysAddr::new(0x4008_2678));
+        assert_eq!(result.shareability, Shareability::InnerShareable);
+        assert_eq!(result.attr, 0xff);
+        assert!(!result.non_secure);
+    }
+
+    #[test]
+    pub fn test_decode_par_fault() {
+        // Stage 2 permission fault at level 3 on a stage 1 walk.
+        let par = 1 | (0b00_1111 << 1) | (1 << 8) | (1 << 9);
+        let fault = decode_par(par, VirtAddr::new(0x1000)).unwrap_err();
+        assert_eq!(fault.status, FaultStatus::Permission { level: 3 });
+        assert_eq!(fault.status.level(), Some(3));
+        assert_eq!(fault.stage, TranslationStage::Stage2);
+        assert!(fault.ptw);
+
+        let par = 1 | (0b00_0101 << 1);
+        let fault = decode_par(par, VirtAddr::new(0x1000)).unwrap_err();
+        assert_eq!(fault.status, FaultStatus::Translation { level: 1 });
+        assert_eq!(fault.stage, TranslationStage::Stage1);
+        assert!(!fault.ptw);
+    }
+}
@@ -1,5 +1,17 @@
+use crate::{
+    addr::VirtAddr,
+    barrier,
+    debug::{self, DebugState},
+    exception::{ExceptionClass, ExceptionInfo, Syndrome},
+    mte::MteState,
+    pauth::{self, PauthKey, PauthKeys},
+    sve::SveRegs,
+    translation::FaultStatus,
+};
+use bit_field::BitField;
 #[allow(unused_imports)]
 use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 #[cfg(target_arch = "aarch64")]
 global_asm!(include_str!("trap.S"));
@@ -21,8 +33,45 @@ pub struct UserContext {
     /// Software Thread ID Register, tpidr_el0
     pub tpidr: usize,
     /// General registers
-    /// Must be last in this struct
+    /// Must be last of the registers saved by trap.S
     pub general: GeneralRegs,
+    /// Floating-point and SIMD registers
+    pub fp: FpRegs,
+    /// Whether the user program may access FP/SIMD registers
+    ///
+    /// If `false`, the first FP/SIMD instruction traps, and `run` sets it before going back to
+    /// user space: the FP/SIMD registers are only switched for the programs using them. Set it
+    /// beforehand to always switch them.
+    pub fp_enabled: bool,
+    /// X0 when the last syscall was made, set by `run`
+    ///
+    /// X0 holds both the first argument and the return value, this keeps the argument to
+    /// restart the syscall.
+    pub orig_x0: usize,
+    /// Hardware breakpoints, watchpoints and single-step
+    ///
+    /// Loaded while the program runs if any is enabled, see [`debug::init`].
+    pub debug: DebugState,
+    /// Pointer authentication keys
+    ///
+    /// Loaded while the program runs if set, which requires pointer authentication to be
+    /// implemented and enabled. Without keys, the program runs with zero keys once pointer
+    /// authentication is in use, see [`pauth`](crate::pauth).
+    pub pauth: Option<PauthKeys>,
+    /// Memory tagging state
+    ///
+    /// Loaded while the program runs if set, which requires the tag instructions to be
+    /// enabled, see [`mte::enable`](crate::mte::enable). Without it, tags are not checked.
+    pub mte: Option<MteState>,
+}
+
+/// Floating-point and SIMD registers
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+#[repr(C, align(16))]
+pub struct FpRegs {
+    pub q: [u128; 32],
+    pub fpcr: u32,
+    pub fpsr: u32,
 }
 
 /// General registers
@@ -65,7 +114,175 @@ pub struct GeneralRegs {
     // x31 means special
 }
 
+/// Where the exception was taken from, the low 16 bits of `trap_num`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TrapSource {
+    /// Current EL, using SP_EL0.
+    CurrentSpEl0 = 0,
+    /// Current EL, using SP_ELx.
+    CurrentSpElx = 1,
+    /// Lower EL, in AArch64 state.
+    LowerAArch64 = 2,
+    /// Lower EL, in AArch32 state.
+    LowerAArch32 = 3,
+}
+
+/// The type of exception, the high bits of `trap_num`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TrapKind {
+    Synchronous = 0,
+    Irq = 1,
+    Fiq = 2,
+    SError = 3,
+}
+
+/// Decodes a `trap_num`, as set by the exception vectors.
+pub fn decode_trap_num(trap_num: usize) -> (TrapSource, TrapKind) {
+    let source = match trap_num & 0xffff {
+        0 => TrapSource::CurrentSpEl0,
+        1 => TrapSource::CurrentSpElx,
+        2 => TrapSource::LowerAArch64,
+        3 => TrapSource::LowerAArch32,
+        _ => panic!("invalid trap num {:#x}", trap_num),
+    };
+    let kind = match trap_num >> 16 {
+        0 => TrapKind::Synchronous,
+        1 => TrapKind::Irq,
+        2 => TrapKind::Fiq,
+        3 => TrapKind::SError,
+        _ => panic!("invalid trap num {:#x}", trap_num),
+    };
+    (source, kind)
+}
+
+/// The kind of access that caused a page fault.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PageFaultAccess {
+    Read,
+    Write,
+    Execute,
+}
+
+/// Why the user program trapped back to the kernel.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TrapReason {
+    /// SVC instruction, the syscall number is in x8.
+    Syscall,
+    /// Translation, access flag or permission fault on a user access.
+    PageFault {
+        addr: VirtAddr,
+        access: PageFaultAccess,
+    },
+    /// IRQ interrupt.
+    Irq,
+    /// FIQ interrupt.
+    Fiq,
+    /// SError interrupt.
+    SError(ExceptionInfo),
+    /// Undefined instruction, or an instruction not allowed at EL0.
+    Undefined,
+    /// FP/SIMD instruction, with FP/SIMD access disabled.
+    FpAccess,
+    /// SVE instruction, with SVE access disabled.
+    ///
+    /// [`UserContext::run`] has nowhere to save the SVE registers: the program needs to be run
+    /// with [`UserContext::run_sve`] instead.
+    SveAccess,
+    /// BRK or BKPT instruction, with its comment.
+    Breakpoint(u16),
+    /// Hardware breakpoint, on the instruction at `elr`.
+    HardwareBreakpoint,
+    /// Hardware watchpoint, on an access to `addr`.
+    Watchpoint { addr: VirtAddr, write: bool },
+    /// Software step, after executing one instruction.
+    SingleStep,
+    /// Pointer authentication failure, with the key used (FEAT_FPAC).
+    ///
+    /// Without FEAT_FPAC, the authenticated pointer is made invalid instead, and its use
+    /// faults.
+    PointerAuth(PauthKey),
+    /// Branch Target Exception, an indirect branch into a guarded page not reaching a BTI
+    /// instruction, with PSTATE.BTYPE (FEAT_BTI).
+    BranchTarget(u8),
+    /// Synchronous Tag Check Fault, on an access to `addr` whose Logical Tag does not match
+    /// the Allocation Tag of the memory.
+    TagCheck { addr: VirtAddr },
+    /// PC or SP alignment fault.
+    Alignment,
+    /// Any other synchronous exception.
+    Other(ExceptionInfo),
+}
+
+impl TrapReason {
+    /// Builds the reason of a trap of `kind`, from the syndrome `info`.
+    ///
+    /// `info` is only meaningful for synchronous exceptions and SErrors.
+    pub fn new(kind: TrapKind, info: ExceptionInfo) -> Self {
+        use ExceptionClass::*;
+
+        match kind {
+            TrapKind::Irq => return TrapReason::Irq,
+            TrapKind::Fiq => return TrapReason::Fiq,
+            TrapKind::SError => return TrapReason::SError(info),
+            TrapKind::Synchronous => {}
+        }
+        let access = match (info.syndrome, info.far) {
+            (Syndrome::DataAbort(abort), Some(addr)) => Some((
+                abort.status,
+                addr,
+                if abort.write && !abort.cache_maintenance {
+                    PageFaultAccess::Write
+                } else {
+                    PageFaultAccess::Read
+                },
+            )),
+            (Syndrome::InstructionAbort(abort), Some(addr)) => {
+                Some((abort.status, addr, PageFaultAccess::Execute))
+            }
+            _ => None,
+        };
+        if let Some((status, addr, access)) = access {
+            return match status {
+                FaultStatus::Translation { .. }
+                | FaultStatus::AccessFlag { .. }
+                | FaultStatus::Permission { .. } => TrapReason::PageFault { addr, access },
+                FaultStatus::Alignment => TrapReason::Alignment,
+                FaultStatus::SyncTagCheck => TrapReason::TagCheck { addr },
+                _ => TrapReason::Other(info),
+            };
+        }
+        match info.class {
+            Svc32 | Svc64 => TrapReason::Syscall,
+            Unknown | IllegalState | MsrMrs => TrapReason::Undefined,
+            FpSimdAccess => TrapReason::FpAccess,
+            Sve => TrapReason::SveAccess,
+            Brk64 | Bkpt32 => match info.syndrome {
+                Syndrome::Brk(comment) | Syndrome::Bkpt(comment) => TrapReason::Breakpoint(comment),
+                _ => unreachable!(),
+            },
+            PcAlignment | SpAlignment => TrapReason::Alignment,
+            BreakpointLower | BreakpointSame => TrapReason::HardwareBreakpoint,
+            SoftwareStepLower | SoftwareStepSame => TrapReason::SingleStep,
+            BranchTarget => TrapReason::BranchTarget(info.iss.get_bits(0..2) as u8),
+            Fpac => TrapReason::PointerAuth(PauthKey::from_fpac_iss(info.iss)),
+            WatchpointLower | WatchpointSame => match info.far {
+                Some(addr) => TrapReason::Watchpoint {
+                    addr,
+                    write: info.iss.get_bit(6),
+                },
+                None => TrapReason::Other(info),
+            },
+            _ => TrapReason::Other(info),
+        }
+    }
+}
+
 impl UserContext {
+    /// Get the source and kind of the last trap
+    pub fn trap_type(&self) -> (TrapSource, TrapKind) {
+        decode_trap_num(self.trap_num)
+    }
+
     /// Get number of syscall
     pub fn get_syscall_num(&self) -> usize {
         self.general.x8
@@ -93,6 +310,24 @@ impl UserContext {
         ]
     }
 
+    /// Set the arguments of a call into user space, e.g. an upcall, X0-X7
+    pub fn set_syscall_args(&mut self, args: &[usize]) {
+        let g = &mut self.general;
+        let mut regs = [
+            &mut g.x0, &mut g.x1, &mut g.x2, &mut g.x3, &mut g.x4, &mut g.x5, &mut g.x6, &mut g.x7,
+        ];
+        assert!(args.len() <= regs.len(), "too many arguments");
+        for (reg, &arg) in regs.iter_mut().zip(args) {
+            **reg = arg;
+        }
+    }
+
+    /// Moves back to the SVC instruction and restores X0, to execute the syscall again.
+    pub fn restart_syscall(&mut self) {
+        self.general.x0 = self.orig_x0;
+        self.elr = self.elr.wrapping_sub(4);
+    }
+
     /// Set instruction pointer
     pub fn set_ip(&mut self, ip: usize) {
         self.elr = ip;
@@ -113,13 +348,25 @@ impl UserContext {
         self.tpidr = tls;
     }
 
+    /// Get PSTATE.BTYPE, the type of the indirect branch to the next instruction
+    pub fn btype(&self) -> u8 {
+        self.spsr.get_bits(10..12) as u8
+    }
+
+    /// Set PSTATE.BTYPE, e.g. 0 when the next instruction is not reached by a branch
+    pub fn set_btype(&mut self, btype: u8) {
+        self.spsr.set_bits(10..12, btype as usize & 0b11);
+    }
+
     /// Go to user space with the context, and come back when a trap occurs.
     ///
     /// On return, the context will be reset to the status before the trap.
-    /// Trap reason and error code will be returned.
+    /// The trap reason is decoded from `trap_num`, ESR_EL1 and FAR_EL1, so this must be called
+    /// with interrupts disabled.
     ///
     /// # Example
     /// ```no_run
+    /// # use aarch64::trap::{GeneralRegs, UserContext};
     /// // init user space context
     /// let mut context = UserContext {
     ///     general: GeneralRegs {
@@ -130,12 +377,80 @@ impl UserContext {
     ///     ..Default::default()
     /// };
     /// // go to user
-    /// context.run();
+    /// let reason = context.run();
     /// // back from user
-    /// println!("back from user: {:#x?}", context);
+    /// println!("back from user: {:?} {:#x?}", reason, context);
     /// ```
-    pub fn run(&mut self) {
-        unsafe { run_user(self) }
+    pub fn run(&mut self) -> TrapReason {
+        self.run_with(None)
+    }
+
+    /// Go to user space with the context and the SVE registers `sve`, and come back when a trap
+    /// occurs.
+    ///
+    /// SVE is trapped until the first SVE instruction, then the SVE registers are switched with
+    /// the vector length of `sve`. See [`run`](Self::run).
+    pub fn run_sve(&mut self, sve: &mut SveRegs) -> TrapReason {
+        self.run_with(Some(sve))
+    }
+
+    fn run_with(&mut self, mut sve: Option<&mut SveRegs>) -> TrapReason {
+        loop {
+            let sve_live = matches!(&sve, Some(sve) if sve.is_live());
+            set_user_fp_access(self.fp_enabled, sve_live);
+            let debug_active = self.debug.is_active();
+            // PSTATE.SS is loaded from SPSR_EL1, to step a single instruction.
+            self.spsr.set_bit(21, self.debug.single_step);
+            unsafe {
+                if debug_active {
+                    self.debug.restore();
+                }
+                if let Some(mte) = &self.mte {
+                    mte.restore();
+                }
+                let keys = pauth::user_keys(self.pauth.as_ref())
+                    .map_or(core::ptr::null(), |keys| keys as *const _);
+                let (z, p) = match sve.as_deref_mut().filter(|_| sve_live) {
+                    Some(sve) => sve.prepare_run(),
+                    None => (core::ptr::null_mut(), core::ptr::null_mut()),
+                };
+                // The FP/SIMD and SVE registers of the program are only live in run_user, no
+                // compiled code runs with them.
+                let ctx: *mut Self = self;
+                let fp = if self.fp_enabled {
+                    core::ptr::addr_of_mut!((*ctx).fp)
+                } else {
+                    core::ptr::null_mut()
+                };
+                run_user(ctx, keys, fp, z, p);
+                if let Some(mte) = &mut self.mte {
+                    mte.save();
+                }
+            }
+            if debug_active {
+                debug::disable();
+            }
+            let (_, kind) = self.trap_type();
+            let reason = TrapReason::new(kind, ExceptionInfo::current());
+            match reason {
+                TrapReason::FpAccess if !self.fp_enabled => {
+                    // First use of FP/SIMD, the registers are loaded from now on.
+                    self.fp_enabled = true;
+                    continue;
+                }
+                TrapReason::SveAccess if !sve_live => {
+                    if let Some(sve) = sve.as_deref_mut() {
+                        // First use of SVE, the Z registers extend the FP/SIMD ones.
+                        sve.load_fp(&self.fp);
+                        self.fp_enabled = true;
+                        continue;
+                    }
+                }
+                TrapReason::Syscall => self.orig_x0 = self.general.x0,
+                _ => {}
+            }
+            return reason;
+        }
     }
 }
 
@@ -165,14 +480,20 @@ pub unsafe fn init() {
 ///
 /// # Trap handler
 ///
-/// You need to define a handler function like this:
+/// Handlers are registered for each source and kind with [`register_handler`]:
 ///
 /// ```no_run
-/// #[no_mangle]
-/// pub extern "C" fn trap_handler(tf: &mut TrapFrame) {
-///     println!("TRAP! tf: {:#x?}", tf);
+/// # use aarch64::trap::{register_handler, TrapFrame, TrapInfo, TrapKind, TrapSource};
+/// fn irq_handler(tf: &mut TrapFrame, info: &TrapInfo) {
+///     println!("IRQ! tf: {:#x?}", tf);
 /// }
+///
+/// register_handler(TrapSource::CurrentSpElx, TrapKind::Irq, irq_handler);
 /// ```
+///
+/// Unhandled traps panic, printing the frame and the syndrome.
+///
+/// IRQs and FIQs are handled on the IRQ stack of the CPU, see [`set_irq_stack`].
 #[derive(Debug, Default, Clone, Copy)]
 #[repr(C)]
 pub struct TrapFrame {
@@ -193,7 +514,413 @@ pub struct TrapFrame {
     pub general: GeneralRegs,
 }
 
+impl TrapFrame {
+    /// Get the source and kind of the trap
+    pub fn trap_type(&self) -> (TrapSource, TrapKind) {
+        decode_trap_num(self.trap_num)
+    }
+
+    /// Whether IRQs were masked in the interrupted context
+    ///
+    /// A kernel shouldn't preempt a context that masked IRQs.
+    pub fn irqs_masked(&self) -> bool {
+        self.spsr & (1 << 7) != 0
+    }
+}
+
+/// Decoded information about a trap, passed to its handler.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct TrapInfo {
+    pub source: TrapSource,
+    pub kind: TrapKind,
+    /// The syndrome, for synchronous exceptions and SErrors
+    pub exception: Option<ExceptionInfo>,
+}
+
+impl TrapInfo {
+    /// Decodes the trap of `tf`, reading ESR_EL1 and FAR_EL1.
+    fn current(tf: &TrapFrame) -> Self {
+        let (source, kind) = tf.trap_type();
+        let exception = match kind {
+            TrapKind::Synchronous | TrapKind::SError => Some(ExceptionInfo::current()),
+            TrapKind::Irq | TrapKind::Fiq => None,
+        };
+        Self {
+            source,
+            kind,
+            exception,
+        }
+    }
+}
+
+/// A handler of traps from EL1.
+pub type TrapHandler = fn(&mut TrapFrame, &TrapInfo);
+
+/// Handlers, indexed by `source | kind << 2`.
+static HANDLERS: [AtomicUsize; 16] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+/// Sets the handler of the traps of `kind` from `source`, and returns the previous one.
+///
+/// Traps from lower ELs are not dispatched to handlers, they are returned by
+/// [`UserContext::run`] instead.
+pub fn register_handler(
+    source: TrapSource,
+    kind: TrapKind,
+    handler: TrapHandler,
+) -> Option<TrapHandler> {
+    let old = handler_slot(source, kind).swap(handler as usize, Ordering::AcqRel);
+    unsafe { handler_from_usize(old) }
+}
+
+/// Removes the handler of the traps of `kind` from `source`, and returns it.
+pub fn unregister_handler(source: TrapSource, kind: TrapKind) -> Option<TrapHandler> {
+    let old = handler_slot(source, kind).swap(0, Ordering::AcqRel);
+    unsafe { handler_from_usize(old) }
+}
+
+fn handler_slot(source: TrapSource, kind: TrapKind) -> &'static AtomicUsize {
+    &HANDLERS[source as usize | (kind as usize) << 2]
+}
+
+unsafe fn handler_from_usize(handler: usize) -> Option<TrapHandler> {
+    if handler == 0 {
+        None
+    } else {
+        Some(core::mem::transmute::<usize, TrapHandler>(handler))
+    }
+}
+
+/// Called by trap.S for every trap from EL1.
+#[no_mangle]
+extern "C" fn __trap_dispatch(tf: &mut TrapFrame) {
+    let info = TrapInfo::current(tf);
+    let handler = handler_slot(info.source, info.kind).load(Ordering::Acquire);
+    match unsafe { handler_from_usize(handler) } {
+        Some(handler) => handler(tf, &info),
+        None => default_handler(tf, &info),
+    }
+}
+
+/// Panics with the frame and, for synchronous exceptions and SErrors, ESR_EL1.
+fn default_handler(tf: &mut TrapFrame, info: &TrapInfo) -> ! {
+    use crate::registers::{Readable, ESR_EL1};
+
+    match info.exception {
+        Some(exception) => panic!(
+            "unhandled trap: {:?} from {:?}, ESR {:#x}: {:#x?}\n{:#x?}",
+            info.kind,
+            info.source,
+            ESR_EL1.get(),
+            exception,
+            tf
+        ),
+        None => panic!(
+            "unhandled trap: {:?} from {:?}\n{:#x?}",
+            info.kind, info.source, tf
+        ),
+    }
+}
+
+/// The maximum number of CPUs, as identified by [`cpuid`](crate::asm::cpuid).
+pub const MAX_CPUS: usize = 4;
+
+/// Interrupt state of a CPU, shared with trap.S.
+#[repr(C)]
+struct CpuTrapState {
+    /// Top of the IRQ stack, or 0 to stay on the interrupted stack
+    irq_stack_top: usize,
+    /// Number of nested IRQ and FIQ handlers
+    irq_depth: usize,
+    /// Frame of the innermost IRQ or FIQ, linked to the outer ones by `__reserved`
+    irq_frame: *mut TrapFrame,
+    /// Bottom of the current stack, checked on traps from EL1
+    stack_limit: usize,
+    /// Bottom of the interrupted stack, while on the IRQ stack
+    saved_stack_limit: usize,
+    /// Bottom of the IRQ stack
+    irq_stack_bottom: usize,
+    /// Top of the stack used on a kernel stack overflow
+    overflow_stack_top: *mut u8,
+    /// Bottom of the overflow stack, the stack limit once switched to it
+    overflow_stack_bottom: *mut u8,
+}
+
+/// Size of the default overflow stack of each CPU, enough to report the overflow.
+pub const DEFAULT_OVERFLOW_STACK_SIZE: usize = 0x1000;
+
+#[repr(C, align(16))]
+struct OverflowStack([u8; DEFAULT_OVERFLOW_STACK_SIZE]);
+
+/// Overflow stacks of the CPUs without one set by [`set_overflow_stack`].
+static mut DEFAULT_OVERFLOW_STACKS: [OverflowStack; MAX_CPUS] = [
+    OverflowStack([0; DEFAULT_OVERFLOW_STACK_SIZE]),
+    OverflowStack([0; DEFAULT_OVERFLOW_STACK_SIZE]),
+    OverflowStack([0; DEFAULT_OVERFLOW_STACK_SIZE]),
+    OverflowStack([0; DEFAULT_OVERFLOW_STACK_SIZE]),
+];
+
+#[export_name = "__cpu_trap_state"]
+static mut CPU_TRAP_STATE: [CpuTrapState; MAX_CPUS] = unsafe {
+    [
+        CpuTrapState::new(default_overflow_stack(0)),
+        CpuTrapState::new(default_overflow_stack(1)),
+        CpuTrapState::new(default_overflow_stack(2)),
+        CpuTrapState::new(default_overflow_stack(3)),
+    ]
+};
+
+/// Returns the bottom and the top of the default overflow stack of `cpu`.
+const unsafe fn default_overflow_stack(cpu: usize) -> (*mut u8, *mut u8) {
+    let stack = core::ptr::addr_of_mut!(DEFAULT_OVERFLOW_STACKS[cpu]);
+    (stack as *mut u8, stack.add(1) as *mut u8)
+}
+
+/// Called after the outermost IRQ or FIQ handler, see [`set_irq_exit_hook`].
+static IRQ_EXIT_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+/// Called on a kernel stack overflow, see [`set_stack_overflow_handler`].
+static STACK_OVERFLOW_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+impl CpuTrapState {
+    const fn new((overflow_stack_bottom, overflow_stack_top): (*mut u8, *mut u8)) -> Self {
+        Self {
+            irq_stack_top: 0,
+            irq_depth: 0,
+            irq_frame: core::ptr::null_mut(),
+            stack_limit: 0,
+            saved_stack_limit: 0,
+            irq_stack_bottom: 0,
+            overflow_stack_top,
+            overflow_stack_bottom,
+        }
+    }
+
+    /// Returns the state of the current CPU.
+    ///
+    /// Only the current CPU may access it, with IRQs masked for writes.
+    fn current() -> *mut Self {
+        unsafe { core::ptr::addr_of_mut!(CPU_TRAP_STATE[crate::asm::cpuid()]) }
+    }
+}
+
+/// Sets the IRQ stack `[bottom, top)` of the current CPU.
+///
+/// IRQs and FIQs taken from EL1 switch to this stack, unless they are nested. The frame of the
+/// interrupted context is still pushed on the interrupted stack.
+///
+/// # Safety
+///
+/// `top` must be the 16 bytes aligned top of a stack owned by the current CPU, or 0 to handle
+/// IRQs on the interrupted stack. Must be called with IRQs and FIQs masked.
+pub unsafe fn set_irq_stack(bottom: usize, top: usize) {
+    let state = CpuTrapState::current();
+    (*state).irq_stack_bottom = bottom;
+    (*state).irq_stack_top = top;
+}
+
+/// Sets the bottom of the kernel stack running on the current CPU, e.g. on a thread switch.
+///
+/// Traps from EL1 with SP_EL1 less than a [`TrapFrame`] above `limit` are handled as a stack
+/// overflow, on the overflow stack: see [`set_overflow_stack`]. The check uses SP_EL0 and
+/// TPIDR_EL0 as scratch registers, which are restored from the [`UserContext`] by
+/// [`UserContext::run`], so it is only done for traps from EL1 using SP_EL1.
+///
+/// # Safety
+///
+/// Must be called with IRQs and FIQs masked, and SP_EL1 above `limit`.
+pub unsafe fn set_kernel_stack_limit(limit: usize) {
+    (*CpuTrapState::current()).stack_limit = limit;
+}
+
+/// Sets the stack `[bottom, top)` used on a kernel stack overflow on the current CPU.
+///
+/// Each CPU starts with a default overflow stack of [`DEFAULT_OVERFLOW_STACK_SIZE`] bytes, which
+/// is restored if `top` is 0. Set a larger one for a handler needing more, see
+/// [`set_stack_overflow_handler`]. The stack limit is moved to `bottom` on the switch, so that a
+/// trap taken by the handler is checked against the overflow stack.
+///
+/// # Safety
+///
+/// `top` must be the 16 bytes aligned top of a stack owned by the current CPU, and not used for
+/// anything else, or 0. Must be called with IRQs and FIQs masked.
+pub unsafe fn set_overflow_stack(bottom: usize, top: usize) {
+    let (bottom, top) = if top == 0 {
+        default_overflow_stack(crate::asm::cpuid())
+    } else {
+        (bottom as *mut u8, top as *mut u8)
+    };
+    let state = CpuTrapState::current();
+    (*state).overflow_stack_bottom = bottom;
+    (*state).overflow_stack_top = top;
+}
+
+/// A handler of kernel stack overflows, running on the overflow stack. The frame holds the
+/// overflowed SP.
+pub type StackOverflowHandler = fn(&mut TrapFrame, &TrapInfo) -> !;
+
+/// Sets the handler of kernel stack overflows, instead of a panic.
+pub fn set_stack_overflow_handler(handler: StackOverflowHandler) {
+    STACK_OVERFLOW_HANDLER.store(handler as usize, Ordering::Release);
+}
+
+#[no_mangle]
+extern "C" fn __trap_stack_overflow(tf: &mut TrapFrame) -> ! {
+    let info = TrapInfo::current(tf);
+    let handler = STACK_OVERFLOW_HANDLER.load(Ordering::Acquire);
+    if handler != 0 {
+        let handler: StackOverflowHandler = unsafe { core::mem::transmute(handler) };
+        handler(tf, &info);
+    }
+    let limit = unsafe { (*CpuTrapState::current()).stack_limit };
+    panic!(
+        "kernel stack overflow: sp {:#x}, limit {:#x}, {:#x?}\n{:#x?}",
+        tf.sp, limit, info, tf
+    );
+}
+
+/// Returns the number of nested IRQ and FIQ handlers running on the current CPU.
+///
+/// Handlers may unmask IRQs, for a higher priority interrupt to preempt them.
+pub fn irq_depth() -> usize {
+    unsafe { (*CpuTrapState::current()).irq_depth }
+}
+
+/// Returns the frame of the innermost IRQ or FIQ taken from EL1 on the current CPU.
+///
+/// # Safety
+///
+/// The frame must not be used after the handler returns.
+pub unsafe fn irq_frame() -> Option<&'static mut TrapFrame> {
+    (*CpuTrapState::current()).irq_frame.as_mut()
+}
+
+/// Sets a function called when the outermost IRQ or FIQ handler returns, back on the
+/// interrupted stack with IRQs masked, before returning to the interrupted context.
+///
+/// This is where a kernel may preempt the interrupted thread, by switching to another one,
+/// unless [`TrapFrame::irqs_masked`].
+pub fn set_irq_exit_hook(hook: fn(&mut TrapFrame)) {
+    IRQ_EXIT_HOOK.store(hook as usize, Ordering::Release);
+}
+
+#[no_mangle]
+extern "C" fn __trap_irq_exit(tf: &mut TrapFrame) {
+    let hook = IRQ_EXIT_HOOK.load(Ordering::Acquire);
+    if hook != 0 {
+        let hook: fn(&mut TrapFrame) = unsafe { core::mem::transmute(hook) };
+        hook(tf);
+    }
+}
+
+/// Traps FP/SIMD instructions at EL0 with CPACR_EL1.FPEN unless `fp_enabled`, and SVE
+/// instructions with CPACR_EL1.ZEN unless `sve_enabled`.
+///
+/// They are never trapped at EL1.
+fn set_user_fp_access(fp_enabled: bool, sve_enabled: bool) {
+    use crate::registers::{ReadWriteable, Readable, CPACR_EL1};
+
+    let fpen = if fp_enabled {
+        CPACR_EL1::FPEN::TrapNothing
+    } else {
+        CPACR_EL1::FPEN::TrapEl0
+    };
+    let zen = if sve_enabled {
+        CPACR_EL1::ZEN::TrapNothing
+    } else {
+        CPACR_EL1::ZEN::TrapEl0
+    };
+    if !CPACR_EL1.matches_all(fpen + zen) {
+        CPACR_EL1.modify(fpen + zen);
+        unsafe { barrier::isb(barrier::SY) };
+    }
+}
+
 #[allow(improper_ctypes)]
 extern "C" {
-    fn run_user(regs: &mut UserContext);
+    fn run_user(
+        regs: *mut UserContext,
+        keys: *const PauthKeys,
+        fp: *mut FpRegs,
+        sve_z: *mut u8,
+        sve_p: *mut u8,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_trap_reason() {
+        assert_eq!(
+            decode_trap_num(2 | 1 << 16),
+            (TrapSource::LowerAArch64, TrapKind::Irq)
+        );
+
+        let reason =
+            |esr, far| TrapReason::new(TrapKind::Synchronous, ExceptionInfo::new(esr, far));
+        assert_eq!(reason(0x5600_0000, 0), TrapReason::Syscall);
+        assert_eq!(reason(0xf200_0007, 0), TrapReason::Breakpoint(7));
+        assert_eq!(reason(0x0200_0000, 0), TrapReason::Undefined);
+        assert_eq!(reason(0x3600_0002, 0), TrapReason::BranchTarget(2));
+        // Permission fault at level 3 on a write
+        assert_eq!(
+            reason(0x9200_004f, 0x4000),
+            TrapReason::PageFault {
+                addr: VirtAddr::new(0x4000),
+                access: PageFaultAccess::Write
+            }
+        );
+        // Translation fault at level 2 on an instruction fetch
+        assert_eq!(
+            reason(0x8200_0006, 0x1000),
+            TrapReason::PageFault {
+                addr: VirtAddr::new(0x1000),
+                access: PageFaultAccess::Execute
+            }
+        );
+
+        let mut ctx = UserContext {
+            spsr: 0x2000_0000,
+            ..Default::default()
+        };
+        ctx.set_btype(3);
+        assert_eq!((ctx.btype(), ctx.spsr), (3, 0x2000_0c00));
+        ctx.set_btype(0);
+        assert_eq!(ctx.spsr, 0x2000_0000);
+    }
+
+    #[test]
+    pub fn test_default_overflow_stack() {
+        // Every CPU starts with its own overflow stack
+        let stacks = unsafe { core::ptr::addr_of!(DEFAULT_OVERFLOW_STACKS) } as usize;
+        for cpu in 0..MAX_CPUS {
+            let state = unsafe { &CPU_TRAP_STATE[cpu] };
+            let (bottom, top) = (
+                state.overflow_stack_bottom as usize,
+                state.overflow_stack_top as usize,
+            );
+            assert_eq!(top % 16, 0);
+            assert_eq!(bottom, stacks + cpu * DEFAULT_OVERFLOW_STACK_SIZE);
+            assert_eq!(top, bottom + DEFAULT_OVERFLOW_STACK_SIZE);
+        }
+        assert_eq!(core::mem::size_of::<CpuTrapState>(), 64);
+    }
 }
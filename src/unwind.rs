@@ -0,0 +1,261 @@
+//! Stack unwinding through frame records.
+//!
+//! With frame pointers, X29 points to a frame record of two words on the stack: the X29 of the
+//! caller, and its return address. [`Unwinder`] walks this chain, from a [`TrapFrame`] or from
+//! the current function, checking that every record is within the bounds of the stack.
+//!
+//! The kernel must be built with frame pointers, e.g. `-C force-frame-pointers=yes`.
+//!
+//! # Example
+//! ```no_run
+//! # use aarch64::trap::{TrapFrame, TrapInfo};
+//! # use aarch64::unwind::{Backtrace, StackBounds, Symbol, Unwinder};
+//! # const KERNEL_STACK_BOTTOM: usize = 0x1000;
+//! # const KERNEL_STACK_TOP: usize = 0x5000;
+//! # static KERNEL_SYMBOLS: [Symbol; 0] = [];
+//! fn oops(tf: &mut TrapFrame, info: &TrapInfo) {
+//!     let bounds = StackBounds::new(KERNEL_STACK_BOTTOM, KERNEL_STACK_TOP);
+//!     let unwinder = unsafe { Unwinder::from_trap_frame(tf, bounds) };
+//!     println!("{}", Backtrace::new(unwinder, Some(&KERNEL_SYMBOLS[..])));
+//! }
+//! ```
+
+use crate::trap::TrapFrame;
+use core::fmt;
+
+/// The most frames printed by [`Backtrace`].
+pub const MAX_BACKTRACE_DEPTH: usize = 64;
+
+/// The stack memory of an unwound stack, from `bottom` (included) to `top` (excluded).
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct StackBounds {
+    pub bottom: usize,
+    pub top: usize,
+}
+
+impl StackBounds {
+    pub const fn new(bottom: usize, top: usize) -> Self {
+        Self { bottom, top }
+    }
+
+    /// Returns whether a frame record at `fp` is within the bounds.
+    pub fn contains_record(&self, fp: usize) -> bool {
+        fp & 7 == 0 && fp >= self.bottom && fp < self.top && self.top - fp >= 16
+    }
+}
+
+/// A frame of the unwound stack.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Frame {
+    /// Address of the instruction: the trapping one for the first frame of a [`TrapFrame`], the
+    /// call for the callers.
+    pub pc: usize,
+    /// Frame pointer of the function, 0 if unknown.
+    pub fp: usize,
+}
+
+/// Iterator over the frames of a stack, from the innermost one.
+///
+/// The walk stops at a null frame pointer, or at the first frame record out of bounds or not
+/// above the previous one.
+#[derive(Debug, Clone, Copy)]
+pub struct Unwinder {
+    next: Option<Frame>,
+    bounds: StackBounds,
+}
+
+impl Unwinder {
+    /// Starts unwinding from the function at `pc`, with the frame pointer `fp`.
+    ///
+    /// # Safety
+    ///
+    /// The memory within `bounds` must be readable.
+    pub unsafe fn new(pc: usize, fp: usize, bounds: StackBounds) -> Self {
+        Self {
+            next: Some(Frame { pc, fp }),
+            bounds,
+        }
+    }
+
+    /// Starts unwinding from the instruction that trapped to `tf`.
+    ///
+    /// Frames of functions without a frame record, e.g. leaf functions, are skipped.
+    ///
+    /// # Safety
+    ///
+    /// The memory within `bounds` must be readable.
+    pub unsafe fn from_trap_frame(tf: &TrapFrame, bounds: StackBounds) -> Self {
+        Self::new(tf.elr, tf.general.x29, bounds)
+    }
+
+    /// Starts unwinding from the caller.
+    ///
+    /// Frame records below the current stack pointer are ignored.
+    ///
+    /// # Safety
+    ///
+    /// The memory within `bounds` must be readable.
+    #[inline(always)]
+    pub unsafe fn current(bounds: StackBounds) -> Self {
+        #[cfg(target_arch = "aarch64")]
+        {
+            let fp: usize;
+            core::arch::asm!("mov {}, x29", out(reg) fp, options(nomem, nostack));
+            let bottom = bounds.bottom.max(crate::asm::sp() as usize);
+            Self::new(crate::asm::pc(), fp, StackBounds::new(bottom, bounds.top))
+        }
+
+        #[cfg(not(target_arch = "aarch64"))]
+        {
+            let _ = bounds;
+            unimplemented!()
+        }
+    }
+}
+
+impl Iterator for Unwinder {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        let frame = self.next.take()?;
+        let fp = frame.fp;
+        if self.bounds.contains_record(fp) {
+            let (caller_fp, lr) = unsafe { (*(fp as *const usize), *(fp as *const usize).add(1)) };
+            let lr = strip_pac(lr);
+            if lr != 0 {
+                // The records of the callers are above, so that the walk ends.
+                self.bounds.bottom = fp + 16;
+                self.next = Some(Frame {
+                    pc: lr.wrapping_sub(4),
+                    fp: caller_fp,
+                });
+            }
+        }
+        Some(frame)
+    }
+}
+
+/// Returns the return address `lr` without the pointer authentication code that `paciasp` adds
+/// to it, when the kernel is built with `-mbranch-protection=pac-ret`.
+fn strip_pac(lr: usize) -> usize {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let mut lr = lr;
+        // XPACLRI, a NOP without pointer authentication
+        unsafe {
+            core::arch::asm!("hint #7", inout("x30") lr, options(nomem, nostack, preserves_flags))
+        };
+        lr
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        // Like XPACLRI with a 48-bit VA: the bits above are copies of bit 55.
+        let pac = !0usize << 48;
+        if lr & 1 << 55 != 0 {
+            lr | pac
+        } else {
+            lr & !pac
+        }
+    }
+}
+
+/// A symbol of the symbol table of a [`Backtrace`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Symbol<'a> {
+    pub addr: usize,
+    pub name: &'a str,
+}
+
+/// An address to symbol table.
+pub trait Symbolize {
+    /// Returns the symbol containing `pc`, and the offset of `pc` in it.
+    fn symbolize(&self, pc: usize) -> Option<(&str, usize)>;
+}
+
+/// A table of symbols sorted by address, each one ending at the next.
+impl Symbolize for [Symbol<'_>] {
+    fn symbolize(&self, pc: usize) -> Option<(&str, usize)> {
+        let i = match self.binary_search_by_key(&pc, |sym| sym.addr) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        Some((self[i].name, pc - self[i].addr))
+    }
+}
+
+/// Formats the frames of an [`Unwinder`], one per line, with their symbols if a table is given.
+pub struct Backtrace<'a, S: Symbolize + ?Sized> {
+    unwinder: Unwinder,
+    symbols: Option<&'a S>,
+}
+
+impl<'a, S: Symbolize + ?Sized> Backtrace<'a, S> {
+    pub fn new(unwinder: Unwinder, symbols: Option<&'a S>) -> Self {
+        Self { unwinder, symbols }
+    }
+}
+
+impl<S: Symbolize + ?Sized> fmt::Display for Backtrace<'_, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, frame) in self.unwinder.take(MAX_BACKTRACE_DEPTH).enumerate() {
+            write!(f, "#{:<2} {:#018x}", i, frame.pc)?;
+            if let Some((name, offset)) = self.symbols.and_then(|s| s.symbolize(frame.pc)) {
+                write!(f, " {}+{:#x}", name, offset)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trap::GeneralRegs;
+
+    #[test]
+    pub fn test_unwind() {
+        // Three frame records, the last one pointing out of the stack.
+        let mut stack = [0usize; 8];
+        let base = stack.as_ptr() as usize;
+        stack[0..2].copy_from_slice(&[base + 32, 0x1004]);
+        stack[4..6].copy_from_slice(&[base + 48, 0x2008]);
+        // A return address signed by paciasp.
+        stack[6..8].copy_from_slice(&[base + 0x1000, 0x002a_0000_0000_3010]);
+        let bounds = StackBounds::new(base, base + 64);
+
+        let tf = TrapFrame {
+            elr: 0x500,
+            general: GeneralRegs {
+                x29: base,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let unwinder = unsafe { Unwinder::from_trap_frame(&tf, bounds) };
+        let pcs = [0x500, 0x1000, 0x2004, 0x300c];
+        assert!(unwinder.map(|frame| frame.pc).eq(pcs.iter().copied()));
+
+        // A loop in the chain is stopped.
+        stack[4] = base;
+        assert_eq!(unsafe { Unwinder::from_trap_frame(&tf, bounds) }.count(), 3);
+
+        assert_eq!(strip_pac(0x3ad5_ffff_0000_1004), 0xffff_ffff_0000_1004);
+
+        let symbols = [
+            Symbol {
+                addr: 0x400,
+                name: "fault",
+            },
+            Symbol {
+                addr: 0x1000,
+                name: "caller",
+            },
+        ];
+        assert_eq!(symbols[..].symbolize(0x500), Some(("fault", 0x100)));
+        assert_eq!(symbols[..].symbolize(0x1000), Some(("caller", 0)));
+        assert_eq!(symbols[..].symbolize(0x100), None);
+    }
+}